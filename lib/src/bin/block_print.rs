@@ -1,7 +1,6 @@
 use btclib::types::Block;
 use btclib::util::Saveable;
 use clap::Parser;
-use std::fs::File;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -12,8 +11,6 @@ struct Cli {
 
 fn main() {
     let cli = Cli::parse();
-    if let Ok(file) = File::open(cli.block_file) {
-        let block = Block::load(file).expect("Failed to load block");
-        println!("{:#?}", block);
-    }
+    let block = Block::load_from_file(cli.block_file).expect("Failed to load block");
+    println!("{:#?}", block);
 }
\ No newline at end of file
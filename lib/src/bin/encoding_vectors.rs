@@ -0,0 +1,101 @@
+use std::collections::BTreeMap;
+use btclib::crypto::{Hash, MerkleRoot, PrivateKey, Signature};
+use btclib::encoding::{Decode, Encode};
+use btclib::types::{Block, BlockHeader, CoinbaseData, Transaction, TransactionInput, TransactionOutput};
+use chrono::DateTime;
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Print the canonical encoding test vectors, or check a file of them against this build
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Vectors file to verify, like encoding_vectors.json at the root of the crate
+    #[arg(long)]
+    check: Option<String>
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct Vector {
+    /// Canonical encoding in hexadecimal
+    encoding: String,
+    /// SHA-256 of the encoding, or what the item goes by, as its 32 encoded bytes in hexadecimal
+    hash: String,
+}
+
+fn vector(item: &impl Encode, hash: Hash) -> Vector {
+    Vector { encoding: hex::encode(item.encoded()), hash: hex::encode(hash.encoded()) }
+}
+
+/// The same items on every run: fixed keys, ids and time, and deterministic (RFC 6979) signatures
+fn vectors() -> BTreeMap<&'static str, Vector> {
+    let private_key = PrivateKey::from_bytes(&[1; 32]).expect("BUG: impossible");
+    let output = TransactionOutput {
+        value: btclib::INITIAL_REWARD * 10u64.pow(8),
+        unique_id: Uuid::from_bytes([0x11; 16]),
+        public_key: private_key.public_key(),
+    };
+    let coinbase = Transaction::new(vec![], vec![output.clone()]);
+    let tagged_coinbase = Transaction::coinbase(
+        vec![output.clone()],
+        CoinbaseData { extra_nonce: 0x0000_0007_0000_0001, data: b"miner".to_vec() },
+    );
+    let input = TransactionInput {
+        prev_transaction_output_hash: output.hash(),
+        signature: Signature::sign_output(&output.hash(), &private_key),
+    };
+    let spend = Transaction::new(vec![input.clone()], vec![TransactionOutput {
+        value: output.value - 1000,
+        unique_id: Uuid::from_bytes([0x22; 16]),
+        public_key: private_key.public_key(),
+    }]);
+    let transactions = vec![coinbase.clone(), spend.clone()];
+    let header = BlockHeader::new(
+        DateTime::from_timestamp(1_700_000_000, 123_456_789).expect("BUG: impossible"),
+        42,
+        Hash::zero(),
+        MerkleRoot::calculate(&transactions),
+        btclib::MIN_TARGET,
+    );
+    let block = Block::new(header.clone(), transactions);
+    BTreeMap::from([
+        ("output", vector(&output, output.hash())),
+        ("input", vector(&input, Hash::digest(&input.encoded()))),
+        ("coinbase", vector(&coinbase, coinbase.hash())),
+        ("tagged_coinbase", vector(&tagged_coinbase, tagged_coinbase.hash())),
+        ("spend", vector(&spend, spend.hash())),
+        ("header", vector(&header, header.hash())),
+        ("block", vector(&block, block.hash())),
+    ])
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let vectors = vectors();
+    let Some(file) = cli.check else {
+        println!("{}", serde_json::to_string_pretty(&vectors).expect("BUG: impossible"));
+        return;
+    };
+    let content = std::fs::read_to_string(&file).expect("Failed to read vectors");
+    let expected: BTreeMap<String, Vector> = serde_json::from_str(&content).expect("Failed to parse vectors");
+    let mut failed = false;
+    for (name, expected) in &expected {
+        let matches = vectors.get(name.as_str()) == Some(expected);
+        // Decoding and encoding again must give the very same bytes
+        let bytes = hex::decode(&expected.encoding).expect("Vector encoding is not hexadecimal");
+        let round_trip = match name.as_str() {
+            "output" => TransactionOutput::decode_all(&bytes).map(|item| item.encoded()),
+            "input" => TransactionInput::decode_all(&bytes).map(|item| item.encoded()),
+            "coinbase" | "tagged_coinbase" | "spend" => Transaction::decode_all(&bytes).map(|item| item.encoded()),
+            "header" => BlockHeader::decode_all(&bytes).map(|item| item.encoded()),
+            "block" => Block::decode_all(&bytes).map(|item| item.encoded()),
+            _ => Ok(bytes.clone()),
+        }.is_ok_and(|encoded| encoded == bytes);
+        println!("{:<15} {}", name, if matches && round_trip { "ok" } else { "MISMATCH" });
+        failed |= !(matches && round_trip);
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
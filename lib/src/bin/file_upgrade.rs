@@ -0,0 +1,72 @@
+use std::path::PathBuf;
+use btclib::crypto::PrivateKey;
+use btclib::types::{Block, Blockchain, Transaction};
+use btclib::util::{file_version, is_not_migratable, Saveable};
+use clap::{Parser, ValueEnum};
+
+/// Rewrite files saved by older builds in the current format version.
+///
+/// Blockchains, blocks and transactions from before the canonical encoding (format versions 0 and 1)
+/// cannot be upgraded: every block and output hash changed with it, which voids the proof of work
+/// and the signatures. A node moves such a blockchain file to `<file>.old` and syncs from scratch,
+/// blocks and transactions have to be made again. Private keys of any version upgrade fine
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// What the files hold
+    #[arg(short, long)]
+    kind: Kind,
+    /// Only report the format version of each file
+    #[arg(long)]
+    dry_run: bool,
+    #[arg(required = true)]
+    files: Vec<PathBuf>
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Kind {
+    Blockchain,
+    Block,
+    Transaction,
+    PrivateKey,
+}
+
+fn upgrade<T: Saveable>(file: &PathBuf, dry_run: bool) -> std::io::Result<()> {
+    let version = file_version(file)?.unwrap_or(0);
+    if version == T::FORMAT_VERSION {
+        println!("{}: version {}, up to date", file.display(), version);
+        return Ok(());
+    }
+    // Loading migrates, and fails on versions newer than this build knows
+    let content = T::load_from_file(file)?;
+    if dry_run {
+        println!("{}: version {}, can be upgraded to {}", file.display(), version, T::FORMAT_VERSION);
+        return Ok(());
+    }
+    content.save_to_file(file)?;
+    println!("{}: upgraded from version {} to {}", file.display(), version, T::FORMAT_VERSION);
+    Ok(())
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let mut failed = false;
+    for file in &cli.files {
+        let result = match cli.kind {
+            Kind::Blockchain => upgrade::<Blockchain>(file, cli.dry_run),
+            Kind::Block => upgrade::<Block>(file, cli.dry_run),
+            Kind::Transaction => upgrade::<Transaction>(file, cli.dry_run),
+            Kind::PrivateKey => upgrade::<PrivateKey>(file, cli.dry_run),
+        };
+        if let Err(e) = result {
+            eprintln!("{}: {}", file.display(), e);
+            if is_not_migratable(&e) {
+                eprintln!("{}: written before the canonical encoding, sync a blockchain again or make a new block or transaction", file.display());
+            }
+            failed = true;
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
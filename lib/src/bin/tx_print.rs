@@ -1,7 +1,6 @@
 use btclib::types::Transaction;
 use btclib::util::Saveable;
 use clap::Parser;
-use std::fs::File;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -12,8 +11,6 @@ struct Cli {
 
 fn main() {
     let cli = Cli::parse();
-    if let Ok(file) = File::open(cli.tx_file) {
-        let tx = Transaction::load(file).expect("Failed to load transaction");
-        println!("{:#?}", tx);
-    }
+    let tx = Transaction::load_from_file(cli.tx_file).expect("Failed to load transaction");
+    println!("{:#?}", tx);
 }
\ No newline at end of file
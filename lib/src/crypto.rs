@@ -1,4 +1,6 @@
 use std::cmp::Ordering;
+use crate::encoding::{Decode, Decoder, Encode};
+use crate::error::BtcError;
 use crate::types::Transaction;
 use crate::util::Saveable;
 use ecdsa::{signature::{Signer, Verifier}, Signature as ECDSASignature, SigningKey, VerifyingKey};
@@ -6,9 +8,10 @@ use k256::elliptic_curve::rand_core::OsRng;
 use k256::Secp256k1;
 use primitive_types::U256;
 use serde::{Deserialize, Serialize};
-use sha256::digest;
+use sha2::{Digest, Sha256};
 use spki::EncodePublicKey;
 use std::fmt;
+use std::str::FromStr;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
 
 
@@ -16,15 +19,25 @@ use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResu
 pub struct Hash(U256);
 impl Hash {
     /// Hash anything that can be serialized via Ciborium
+    #[allow(clippy::self_named_constructors)]
     pub fn hash<T: serde::Serialize>(data: &T) -> Self {
         let mut serialized: Vec<u8> = vec![];
         if let Err(e) = ciborium::into_writer(data, &mut serialized) {
             panic!("Failed to serialize data {:?}", e);
         }
-        let hash = digest(&serialized);
-        let hash_bytes = hex::decode(hash).unwrap();
-        let hash_array: [u8; 32] = hash_bytes.as_slice().try_into().unwrap();
-        Hash(U256::from_big_endian(&hash_array))
+        Self::digest(&serialized)
+    }
+    /// SHA-256 of raw bytes, like a canonical encoding
+    pub fn digest(bytes: &[u8]) -> Self {
+        let mut hasher = Hasher::new();
+        hasher.update(bytes);
+        hasher.finish()
+    }
+    /// SHA-256 of the SHA-256 of raw bytes, as Bitcoin hashes
+    pub fn double_digest(bytes: &[u8]) -> Self {
+        let mut hasher = Hasher::new();
+        hasher.update(bytes);
+        hasher.finish_double()
     }
     /// Check if a hash matches a target
     pub fn matches_target(&self, target: U256) -> bool {
@@ -41,29 +54,123 @@ impl Hash {
     }
 }
 
+/// Incremental SHA-256. Cloning it after feeding a common prefix saves hashing the prefix again
+#[derive(Clone, Default)]
+pub struct Hasher(Sha256);
+impl Hasher {
+    pub fn new() -> Self {
+        Hasher(Sha256::new())
+    }
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+    pub fn finish(self) -> Hash {
+        Hash(U256::from_big_endian(&self.0.finalize()))
+    }
+    /// Hash the digest once more, as Bitcoin does
+    pub fn finish_double(self) -> Hash {
+        Hash(U256::from_big_endian(&Sha256::digest(self.0.finalize())))
+    }
+}
+
+impl Encode for Hash {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+    }
+}
+impl Decode for Hash {
+    fn decode(decoder: &mut Decoder) -> crate::error::Result<Self> {
+        U256::decode(decoder).map(Hash)
+    }
+}
+
 impl fmt::Display for Hash {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:x}", self.0)
     }
 }
+impl FromStr for Hash {
+    type Err = BtcError;
+    /// Parse the hexadecimal form printed by Display
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s.strip_prefix("0x").unwrap_or(s);
+        U256::from_str_radix(digits, 16).map(Hash).map_err(|_| BtcError::InvalidHash)
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Signature(ECDSASignature<Secp256k1>);
 impl Signature {
-    /// Sign a TransactionOutput from its Sha256 hash
+    /// Sign a TransactionOutput from the encoding of its hash
     pub fn sign_output(output_hash: &Hash, private_key: &PrivateKey) -> Self {
         let signing_key = &private_key.0;
-        let signature = signing_key.sign(&output_hash.as_bytes());
+        let signature = signing_key.sign(&output_hash.encoded());
+        Signature(signature)
+    }
+    /// Sign a mined block, proving who submits it. The message is the encoding of its hash behind
+    /// `BLOCK_SIGNATURE_TAG`, so the signature cannot pass for one spending an output of the same hash
+    pub fn sign_block(block_hash: &Hash, private_key: &PrivateKey) -> Self {
+        let signing_key = &private_key.0;
+        let signature = signing_key.sign(&block_message(block_hash));
         Signature(signature)
     }
-    /// Verify a signature
+    /// Verify a signature made with `sign_block`
+    pub fn verify_block(&self, block_hash: &Hash, public_key: &PublicKey) -> bool {
+        public_key.0.verify(&block_message(block_hash), &self.0).is_ok()
+    }
+    /// Verify a signature made with `sign_output`
     pub fn verify(&self, output_hash: &Hash, public_key: &PublicKey) -> bool {
-        public_key.0.verify(&output_hash.as_bytes(), &self.0).is_ok()
+        public_key.0.verify(&output_hash.encoded(), &self.0).is_ok()
+    }
+}
+
+/// Prefix of the message signed when submitting a block
+pub const BLOCK_SIGNATURE_TAG: &[u8] = b"btc-block-submit";
+
+fn block_message(block_hash: &Hash) -> Vec<u8> {
+    let mut message = BLOCK_SIGNATURE_TAG.to_vec();
+    block_hash.encode(&mut message);
+    message
+}
+
+impl Encode for Signature {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0.to_bytes());
+    }
+}
+impl Decode for Signature {
+    fn decode(decoder: &mut Decoder) -> crate::error::Result<Self> {
+        ECDSASignature::from_slice(&decoder.take::<64>()?).map(Signature).map_err(|_| BtcError::InvalidSignature)
     }
 }
 
 #[derive(Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct PublicKey(pub VerifyingKey<Secp256k1>);
+impl PublicKey {
+    /// Compressed SEC1 encoding, 33 bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_encoded_point(true).as_bytes().to_vec()
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        VerifyingKey::from_sec1_bytes(bytes).ok().map(PublicKey)
+    }
+
+    /// What an identity goes by in the logs: the SHA-256 of the compressed key, so anyone can
+    /// compute it from a key file
+    pub fn fingerprint(&self) -> Hash {
+        Hash::digest(&self.to_bytes())
+    }
+}
+impl Encode for PublicKey {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_bytes());
+    }
+}
+impl Decode for PublicKey {
+    fn decode(decoder: &mut Decoder) -> crate::error::Result<Self> {
+        Self::from_bytes(&decoder.take::<33>()?).ok_or(BtcError::InvalidPublicKey)
+    }
+}
 // Save and load as PEM
 impl Saveable for PublicKey {
     fn load<I: Read>(mut reader: I) -> IoResult<Self> {
@@ -113,14 +220,27 @@ impl PrivateKey {
     pub fn new_key() -> Self {
         PrivateKey(SigningKey::random(&mut OsRng))
     }
+    /// Key from its 32 secret bytes, None if they are not a valid scalar
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        SigningKey::from_slice(bytes).ok().map(PrivateKey)
+    }
     pub fn public_key(&self) -> PublicKey {
-        PublicKey(self.0.verifying_key().clone())
+        PublicKey(*self.0.verifying_key())
+    }
+    /// Secret shared with the owner of another key (ECDH), both sides get the same bytes
+    pub fn diffie_hellman(&self, other: &PublicKey) -> [u8; 32] {
+        let shared = k256::ecdh::diffie_hellman(self.0.as_nonzero_scalar(), other.0.as_affine());
+        (*shared.raw_secret_bytes()).into()
     }
 }
 impl Saveable for PrivateKey {
+    const HEADER: bool = true;
+    /// 1: CBOR byte string of the secret scalar
+    const FORMAT_VERSION: u32 = 1;
+
     fn load<I: Read>(reader: I) -> IoResult<Self> {
-        ciborium::de::from_reader(reader).map_err(|_| {
-            IoError::new(IoErrorKind::InvalidData, "Failed to deserialize PrivateKey")
+        ciborium::de::from_reader(reader).map_err(|e| {
+            IoError::new(IoErrorKind::InvalidData, format!("Failed to deserialize PrivateKey: {}", e))
         })
     }
     fn save<O: Write>(&self, writer: O) -> IoResult<()> {
@@ -128,6 +248,14 @@ impl Saveable for PrivateKey {
             IoError::new(IoErrorKind::InvalidData, "Failed to serialize PrivateKey")
         })
     }
+
+    fn migrate<I: Read>(version: u32, reader: I) -> IoResult<Self> {
+        match version {
+            // Keys generated before files had headers
+            0 => Self::load(reader),
+            _ => Err(crate::util::unsupported_version(version, Self::FORMAT_VERSION)),
+        }
+    }
 }
 
 mod signkey_serde {
@@ -152,7 +280,7 @@ impl MerkleRoot {
     pub fn calculate(transactions: &[Transaction]) -> MerkleRoot {
         let mut layer: Vec<Hash> = vec![];
         for transaction in transactions {
-            layer.push(Hash::hash(transaction));
+            layer.push(transaction.hash());
         }
         while layer.len() > 1 {
             let mut new_layer = vec![];
@@ -160,10 +288,22 @@ impl MerkleRoot {
                 let left = pair[0];
                 // If there is no right, use the left one again
                 let right = pair.get(1).unwrap_or(&pair[0]);
-                new_layer.push(Hash::hash(&[left, *right]));
+                let mut pair = left.encoded();
+                right.encode(&mut pair);
+                new_layer.push(Hash::digest(&pair));
             }
             layer = new_layer;
         }
         MerkleRoot(layer[0])
     }
+}
+impl Encode for MerkleRoot {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+    }
+}
+impl Decode for MerkleRoot {
+    fn decode(decoder: &mut Decoder) -> crate::error::Result<Self> {
+        Hash::decode(decoder).map(MerkleRoot)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,236 @@
+//! Canonical binary encoding of blocks and transactions.
+//!
+//! Hashes, signatures and the wire all use these bytes, so another implementation only needs this
+//! description to interoperate. Integers are little-endian and fixed width. Lists are a u32 count
+//! followed by the items.
+//!
+//! | Item                | Encoding                                                                          |
+//! |---------------------|-----------------------------------------------------------------------------------|
+//! | `Hash`, `MerkleRoot`| 32 bytes, big-endian, the hexadecimal form read left to right                     |
+//! | target              | 32 bytes, big-endian                                                              |
+//! | timestamp           | i64 seconds since the Unix epoch, then u32 nanoseconds (12 bytes)                 |
+//! | `Uuid`              | its 16 bytes                                                                      |
+//! | `PublicKey`         | compressed SEC1 point, 33 bytes                                                   |
+//! | `Signature`         | ECDSA r then s, 32 bytes each                                                     |
+//! | `BlockHeader`       | timestamp, prev_block_hash, merkle_root, target, u64 nonce (116 bytes)            |
+//! | `TransactionInput`  | prev_transaction_output_hash, signature (96 bytes)                                |
+//! | `TransactionOutput` | u64 value, unique_id, public_key (57 bytes)                                       |
+//! | `Transaction`       | list of inputs, list of outputs                                                   |
+//! | `Block`             | header, list of transactions                                                      |
+//!
+//! The hash of a header, transaction or output is the SHA-256 of its encoding. A block is known by
+//! the hash of its header, the Merkle root covering its transactions. A Merkle node is the SHA-256 of
+//! the two child hashes one after the other. An input signs the 32 encoded bytes of the output it
+//! spends with ECDSA over secp256k1 and SHA-256, low S. A miner submitting a block signs the same
+//! way the bytes `btc-block-submit` followed by the 32 encoded bytes of the block hash.
+//!
+//! A coinbase carrying data has u32 0xFFFFFFFF in place of its input count, followed by a u64
+//! extra nonce and the data as a u32 length and the bytes, then its outputs as usual. Coinbases
+//! without data are encoded like any other transaction.
+//!
+//! `encoding_vectors.json` next to the crate manifest holds examples, `encoding_vectors --check` verifies them.
+
+use chrono::{DateTime, Utc};
+use primitive_types::U256;
+use serde::de::{Error as DeError, SeqAccess, Visitor};
+use serde::Deserializer;
+use uuid::Uuid;
+use crate::error::BtcError;
+
+/// Types with a canonical encoding
+pub trait Encode {
+    fn encode(&self, out: &mut Vec<u8>);
+
+    fn encoded(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+}
+
+/// Types that can be read back from their canonical encoding
+pub trait Decode: Sized {
+    fn decode(decoder: &mut Decoder) -> crate::error::Result<Self>;
+
+    /// Decode a whole buffer, refusing trailing bytes so every value has exactly one encoding
+    fn decode_all(bytes: &[u8]) -> crate::error::Result<Self> {
+        let mut decoder = Decoder::new(bytes);
+        let value = Self::decode(&mut decoder)?;
+        decoder.finish()?;
+        Ok(value)
+    }
+}
+
+/// Reads encoded values from the front of a buffer
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Decoder { bytes }
+    }
+
+    pub fn take<const N: usize>(&mut self) -> crate::error::Result<[u8; N]> {
+        if self.bytes.len() < N {
+            return Err(BtcError::InvalidEncoding("unexpected end of data"));
+        }
+        let (taken, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+        Ok(taken.try_into().expect("BUG: impossible"))
+    }
+
+    pub fn u32(&mut self) -> crate::error::Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    pub fn u64(&mut self) -> crate::error::Result<u64> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    /// A u32 count followed by that many items
+    pub fn list<T: Decode>(&mut self) -> crate::error::Result<Vec<T>> {
+        let count = self.u32()?;
+        self.items(count)
+    }
+
+    /// `count` items, when the count was read already
+    pub fn items<T: Decode>(&mut self, count: u32) -> crate::error::Result<Vec<T>> {
+        let count = count as usize;
+        // Every item takes at least a byte, a count beyond that is a lie and not worth allocating for
+        if count > self.bytes.len() {
+            return Err(BtcError::InvalidEncoding("list longer than the data"));
+        }
+        (0..count).map(|_| T::decode(self)).collect()
+    }
+
+    /// A u32 length followed by that many bytes
+    pub fn bytes(&mut self) -> crate::error::Result<Vec<u8>> {
+        let length = self.u32()? as usize;
+        if length > self.bytes.len() {
+            return Err(BtcError::InvalidEncoding("unexpected end of data"));
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(taken.to_vec())
+    }
+
+    pub fn finish(self) -> crate::error::Result<()> {
+        if !self.bytes.is_empty() {
+            return Err(BtcError::InvalidEncoding("trailing bytes"));
+        }
+        Ok(())
+    }
+}
+
+pub fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+pub fn encode_list<T: Encode>(items: &[T], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(items.len() as u32).to_le_bytes());
+    for item in items {
+        item.encode(out);
+    }
+}
+
+impl Encode for U256 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_big_endian());
+    }
+}
+impl Decode for U256 {
+    fn decode(decoder: &mut Decoder) -> crate::error::Result<Self> {
+        Ok(U256::from_big_endian(&decoder.take::<32>()?))
+    }
+}
+
+impl Encode for DateTime<Utc> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.timestamp().to_le_bytes());
+        out.extend_from_slice(&self.timestamp_subsec_nanos().to_le_bytes());
+    }
+}
+impl Decode for DateTime<Utc> {
+    fn decode(decoder: &mut Decoder) -> crate::error::Result<Self> {
+        let seconds = i64::from_le_bytes(decoder.take()?);
+        let nanoseconds = decoder.u32()?;
+        DateTime::from_timestamp(seconds, nanoseconds).ok_or(BtcError::InvalidEncoding("timestamp out of range"))
+    }
+}
+
+impl Encode for Uuid {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_bytes());
+    }
+}
+impl Decode for Uuid {
+    fn decode(decoder: &mut Decoder) -> crate::error::Result<Self> {
+        Ok(Uuid::from_bytes(decoder.take()?))
+    }
+}
+
+/// Accepts a byte string, or a sequence of bytes from formats without byte strings
+pub(crate) struct BytesVisitor;
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("canonically encoded bytes")
+    }
+
+    fn visit_bytes<E: DeError>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+        Ok(bytes.to_vec())
+    }
+
+    fn visit_byte_buf<E: DeError>(self, bytes: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(bytes)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
+}
+
+pub(crate) fn deserialize_canonical<'de, T: Decode, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+    let bytes = deserializer.deserialize_bytes(BytesVisitor)?;
+    T::decode_all(&bytes).map_err(D::Error::custom)
+}
+
+/// Serialize as the canonical encoding in binary formats like the CBOR of the wire and the files,
+/// and field by field in human readable ones like the JSON of the RPC server. The type needs
+/// `#[serde(remote = "Self")]` on its derives, which provides the field by field form
+/// A check can be given as well, run on whatever is read back in the field by field form, which
+/// unlike the encoding can express values the type does not allow
+macro_rules! canonical_serde {
+    ($type:ty) => {
+        crate::encoding::canonical_serde!($type, |_: &$type| Ok::<(), crate::error::BtcError>(()));
+    };
+    ($type:ty, $check:expr) => {
+        impl serde::Serialize for $type {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                if serializer.is_human_readable() {
+                    <$type>::serialize(self, serializer)
+                } else {
+                    serializer.serialize_bytes(&crate::encoding::Encode::encoded(self))
+                }
+            }
+        }
+        impl<'de> serde::Deserialize<'de> for $type {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                if deserializer.is_human_readable() {
+                    let value = <$type>::deserialize(deserializer)?;
+                    ($check)(&value).map_err(<D::Error as serde::de::Error>::custom)?;
+                    Ok(value)
+                } else {
+                    crate::encoding::deserialize_canonical(deserializer)
+                }
+            }
+        }
+    };
+}
+pub(crate) use canonical_serde;
@@ -6,6 +6,8 @@ pub enum BtcError {
     InvalidTransaction,
     #[error("Invalid block")]
     InvalidBlock,
+    #[error("Orphan block, parent unknown")]
+    OrphanBlock,
     #[error("Invalid block header")]
     InvalidBlockHeader,
     #[error("Invalid transaction input")]
@@ -22,6 +24,19 @@ pub enum BtcError {
     InvalidPublicKey,
     #[error("Invalid private key")]
     InvalidPrivateKey,
+    #[error("Invalid encoding: {0}")]
+    InvalidEncoding(&'static str),
 }
 
-pub type Result<T> = std::result::Result<T, BtcError>;
\ No newline at end of file
+pub type Result<T> = std::result::Result<T, BtcError>;
+
+/// Why a saved file could not be read back, found inside the `InvalidData` errors of `Saveable::load_from_file`
+#[derive(Error, Debug)]
+pub enum FileFormatError {
+    #[error("File is corrupted: {0}")]
+    Corrupted(String),
+    #[error("File has format version {found}, this build reads versions up to {supported}")]
+    UnsupportedVersion { found: u32, supported: u32 },
+    #[error("File has format version {found}, which cannot be migrated: {reason}")]
+    NotMigratable { found: u32, reason: &'static str },
+}
\ No newline at end of file
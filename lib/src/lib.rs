@@ -21,10 +21,21 @@ pub const DIFFICULTY_UPDATE_INTERVAL: u64 = 50;
 pub const MAX_MEMPOOL_TRANSACTION_AGE: u64 = 600;
 /// maximum number of transactions allowed in a block
 pub const BLOCK_TRANSACTION_CAP: usize = 20;
+/// Most bytes of data a coinbase may carry
+pub const MAX_COINBASE_DATA: usize = 100;
+/// Maximum number of blocks kept waiting for their parent
+pub const MAX_ORPHAN_BLOCKS: usize = 100;
+/// Max orphan block age in seconds, a parent that has not arrived by then is not coming from that peer
+pub const MAX_ORPHAN_BLOCK_AGE: u64 = 600;
+/// Largest message accepted from the network in bytes
+pub const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 pub mod crypto;
+pub mod encoding;
 pub mod error;
 pub mod network;
+pub mod stratum;
+pub mod transport;
 pub mod types;
 pub mod util;
\ No newline at end of file
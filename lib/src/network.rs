@@ -1,46 +1,215 @@
 use std::collections::HashSet;
-use std::io::{Error as IoError, Read, Write};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Write};
+use primitive_types::U256;
 use serde::{Deserialize, Serialize};
-use crate::crypto::PublicKey;
-use crate::types::{Block, Transaction, TransactionOutput};
+use crate::crypto::{Hash, PublicKey, Signature};
+use crate::MAX_MESSAGE_SIZE;
+use crate::types::{Block, Blockchain, ChainEvent, CompactBlock, EventTopic, HistoryEntry, Transaction, TransactionOutput, TxLocation};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
+
+/// Summary of a node's best chain, used to pick whom to sync from
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ChainTip {
+    /// Hash of the last block, zero for an empty chain
+    pub hash: Hash,
+    /// Number of blocks in the chain
+    pub height: u64,
+    /// Cumulative proof of work of all the blocks
+    pub work: U256,
+}
+impl ChainTip {
+    pub fn of(blockchain: &Blockchain) -> Self {
+        ChainTip {
+            hash: blockchain.tip_hash(),
+            height: blockchain.block_height(),
+            work: blockchain.chain_work(),
+        }
+    }
+}
+
+/// How a node introduces itself to another one
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct NodeInfo {
+    /// Address other nodes can dial to reach this one
+    pub address: String,
+    /// Random number picked at startup, tells connections to the same node under different addresses apart
+    pub id: u64,
+}
+
+/// A transaction found by hash. Without location it is still waiting in the mempool
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TransactionInfo {
+    pub transaction: Transaction,
+    pub location: Option<TxLocation>,
+    pub confirmations: u64,
+}
+impl TransactionInfo {
+    /// Look a transaction up in the mempool, then in the chain
+    pub fn find(blockchain: &Blockchain, hash: &Hash) -> Option<Self> {
+        if let Some(transaction) = blockchain.mempool_transaction(hash) {
+            return Some(TransactionInfo { transaction: transaction.clone(), location: None, confirmations: 0 });
+        }
+        let location = blockchain.locate_transaction(hash)?;
+        Some(TransactionInfo {
+            transaction: blockchain.transaction_at(&location)?.clone(),
+            location: Some(location),
+            confirmations: blockchain.confirmations(&location),
+        })
+    }
+}
+
+/// Most history entries sent in one page
+pub const MAX_HISTORY_PAGE: u64 = 100;
+
+/// One page of the history of a public key, newest first
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HistoryPage {
+    pub entries: Vec<HistoryEntry>,
+    /// Number of entries in the whole history
+    pub total: u64,
+    /// Height of the chain, to count confirmations
+    pub height: u64,
+}
+
+/// The coinbase a miner wants in its templates
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CoinbaseSpec {
+    /// Who the reward and the fees go to, split in proportion to the weights
+    pub payees: Vec<(PublicKey, u32)>,
+    /// Up to `MAX_COINBASE_DATA` bytes, like a tag naming the miner
+    pub data: Vec<u8>,
+}
+impl CoinbaseSpec {
+    /// Everything to one key, no data
+    pub fn pay_to(public_key: PublicKey) -> Self {
+        CoinbaseSpec { payees: vec![(public_key, 1)], data: vec![] }
+    }
+
+    /// Outputs sharing out `total` Sats by weight, the first payee getting what rounding leaves.
+    /// None if there is nobody to pay or too much data
+    pub fn outputs(&self, total: u64) -> Option<Vec<TransactionOutput>> {
+        let weights: u64 = self.payees.iter().map(|(_, weight)| *weight as u64).sum();
+        if weights == 0 || self.data.len() > crate::MAX_COINBASE_DATA {
+            return None;
+        }
+        let mut outputs: Vec<TransactionOutput> = self.payees.iter()
+            .filter(|(_, weight)| *weight > 0)
+            .map(|(public_key, weight)| TransactionOutput {
+                value: (total as u128 * *weight as u128 / weights as u128) as u64,
+                unique_id: Uuid::new_v4(),
+                public_key: public_key.clone(),
+            })
+            .collect();
+        let paid: u64 = outputs.iter().map(|output| output.value).sum();
+        outputs[0].value += total - paid;
+        Some(outputs)
+    }
+}
+
+/// Reference to a block or transaction by hash, announced before sending the full data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Inventory {
+    Block(Hash),
+    Transaction(Hash),
+    /// Only asked for with GetData, to receive a block as a `NewCompactBlock`
+    CompactBlock(Hash),
+}
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Message {
 
-    /// Ask a node what the highest block it knows about in comparison to the local blockchain is
-    AskDifference(u32),
+    /// Ask a node about its best chain, sending along our own
+    AskTip(ChainTip),
 
-    /// Response to AskDifference
-    Difference(i32),
+    /// Response to AskTip
+    Tip(ChainTip),
 
-    /// Ask a node to report all the other nodes it knows about
-    DiscoverNodes(String, String),
+    /// Ask a node where its chain and ours part ways, sending a locator of our chain
+    FindFork(Vec<Hash>),
+
+    /// Response to FindFork, the height the two chains start to differ at
+    ForkPoint(u64),
+
+    /// Ask a node to report all the other nodes it knows about, introducing ourselves and naming the address we dialed
+    DiscoverNodes(NodeInfo, String),
+
+    /// Event pushed to a subscribed connection
+    Event(ChainEvent),
 
     /// Ask a node to send a block with the specified height
-    FetchBlock(usize),
+    FetchBlock(u64),
+
+    /// Ask a node for the addresses of the nodes it has been able to reach
+    GetAddr,
 
-    /// Ask the node to prepare the optimal block template with the coinbase transaction paying the specified public key
-    FetchTemplate(PublicKey),
+    /// Response to GetAddr, also accepted unsolicited
+    Addr(Vec<String>),
+
+    /// Tell a node we are shutting down, so it drops the connection right away
+    Goodbye,
+
+    /// Ask a node for the full blocks and transactions it announced
+    GetData(Vec<Inventory>),
+
+    /// Ask the node to prepare the optimal block template with the coinbase transaction as specified
+    FetchTemplate(CoinbaseSpec),
 
     /// Fetch all UTXOs belonging to a public key
     FetchUTXOs(PublicKey),
 
+    /// Ask for the confirmed transactions of a public key, newest first: key, offset and page size
+    FetchHistory(PublicKey, u64, u64),
+
+    /// Response to FetchHistory, None if the node does not index public keys
+    History(Option<HistoryPage>),
+
+    /// Look a transaction up by hash, in the mempool or in the chain
+    GetTransaction(Hash),
+
+    /// Response to GetTransaction, None if the node has never seen the transaction
+    FoundTransaction(Option<TransactionInfo>),
+
+    /// Announce blocks and transactions by hash, peers fetch the ones they miss with GetData
+    Inv(Vec<Inventory>),
+
     /// Broadcast a new block to other nodes
     NewBlock(Block),
 
+    /// A block sent as its header and short transaction ids, rebuilt from the receiver's mempool
+    NewCompactBlock(CompactBlock),
+
+    /// Ask for the transactions of a compact block missing from our mempool, by position in the block
+    GetBlockTransactions(Hash, Vec<u32>),
+
+    /// Response to GetBlockTransactions, in the order they were asked for
+    BlockTransactions(Hash, Vec<Transaction>),
+
     /// Broadcast a new transaction to other nodes
     NewTransaction(Transaction),
 
-    /// Response to DiscoverNodes
-    NodeList(HashSet<String>),
+    /// Response to DiscoverNodes, introducing the dialed node
+    NodeList(NodeInfo, HashSet<String>),
+
+    /// Wrap a message expecting an answer, so the answer can be matched by its id
+    Request(u64, Box<Message>),
 
-    /// Submit a mined block to a node
-    SubmitTemplate(Block, PublicKey),
+    /// Answer to the Request with the same id
+    Response(u64, Box<Message>),
+
+    /// Submit a mined block to a node, signed by the miner's key over the block hash
+    SubmitTemplate(Block, PublicKey, Signature),
 
     /// Send a transaction to the network
     SubmitTransaction(Transaction),
 
+    /// Turn the connection into a stream of `Event`s about the given topics, all of them if empty
+    Subscribe(Vec<EventTopic>),
+
+    /// Turn the connection into a stream of `Template`s with the coinbase as specified, a fresh one
+    /// whenever the tip or the mempool changes. Mined blocks are still submitted over it
+    SubscribeTemplates(CoinbaseSpec),
+
     /// Block template
     Template(Block),
 
@@ -56,6 +225,45 @@ pub enum Message {
 }
 impl Message {
 
+    /// Name of the variant, for logs and metrics. Requests and responses go by the message they wrap
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::AskTip(_) => "AskTip",
+            Message::Tip(_) => "Tip",
+            Message::FindFork(_) => "FindFork",
+            Message::ForkPoint(_) => "ForkPoint",
+            Message::DiscoverNodes(..) => "DiscoverNodes",
+            Message::Event(_) => "Event",
+            Message::FetchBlock(_) => "FetchBlock",
+            Message::GetAddr => "GetAddr",
+            Message::Addr(_) => "Addr",
+            Message::Goodbye => "Goodbye",
+            Message::GetData(_) => "GetData",
+            Message::FetchTemplate(_) => "FetchTemplate",
+            Message::FetchUTXOs(_) => "FetchUTXOs",
+            Message::FetchHistory(..) => "FetchHistory",
+            Message::History(_) => "History",
+            Message::GetTransaction(_) => "GetTransaction",
+            Message::FoundTransaction(_) => "FoundTransaction",
+            Message::Inv(_) => "Inv",
+            Message::NewBlock(_) => "NewBlock",
+            Message::NewCompactBlock(_) => "NewCompactBlock",
+            Message::GetBlockTransactions(..) => "GetBlockTransactions",
+            Message::BlockTransactions(..) => "BlockTransactions",
+            Message::NewTransaction(_) => "NewTransaction",
+            Message::NodeList(..) => "NodeList",
+            Message::Request(_, message) | Message::Response(_, message) => message.kind(),
+            Message::SubmitTemplate(..) => "SubmitTemplate",
+            Message::SubmitTransaction(_) => "SubmitTransaction",
+            Message::Subscribe(_) => "Subscribe",
+            Message::SubscribeTemplates(_) => "SubscribeTemplates",
+            Message::Template(_) => "Template",
+            Message::TemplateValidity(_) => "TemplateValidity",
+            Message::UTXOs(_) => "UTXOs",
+            Message::ValidateTemplate(_) => "ValidateTemplate",
+        }
+    }
+
     /// We will use length-prefixed encoding for message
     pub fn encode(&self) -> Result<Vec<u8>, ciborium::ser::Error<IoError>> {
         let mut bytes = Vec::new();
@@ -78,7 +286,7 @@ impl Message {
     pub fn receive(stream: &mut impl Read) -> Result<Self, ciborium::de::Error<IoError>> {
         let mut len_bytes = [0u8; 8];
         stream.read_exact(&mut len_bytes)?;
-        let len = u64::from_be_bytes(len_bytes) as usize;
+        let len = checked_length(len_bytes)?;
         let mut data = vec![0u8; len];
         stream.read_exact(&mut data)?;
         Self::decode(&data)
@@ -95,9 +303,69 @@ impl Message {
     pub async fn receive_async(stream: &mut (impl AsyncRead + Unpin)) -> Result<Self, ciborium::de::Error<IoError>> {
         let mut len_bytes = [0u8; 8];
         stream.read_exact(&mut len_bytes).await?;
-        let len = u64::from_be_bytes(len_bytes) as usize;
+        let len = checked_length(len_bytes)?;
         let mut data = vec![0u8; len];
         stream.read_exact(&mut data).await?;
         Self::decode(&data)
     }
-}
\ No newline at end of file
+}
+
+/// Length of a frame from its prefix, refusing anything we would not allocate for
+pub(crate) fn checked_length(len_bytes: [u8; 8]) -> Result<usize, IoError> {
+    let len = u64::from_be_bytes(len_bytes);
+    if len > MAX_MESSAGE_SIZE as u64 {
+        return Err(IoError::new(IoErrorKind::InvalidData, format!("message of {} bytes is too large", len)));
+    }
+    Ok(len as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::PrivateKey;
+    use super::*;
+
+    fn spec(weights: &[u32]) -> CoinbaseSpec {
+        CoinbaseSpec {
+            payees: weights.iter().map(|weight| (PrivateKey::new_key().public_key(), *weight)).collect(),
+            data: vec![],
+        }
+    }
+
+    fn values(outputs: &[TransactionOutput]) -> Vec<u64> {
+        outputs.iter().map(|output| output.value).collect()
+    }
+
+    #[test]
+    fn splits_by_weight() {
+        let outputs = spec(&[1, 3]).outputs(4_000).unwrap();
+        assert_eq!(values(&outputs), vec![1_000, 3_000]);
+    }
+
+    #[test]
+    fn gives_the_remainder_to_the_first_payee() {
+        assert_eq!(values(&spec(&[1, 1, 1]).outputs(100).unwrap()), vec![34, 33, 33]);
+        assert_eq!(values(&spec(&[1, 2]).outputs(1).unwrap()), vec![1, 0]);
+        // The largest amounts still add up, the products do not fit in a u64
+        let outputs = spec(&[u32::MAX, u32::MAX, 1]).outputs(u64::MAX).unwrap();
+        assert_eq!(outputs.iter().map(|output| output.value as u128).sum::<u128>(), u64::MAX as u128);
+    }
+
+    #[test]
+    fn leaves_out_payees_without_weight() {
+        let spec = spec(&[0, 1, 2]);
+        let outputs = spec.outputs(10).unwrap();
+        assert_eq!(values(&outputs), vec![4, 6]);
+        assert_eq!(outputs[0].public_key, spec.payees[1].0);
+    }
+
+    #[test]
+    fn refuses_nobody_to_pay_and_too_much_data() {
+        assert!(spec(&[]).outputs(10).is_none());
+        assert!(spec(&[0, 0]).outputs(10).is_none());
+        let mut spec = spec(&[1]);
+        spec.data = vec![0; crate::MAX_COINBASE_DATA];
+        assert!(spec.outputs(10).is_some());
+        spec.data.push(0);
+        assert!(spec.outputs(10).is_none());
+    }
+}
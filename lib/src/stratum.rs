@@ -0,0 +1,222 @@
+//! Stratum-like protocol between a mining pool and its miners.
+//!
+//! Messages are JSON objects, one per line. Calls carry an `id` when they expect a reply, and
+//! a null `id` when they are notifications. Replies carry the `id` of the call, its `result`, and
+//! an `error` of `[code, message, null]` when the call failed.
+//!
+//! | Method              | From  | Params                                   | Result                    |
+//! |---------------------|-------|------------------------------------------|---------------------------|
+//! | `mining.subscribe`  | miner | previous result when reconnecting        | `{"nonce_prefix": <u16>}` |
+//! | `mining.authorize`  | miner | worker, password                         | `true`                    |
+//! | `mining.submit`     | miner | worker, job id, nonce                    | `true`                    |
+//! | `mining.set_target` | pool  | share target                             |                           |
+//! | `mining.notify`     | pool  | job id, header, whether to drop old jobs |                           |
+//!
+//! A worker is named after the public key its share of the rewards goes to, in the base64 of its
+//! compressed form, optionally followed by a dot and the name of the rig. Headers are the hex of
+//! their canonical encoding, targets 64 hex digits and nonces 16. The nonces a miner tries must
+//! start with the 16 bits of the prefix it got when subscribing, so no two miners search the same
+//! work. A miner reconnecting asks for its previous prefix back, so the shares it found in the
+//! meantime still count, which it gets unless another connected miner holds it. A pool with every
+//! prefix in use refuses further subscriptions. Any hash within the share target counts as a share.
+
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::ops::Range;
+use primitive_types::U256;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use crate::encoding::{Decode, Encode};
+use crate::types::BlockHeader;
+
+pub const SUBSCRIBE: &str = "mining.subscribe";
+pub const AUTHORIZE: &str = "mining.authorize";
+pub const SUBMIT: &str = "mining.submit";
+pub const SET_TARGET: &str = "mining.set_target";
+pub const NOTIFY: &str = "mining.notify";
+
+/// Longest line accepted, a job takes well under a kilobyte
+pub const MAX_LINE_LENGTH: u64 = 16 * 1024;
+
+/// Method call, or notification when it has no id
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Call {
+    pub id: Option<u64>,
+    pub method: String,
+    #[serde(default)]
+    pub params: Vec<Value>,
+}
+
+/// Answer to the call with the same id
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Reply {
+    pub id: u64,
+    pub result: Value,
+    pub error: Option<StratumError>,
+}
+impl Reply {
+    pub fn ok(id: u64, result: impl Serialize) -> Self {
+        Reply { id, result: serde_json::to_value(result).unwrap_or(Value::Null), error: None }
+    }
+
+    pub fn error(id: u64, error: StratumError) -> Self {
+        Reply { id, result: Value::Null, error: Some(error) }
+    }
+}
+
+/// Anything a pool or a miner may read off the connection
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Line {
+    Call(Call),
+    Reply(Reply),
+}
+
+/// Error codes of Stratum mining pools
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StratumError(pub i32, pub String, pub Option<Value>);
+impl StratumError {
+    pub const OTHER: i32 = 20;
+    pub const STALE: i32 = 21;
+    pub const DUPLICATE: i32 = 22;
+    pub const LOW_DIFFICULTY: i32 = 23;
+    pub const UNAUTHORIZED: i32 = 24;
+    pub const NOT_SUBSCRIBED: i32 = 25;
+
+    pub fn new(code: i32, message: impl Into<String>) -> Self {
+        StratumError(code, message.into(), None)
+    }
+
+    pub fn code(&self) -> i32 {
+        self.0
+    }
+
+    fn invalid_params(method: &str) -> Self {
+        StratumError::new(Self::OTHER, format!("invalid params for {}", method))
+    }
+}
+impl std::fmt::Display for StratumError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} (code {})", self.1, self.0)
+    }
+}
+
+/// Result of `mining.subscribe`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Subscription {
+    pub nonce_prefix: u16,
+}
+impl Subscription {
+    /// The nonces the subscriber may try
+    pub fn nonces(&self) -> Range<u64> {
+        let start = (self.nonce_prefix as u64) << 48;
+        start..start.saturating_add(1 << 48)
+    }
+
+    pub fn owns(&self, nonce: u64) -> bool {
+        nonce >> 48 == self.nonce_prefix as u64
+    }
+}
+
+/// Work handed to miners with `mining.notify`
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub id: String,
+    pub header: BlockHeader,
+    /// Whether work on earlier jobs is stale
+    pub clean: bool,
+}
+impl Job {
+    pub fn to_call(&self) -> Call {
+        notification(NOTIFY, vec![self.id.clone().into(), hex::encode(self.header.encoded()).into(), self.clean.into()])
+    }
+
+    pub fn from_params(params: &[Value]) -> Result<Self, StratumError> {
+        let invalid = || StratumError::invalid_params(NOTIFY);
+        match params {
+            [Value::String(id), Value::String(header), Value::Bool(clean)] => {
+                let header = hex::decode(header).ok()
+                    .and_then(|bytes| BlockHeader::decode_all(&bytes).ok())
+                    .ok_or_else(invalid)?;
+                Ok(Job { id: id.clone(), header, clean: *clean })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// A nonce a miner found for a job, sent with `mining.submit`
+#[derive(Clone, Debug)]
+pub struct Share {
+    pub worker: String,
+    pub job_id: String,
+    pub nonce: u64,
+}
+impl Share {
+    pub fn to_call(&self, id: u64) -> Call {
+        Call {
+            id: Some(id),
+            method: SUBMIT.to_string(),
+            params: vec![self.worker.clone().into(), self.job_id.clone().into(), format!("{:016x}", self.nonce).into()],
+        }
+    }
+
+    pub fn from_params(params: &[Value]) -> Result<Self, StratumError> {
+        let invalid = || StratumError::invalid_params(SUBMIT);
+        match params {
+            [Value::String(worker), Value::String(job_id), Value::String(nonce)] => Ok(Share {
+                worker: worker.clone(),
+                job_id: job_id.clone(),
+                nonce: u64::from_str_radix(nonce, 16).map_err(|_| invalid())?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+pub fn set_target(target: U256) -> Call {
+    notification(SET_TARGET, vec![hex::encode(target.to_big_endian()).into()])
+}
+
+pub fn target_from_params(params: &[Value]) -> Result<U256, StratumError> {
+    match params {
+        [Value::String(target)] if target.len() == 64 => {
+            U256::from_str_radix(target, 16).map_err(|_| StratumError::invalid_params(SET_TARGET))
+        }
+        _ => Err(StratumError::invalid_params(SET_TARGET)),
+    }
+}
+
+fn notification(method: &str, params: Vec<Value>) -> Call {
+    Call { id: None, method: method.to_string(), params }
+}
+
+/// Reads one JSON line after another. Not cancel safe, a line may be lost halfway
+pub struct LineReader<R> {
+    reader: BufReader<R>,
+    buffer: String,
+}
+impl<R: AsyncRead + Unpin> LineReader<R> {
+    pub fn new(reader: R) -> Self {
+        LineReader { reader: BufReader::new(reader), buffer: String::new() }
+    }
+
+    /// The next line, None once the other side hung up
+    pub async fn receive(&mut self) -> IoResult<Option<Line>> {
+        self.buffer.clear();
+        let read = (&mut self.reader).take(MAX_LINE_LENGTH).read_line(&mut self.buffer).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        if !self.buffer.ends_with('\n') && read as u64 == MAX_LINE_LENGTH {
+            return Err(IoError::new(IoErrorKind::InvalidData, "line too long"));
+        }
+        serde_json::from_str(&self.buffer).map(Some).map_err(|e| IoError::new(IoErrorKind::InvalidData, e))
+    }
+}
+
+pub async fn send_line(writer: &mut (impl AsyncWrite + Unpin), line: &impl Serialize) -> IoResult<()> {
+    let mut bytes = serde_json::to_vec(line).map_err(|e| IoError::new(IoErrorKind::InvalidData, e))?;
+    bytes.push(b'\n');
+    writer.write_all(&bytes).await
+}
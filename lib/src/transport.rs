@@ -0,0 +1,350 @@
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::net::SocketAddr;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use crate::crypto::{PrivateKey, PublicKey};
+use crate::network::{checked_length, Message};
+
+/// Opens an encrypted connection. Read as the length prefix of a plaintext message it is far beyond
+/// `MAX_MESSAGE_SIZE`, so a listener can tell both kinds of clients apart from the first 8 bytes
+pub const HANDSHAKE_MAGIC: [u8; 8] = [0xFF, b'B', b'T', b'C', b'N', b'O', b'I', b'S'];
+/// Noise protocol name, hashed into the handshake so both sides agree on every primitive
+const PROTOCOL_NAME: &[u8] = b"Noise_XX_secp256k1_ChaChaPoly_SHA256";
+/// Compressed secp256k1 public key
+const KEY_LEN: usize = 33;
+/// Poly1305 authentication tag
+const TAG_LEN: usize = 16;
+
+/// One direction of an encrypted connection: a key and a counter used as nonce
+struct CipherState {
+    cipher: ChaCha20Poly1305,
+    nonce: u64,
+}
+impl CipherState {
+    fn new(key: [u8; 32]) -> Self {
+        CipherState { cipher: ChaCha20Poly1305::new(&Key::from(key)), nonce: 0 }
+    }
+
+    fn next_nonce(&mut self) -> IoResult<Nonce> {
+        // A nonce must never be used twice with the same key
+        if self.nonce == u64::MAX {
+            return Err(IoError::new(IoErrorKind::InvalidData, "nonce exhausted, reconnect"));
+        }
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.nonce.to_le_bytes());
+        self.nonce += 1;
+        Ok(Nonce::from(nonce))
+    }
+
+    fn encrypt(&mut self, ad: &[u8], plaintext: &[u8]) -> IoResult<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+        self.cipher.encrypt(&nonce, Payload { msg: plaintext, aad: ad }).map_err(|_| {
+            IoError::new(IoErrorKind::InvalidData, "Failed to encrypt message")
+        })
+    }
+
+    fn decrypt(&mut self, ad: &[u8], ciphertext: &[u8]) -> IoResult<Vec<u8>> {
+        let nonce = self.next_nonce()?;
+        self.cipher.decrypt(&nonce, Payload { msg: ciphertext, aad: ad }).map_err(|_| {
+            IoError::new(IoErrorKind::InvalidData, "Message failed authentication")
+        })
+    }
+}
+
+/// Running hash and chaining key of a Noise handshake
+struct HandshakeState {
+    /// Hash of everything sent so far, authenticated along with every encrypted handshake payload
+    h: [u8; 32],
+    /// Chaining key, every Diffie-Hellman result is mixed into it
+    ck: [u8; 32],
+    cipher: Option<CipherState>,
+}
+impl HandshakeState {
+    fn new() -> Self {
+        let h: [u8; 32] = Sha256::digest(PROTOCOL_NAME).into();
+        HandshakeState { h, ck: h, cipher: None }
+    }
+
+    /// Noise HKDF, which is RFC 5869 with the chaining key as salt and no info
+    fn hkdf(&self, ikm: &[u8]) -> ([u8; 32], [u8; 32]) {
+        let mut okm = [0u8; 64];
+        Hkdf::<Sha256>::new(Some(&self.ck), ikm)
+            .expand(&[], &mut okm)
+            .expect("64 bytes is a valid HKDF output length");
+        (okm[..32].try_into().unwrap(), okm[32..].try_into().unwrap())
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.h = Sha256::new().chain_update(self.h).chain_update(data).finalize().into();
+    }
+
+    fn mix_key(&mut self, shared_secret: &[u8]) {
+        let (ck, key) = self.hkdf(shared_secret);
+        self.ck = ck;
+        self.cipher = Some(CipherState::new(key));
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> IoResult<Vec<u8>> {
+        let cipher = self.cipher.as_mut().expect("BUG: handshake encrypts before any key was mixed");
+        let ciphertext = cipher.encrypt(&self.h, plaintext)?;
+        self.mix_hash(&ciphertext);
+        Ok(ciphertext)
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> IoResult<Vec<u8>> {
+        let cipher = self.cipher.as_mut().expect("BUG: handshake decrypts before any key was mixed");
+        let plaintext = cipher.decrypt(&self.h, ciphertext)?;
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    /// Keys for both directions once the handshake is done: (initiator to responder, responder to initiator)
+    fn split(self) -> (CipherState, CipherState) {
+        let (first, second) = self.hkdf(&[]);
+        (CipherState::new(first), CipherState::new(second))
+    }
+}
+
+fn read_public_key(bytes: &[u8]) -> IoResult<PublicKey> {
+    PublicKey::from_bytes(bytes).ok_or_else(|| IoError::new(IoErrorKind::InvalidData, "Invalid public key in handshake"))
+}
+
+async fn read_bytes<const N: usize>(stream: &mut (impl AsyncRead + Unpin)) -> IoResult<[u8; N]> {
+    let mut bytes = [0u8; N];
+    stream.read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
+
+/// Receiving side of a connection, decrypting messages if the connection is encrypted
+pub struct MessageReader<R> {
+    inner: R,
+    cipher: Option<CipherState>,
+    /// Length prefix of the first plaintext message, already consumed while looking for the handshake
+    first_length: Option<[u8; 8]>,
+}
+impl<R: AsyncRead + Unpin> MessageReader<R> {
+    pub async fn receive(&mut self) -> IoResult<Message> {
+        let len_bytes = match self.first_length.take() {
+            Some(len_bytes) => len_bytes,
+            None => read_bytes(&mut self.inner).await?,
+        };
+        let mut data = vec![0u8; checked_length(len_bytes)?];
+        self.inner.read_exact(&mut data).await?;
+        if let Some(cipher) = self.cipher.as_mut() {
+            data = cipher.decrypt(&len_bytes, &data)?;
+        }
+        Message::decode(&data).map_err(|e| IoError::new(IoErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+/// Sending side of a connection, encrypting messages if the connection is encrypted
+pub struct MessageWriter<W> {
+    inner: W,
+    cipher: Option<CipherState>,
+}
+impl<W: AsyncWrite + Unpin> MessageWriter<W> {
+    pub async fn send(&mut self, message: &Message) -> IoResult<()> {
+        let mut data = message.encode().map_err(|e| IoError::new(IoErrorKind::InvalidData, e.to_string()))?;
+        if let Some(cipher) = self.cipher.as_mut() {
+            // The length prefix is authenticated too, so it cannot be tampered with either
+            let len_bytes = ((data.len() + TAG_LEN) as u64).to_be_bytes();
+            data = cipher.encrypt(&len_bytes, &data)?;
+        }
+        self.inner.write_all(&(data.len() as u64).to_be_bytes()).await?;
+        self.inner.write_all(&data).await?;
+        Ok(())
+    }
+}
+
+/// A connection carrying `Message`s, either in plaintext for local development or encrypted and
+/// authenticated after a Noise XX handshake between the secp256k1 identity keys of both sides
+pub struct Connection {
+    reader: MessageReader<OwnedReadHalf>,
+    writer: MessageWriter<OwnedWriteHalf>,
+    /// Identity key the other side proved to own, only known for encrypted connections
+    remote_key: Option<PublicKey>,
+}
+impl Connection {
+    /// Dial an address, encrypting the connection when an identity is given
+    pub async fn connect(address: &str, identity: Option<&PrivateKey>, expected: Option<&PublicKey>) -> IoResult<Self> {
+        let stream = TcpStream::connect(address).await?;
+        match identity {
+            Some(identity) => Self::initiate(stream, identity, expected).await,
+            None => Ok(Self::plaintext(stream)),
+        }
+    }
+
+    pub fn plaintext(stream: TcpStream) -> Self {
+        Self::new(stream, None, None, None)
+    }
+
+    /// Run the handshake as the dialing side. With `expected` set, the connection is refused unless
+    /// the other side owns that identity key
+    pub async fn initiate(mut stream: TcpStream, identity: &PrivateKey, expected: Option<&PublicKey>) -> IoResult<Self> {
+        let mut handshake = HandshakeState::new();
+        // -> e
+        let ephemeral = PrivateKey::new_key();
+        let ephemeral_public = ephemeral.public_key().to_bytes();
+        handshake.mix_hash(&ephemeral_public);
+        stream.write_all(&HANDSHAKE_MAGIC).await?;
+        stream.write_all(&ephemeral_public).await?;
+        // <- e, ee, s, es
+        let remote_ephemeral_bytes = read_bytes::<KEY_LEN>(&mut stream).await?;
+        let remote_ephemeral = read_public_key(&remote_ephemeral_bytes)?;
+        handshake.mix_hash(&remote_ephemeral_bytes);
+        handshake.mix_key(&ephemeral.diffie_hellman(&remote_ephemeral));
+        let remote_static = handshake.decrypt_and_hash(&read_bytes::<{ KEY_LEN + TAG_LEN }>(&mut stream).await?)?;
+        let remote_key = read_public_key(&remote_static)?;
+        if let Some(expected) = expected && *expected != remote_key {
+            return Err(IoError::new(IoErrorKind::PermissionDenied, "Remote identity key does not match the expected one"));
+        }
+        handshake.mix_key(&ephemeral.diffie_hellman(&remote_key));
+        handshake.decrypt_and_hash(&read_bytes::<TAG_LEN>(&mut stream).await?)?;
+        // -> s, se
+        let mut reply = handshake.encrypt_and_hash(&identity.public_key().to_bytes())?;
+        handshake.mix_key(&identity.diffie_hellman(&remote_ephemeral));
+        reply.extend(handshake.encrypt_and_hash(&[])?);
+        stream.write_all(&reply).await?;
+        let (sending, receiving) = handshake.split();
+        Ok(Self::new(stream, Some((sending, receiving)), Some(remote_key), None))
+    }
+
+    /// Take an incoming connection, running the responding side of the handshake if the client
+    /// starts one and falling back to plaintext otherwise
+    pub async fn accept(mut stream: TcpStream, identity: &PrivateKey) -> IoResult<Self> {
+        let first_bytes = read_bytes::<8>(&mut stream).await?;
+        if first_bytes != HANDSHAKE_MAGIC {
+            return Ok(Self::new(stream, None, None, Some(first_bytes)));
+        }
+        let mut handshake = HandshakeState::new();
+        // -> e
+        let remote_ephemeral_bytes = read_bytes::<KEY_LEN>(&mut stream).await?;
+        let remote_ephemeral = read_public_key(&remote_ephemeral_bytes)?;
+        handshake.mix_hash(&remote_ephemeral_bytes);
+        // <- e, ee, s, es
+        let ephemeral = PrivateKey::new_key();
+        let mut reply = ephemeral.public_key().to_bytes();
+        handshake.mix_hash(&reply);
+        handshake.mix_key(&ephemeral.diffie_hellman(&remote_ephemeral));
+        reply.extend(handshake.encrypt_and_hash(&identity.public_key().to_bytes())?);
+        handshake.mix_key(&identity.diffie_hellman(&remote_ephemeral));
+        reply.extend(handshake.encrypt_and_hash(&[])?);
+        stream.write_all(&reply).await?;
+        // -> s, se
+        let remote_static = handshake.decrypt_and_hash(&read_bytes::<{ KEY_LEN + TAG_LEN }>(&mut stream).await?)?;
+        let remote_key = read_public_key(&remote_static)?;
+        handshake.mix_key(&ephemeral.diffie_hellman(&remote_key));
+        handshake.decrypt_and_hash(&read_bytes::<TAG_LEN>(&mut stream).await?)?;
+        let (receiving, sending) = handshake.split();
+        Ok(Self::new(stream, Some((sending, receiving)), Some(remote_key), None))
+    }
+
+    fn new(stream: TcpStream, ciphers: Option<(CipherState, CipherState)>, remote_key: Option<PublicKey>, first_length: Option<[u8; 8]>) -> Self {
+        let (reader, writer) = stream.into_split();
+        let (sending, receiving) = ciphers.unzip();
+        Connection {
+            reader: MessageReader { inner: reader, cipher: receiving, first_length },
+            writer: MessageWriter { inner: writer, cipher: sending },
+            remote_key,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.remote_key.is_some()
+    }
+
+    pub fn remote_key(&self) -> Option<&PublicKey> {
+        self.remote_key.as_ref()
+    }
+
+    pub fn peer_addr(&self) -> IoResult<SocketAddr> {
+        self.reader.inner.peer_addr()
+    }
+
+    pub async fn send(&mut self, message: &Message) -> IoResult<()> {
+        self.writer.send(message).await
+    }
+
+    pub async fn receive(&mut self) -> IoResult<Message> {
+        self.reader.receive().await
+    }
+
+    /// Separate both directions, to read and write from different tasks
+    pub fn into_split(self) -> (MessageReader<OwnedReadHalf>, MessageWriter<OwnedWriteHalf>) {
+        (self.reader, self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use super::*;
+
+    /// A listener on a free local port, with its address
+    async fn listen() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        (listener, address)
+    }
+
+    fn addresses(message: Message) -> Vec<String> {
+        match message {
+            Message::Addr(addresses) => addresses,
+            message => panic!("expected Addr, got {}", message.kind()),
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_proves_both_identities_and_carries_messages() {
+        let (listener, address) = listen().await;
+        let (server, client) = (PrivateKey::new_key(), PrivateKey::new_key());
+        let server_key = server.public_key();
+        let accepting = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = Connection::accept(stream, &server).await.unwrap();
+            let received = addresses(conn.receive().await.unwrap());
+            conn.send(&Message::Addr(vec![format!("{} back", received[0])])).await.unwrap();
+            conn.remote_key().cloned()
+        });
+        let mut conn = Connection::connect(&address, Some(&client), Some(&server_key)).await.unwrap();
+        assert!(conn.is_encrypted());
+        assert_eq!(conn.remote_key(), Some(&server_key));
+        conn.send(&Message::Addr(vec!["hello".to_string()])).await.unwrap();
+        assert_eq!(addresses(conn.receive().await.unwrap()), vec!["hello back".to_string()]);
+        assert_eq!(accepting.await.unwrap(), Some(client.public_key()));
+    }
+
+    #[tokio::test]
+    async fn refuses_a_node_with_another_key_than_the_pinned_one() {
+        let (listener, address) = listen().await;
+        let accepting = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            Connection::accept(stream, &PrivateKey::new_key()).await.map(|_| ())
+        });
+        let pinned = PrivateKey::new_key().public_key();
+        let e = Connection::connect(&address, Some(&PrivateKey::new_key()), Some(&pinned)).await.err().unwrap();
+        assert_eq!(e.kind(), IoErrorKind::PermissionDenied);
+        // The dialing side gave up before proving itself, so the other side got nothing either
+        assert!(accepting.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn accepts_plaintext_clients() {
+        let (listener, address) = listen().await;
+        let accepting = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = Connection::accept(stream, &PrivateKey::new_key()).await.unwrap();
+            (conn.is_encrypted(), addresses(conn.receive().await.unwrap()))
+        });
+        let mut conn = Connection::connect(&address, None, None).await.unwrap();
+        assert!(!conn.is_encrypted());
+        conn.send(&Message::Addr(vec!["hello".to_string()])).await.unwrap();
+        // The length prefix read while looking for the handshake is not lost
+        assert_eq!(accepting.await.unwrap(), (false, vec!["hello".to_string()]));
+    }
+}
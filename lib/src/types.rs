@@ -1,6 +1,6 @@
-pub use block::{Block, BlockHeader};
-pub use blockchain::Blockchain;
-pub use transaction::{Transaction, TransactionInput, TransactionOutput};
+pub use block::{u256_to_f64, Block, BlockHeader, CompactBlock, HeaderHasher};
+pub use blockchain::{Blockchain, ChainEvent, EventTopic, EvictionReason, HistoryEntry, TxLocation};
+pub use transaction::{CoinbaseData, Transaction, TransactionInput, TransactionOutput};
 
 mod block;
 mod blockchain;
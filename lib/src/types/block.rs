@@ -3,20 +3,34 @@ use std::collections::HashMap;
 use chrono::{DateTime, Utc};
 use primitive_types::U256;
 use serde::{Deserialize, Serialize};
-use crate::crypto::{Hash, MerkleRoot};
+use crate::crypto::{Hash, Hasher, MerkleRoot};
+use crate::encoding::{canonical_serde, encode_list, Decode, Decoder, Encode};
 use crate::error::BtcError;
 use crate::types::transaction::{Transaction, TransactionOutput};
 use crate::util::Saveable;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(remote = "Self")]
 pub struct Block { pub header: BlockHeader, pub transactions: Vec<Transaction> }
 impl Block {
     pub fn new(header: BlockHeader, transactions: Vec<Transaction>) -> Self {
         Block { header, transactions }
     }
 
+    /// A block goes by the hash of its header, which covers the transactions through the Merkle root
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        self.header.hash()
+    }
+
+    /// Set the extra nonce of the coinbase and the Merkle root that follows, for a header never
+    /// tried before. False if the coinbase carries no data to hold an extra nonce
+    pub fn set_extra_nonce(&mut self, extra_nonce: u64) -> bool {
+        let Some(coinbase) = self.transactions.first_mut().and_then(|coinbase| coinbase.coinbase.as_mut()) else {
+            return false;
+        };
+        coinbase.extra_nonce = extra_nonce;
+        self.header.merkle_root = MerkleRoot::calculate(&self.transactions);
+        true
     }
 
     /// Verify all transactions in the block
@@ -29,6 +43,9 @@ impl Block {
         // verify coinbase transaction
         self.verify_coinbase_transaction(predicted_block_height, utxos)?;
         for transaction in self.transactions.iter().skip(1) {
+            if transaction.coinbase.is_some() {
+                return Err(BtcError::InvalidTransaction);
+            }
             let mut input_value = 0;
             let mut output_value = 0;
             for input in &transaction.inputs {
@@ -63,10 +80,13 @@ impl Block {
     pub fn verify_coinbase_transaction(&self, predicted_block_height: u64, utxos: &HashMap<Hash,  (bool, TransactionOutput)>) -> crate::error::Result<()> {
         // Coinbase tx is the first transaction in the block
         let coinbase_transaction = &self.transactions[0];
-        if coinbase_transaction.inputs.len() != 0 {
+        if !coinbase_transaction.inputs.is_empty() {
             return Err(BtcError::InvalidTransaction);
         }
-        if coinbase_transaction.outputs.len() == 0 {
+        if coinbase_transaction.outputs.is_empty() {
+            return Err(BtcError::InvalidTransaction);
+        }
+        if coinbase_transaction.coinbase.as_ref().is_some_and(|coinbase| coinbase.data.len() > crate::MAX_COINBASE_DATA) {
             return Err(BtcError::InvalidTransaction);
         }
         let miner_fees = self.calculate_miner_fees(utxos)?;
@@ -110,11 +130,29 @@ impl Block {
         Ok(input_value - output_value)
     }
 }
+impl Encode for Block {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.header.encode(out);
+        encode_list(&self.transactions, out);
+    }
+}
+impl Decode for Block {
+    fn decode(decoder: &mut Decoder) -> crate::error::Result<Self> {
+        Ok(Block { header: BlockHeader::decode(decoder)?, transactions: decoder.list()? })
+    }
+}
+canonical_serde!(Block);
 /// Save and load expecting CBOR from ciborium as format
 impl Saveable for Block {
+    const HEADER: bool = true;
+    /// 1: CBOR of the header and the transactions.
+    /// 2: CBOR byte string holding the canonical encoding
+    /// 3: same, coinbases may carry data
+    const FORMAT_VERSION: u32 = 3;
+
     fn load<I: Read>(reader: I) -> IoResult<Self> {
-        ciborium::de::from_reader(reader).map_err(|_| {
-            IoError::new(IoErrorKind::InvalidData, "Failed to deserialize Block")
+        ciborium::de::from_reader(reader).map_err(|e| {
+            IoError::new(IoErrorKind::InvalidData, format!("Failed to deserialize Block: {}", e))
         })
     }
 
@@ -123,9 +161,20 @@ impl Saveable for Block {
             IoError::new(IoErrorKind::InvalidData, "Failed to serialize Block")
         })
     }
+
+    fn migrate<I: Read>(version: u32, reader: I) -> IoResult<Self> {
+        match version {
+            // The proof of work was done on the old header hash
+            0 | 1 => Err(crate::util::not_migratable(version, "block hashes changed with the canonical encoding")),
+            // Encoded the same, there was just no coinbase data yet
+            2 => Self::load(reader),
+            _ => Err(crate::util::unsupported_version(version, Self::FORMAT_VERSION)),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(remote = "Self")]
 pub struct BlockHeader {
     /// The time when the block was created
     pub timestamp: DateTime<Utc>,
@@ -151,7 +200,16 @@ impl BlockHeader {
     }
 
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        Hash::digest(&self.encoded())
+    }
+
+    /// Expected number of hashes needed to mine a block at this target: 2^256 / (target + 1)
+    pub fn work(&self) -> U256 {
+        // 2^256 does not fit, but (2^256 - target - 1) / (target + 1) + 1 is the same value
+        match self.target.checked_add(U256::one()) {
+            Some(divisor) => (!self.target / divisor) + 1,
+            None => U256::one(),
+        }
     }
 
     pub fn mine(&mut self, steps: usize) -> bool {
@@ -159,17 +217,234 @@ impl BlockHeader {
         if self.hash().matches_target(self.target) {
             return true;
         }
+        let mut hasher = HeaderHasher::new(self);
         for _ in 0..steps {
             if let Some(new_nonce) = self.nonce.checked_add(1) {
                 self.nonce = new_nonce;
             } else {
                 self.nonce = 0;
-                self.timestamp = Utc::now()
+                self.timestamp = Utc::now();
+                hasher = HeaderHasher::new(self);
             }
-            if self.hash().matches_target(self.target) {
+            if hasher.hash_with_nonce(self.nonce).matches_target(self.target) {
                 return true;
             }
         }
         false
     }
-}
\ No newline at end of file
+}
+
+/// A 256 bit number like a target or an amount of work as a float, for difficulties, rates and
+/// gauges. The bits past the 53 a float holds are lost, which none of those need
+pub fn u256_to_f64(value: U256) -> f64 {
+    value.0.iter().rev().fold(0.0, |acc, limb| acc * 18_446_744_073_709_551_616.0 + *limb as f64)
+}
+
+/// Hashes a header with one nonce after another. The header is encoded once, the nonce coming last
+/// only its bytes are rewritten, and the SHA-256 state after the first 64 bytes is reused
+#[derive(Clone)]
+pub struct HeaderHasher {
+    encoded: Vec<u8>,
+    /// State after the first SHA-256 block, none of which depends on the nonce
+    prefix: Hasher,
+}
+impl HeaderHasher {
+    /// Offset of the nonce in the encoding of a header
+    const NONCE_OFFSET: usize = 108;
+    /// Bytes SHA-256 processes at once
+    const SHA256_BLOCK: usize = 64;
+
+    pub fn new(header: &BlockHeader) -> Self {
+        let encoded = header.encoded();
+        let mut prefix = Hasher::new();
+        prefix.update(&encoded[..Self::SHA256_BLOCK]);
+        HeaderHasher { encoded, prefix }
+    }
+
+    /// Hash of the header with the given nonce in place of its own
+    pub fn hash_with_nonce(&mut self, nonce: u64) -> Hash {
+        self.encoded[Self::NONCE_OFFSET..].copy_from_slice(&nonce.to_le_bytes());
+        let mut hasher = self.prefix.clone();
+        hasher.update(&self.encoded[Self::SHA256_BLOCK..]);
+        hasher.finish()
+    }
+}
+impl Encode for BlockHeader {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.timestamp.encode(out);
+        self.prev_block_hash.encode(out);
+        self.merkle_root.encode(out);
+        self.target.encode(out);
+        out.extend_from_slice(&self.nonce.to_le_bytes());
+    }
+}
+impl Decode for BlockHeader {
+    fn decode(decoder: &mut Decoder) -> crate::error::Result<Self> {
+        Ok(BlockHeader {
+            timestamp: DateTime::decode(decoder)?,
+            prev_block_hash: Hash::decode(decoder)?,
+            merkle_root: MerkleRoot::decode(decoder)?,
+            target: U256::decode(decoder)?,
+            nonce: decoder.u64()?,
+        })
+    }
+}
+canonical_serde!(BlockHeader);
+
+/// Block relayed as its header and short ids of its transactions, since the receiver usually
+/// holds most of them in its mempool already
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CompactBlock {
+    /// Hash of the full block, it also salts the short ids
+    pub hash: Hash,
+    pub header: BlockHeader,
+    /// Short id of every transaction, in block order
+    pub short_ids: Vec<u64>,
+    /// Transactions the receiver cannot have yet, like the coinbase, by position in the block
+    pub prefilled: Vec<(u32, Transaction)>,
+}
+impl CompactBlock {
+    pub fn new(block: &Block) -> Self {
+        let hash = block.hash();
+        CompactBlock {
+            hash,
+            header: block.header.clone(),
+            short_ids: block.transactions.iter().map(|tx| Self::short_id(&hash, &tx.hash())).collect(),
+            prefilled: block.transactions.first().map(|coinbase| (0, coinbase.clone())).into_iter().collect(),
+        }
+    }
+
+    /// 48 bits of the transaction hash salted with the block hash, so colliding ids can't be crafted ahead of time
+    pub fn short_id(block_hash: &Hash, tx_hash: &Hash) -> u64 {
+        let mut salted = block_hash.encoded();
+        tx_hash.encode(&mut salted);
+        let bytes = Hash::digest(&salted).encoded();
+        let mut id = [0u8; 8];
+        id[..6].copy_from_slice(&bytes[..6]);
+        u64::from_le_bytes(id)
+    }
+
+    /// Place the prefilled transactions and those we know by short id, leaving the others empty
+    pub fn reconstruct<'a>(&self, known: impl IntoIterator<Item = &'a Transaction>) -> Vec<Option<Transaction>> {
+        let mut slots: Vec<Option<Transaction>> = vec![None; self.short_ids.len()];
+        for (index, tx) in &self.prefilled {
+            if let Some(slot) = slots.get_mut(*index as usize) {
+                *slot = Some(tx.clone());
+            }
+        }
+        let positions: HashMap<u64, usize> = self.short_ids.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+        for tx in known {
+            if let Some(&i) = positions.get(&Self::short_id(&self.hash, &tx.hash())) && slots[i].is_none() {
+                slots[i] = Some(tx.clone());
+            }
+        }
+        slots
+    }
+
+    /// Positions of the transactions still to be fetched
+    pub fn missing(slots: &[Option<Transaction>]) -> Vec<u32> {
+        slots.iter().enumerate().filter(|(_, slot)| slot.is_none()).map(|(i, _)| i as u32).collect()
+    }
+
+    /// Assemble the block once every slot is filled. A short id collision shows up as a wrong Merkle
+    /// root, in which case the full block has to be fetched instead
+    pub fn complete(&self, slots: Vec<Option<Transaction>>) -> crate::error::Result<Block> {
+        let transactions = slots.into_iter().collect::<Option<Vec<_>>>().ok_or(BtcError::InvalidBlock)?;
+        if transactions.is_empty() {
+            return Err(BtcError::InvalidBlock);
+        }
+        if MerkleRoot::calculate(&transactions) != self.header.merkle_root {
+            return Err(BtcError::InvalidMerkleRoot);
+        }
+        let block = Block::new(self.header.clone(), transactions);
+        if block.hash() != self.hash {
+            return Err(BtcError::InvalidHash);
+        }
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+    use crate::crypto::PrivateKey;
+    use super::*;
+
+    fn transaction(value: u64) -> Transaction {
+        let public_key = PrivateKey::new_key().public_key();
+        Transaction::new(vec![], vec![TransactionOutput { value, unique_id: Uuid::new_v4(), public_key }])
+    }
+
+    /// A coinbase and `count` other transactions
+    fn block(count: u64) -> Block {
+        let transactions = (0..=count).map(transaction).collect::<Vec<_>>();
+        let header = BlockHeader::new(Utc::now(), 0, Hash::zero(), MerkleRoot::calculate(&transactions), U256::MAX);
+        Block::new(header, transactions)
+    }
+
+    #[test]
+    fn rebuilds_the_block_from_known_transactions() {
+        let block = block(3);
+        let compact = CompactBlock::new(&block);
+        // The order we know them in does not matter, nor do transactions the block does not hold
+        let unrelated = transaction(100);
+        let known = [&block.transactions[3], &unrelated, &block.transactions[1], &block.transactions[2]];
+        let slots = compact.reconstruct(known);
+        assert!(CompactBlock::missing(&slots).is_empty());
+        assert_eq!(compact.complete(slots).unwrap().hash(), block.hash());
+    }
+
+    #[test]
+    fn reports_the_missing_slots() {
+        let block = block(3);
+        let compact = CompactBlock::new(&block);
+        let mut slots = compact.reconstruct([&block.transactions[2]]);
+        // The coinbase comes prefilled
+        assert_eq!(CompactBlock::missing(&slots), vec![1, 3]);
+        assert!(matches!(compact.complete(slots.clone()), Err(BtcError::InvalidBlock)));
+        for index in CompactBlock::missing(&slots) {
+            slots[index as usize] = Some(block.transactions[index as usize].clone());
+        }
+        assert_eq!(compact.complete(slots).unwrap().hash(), block.hash());
+    }
+
+    #[test]
+    fn catches_short_id_collisions() {
+        let block = block(2);
+        let mut compact = CompactBlock::new(&block);
+        // A transaction of our mempool taking the short id of one in the block
+        let impostor = transaction(100);
+        compact.short_ids[1] = CompactBlock::short_id(&compact.hash, &impostor.hash());
+        let slots = compact.reconstruct([&impostor, &block.transactions[2]]);
+        assert!(CompactBlock::missing(&slots).is_empty());
+        assert!(matches!(compact.complete(slots), Err(BtcError::InvalidMerkleRoot)));
+    }
+
+    #[test]
+    fn leaves_duplicate_short_ids_to_be_fetched() {
+        let block = block(2);
+        let mut compact = CompactBlock::new(&block);
+        compact.short_ids[1] = compact.short_ids[2];
+        let slots = compact.reconstruct(&block.transactions);
+        // Only one of the slots sharing an id gets the transaction, the other one is fetched
+        assert_eq!(CompactBlock::missing(&slots).len(), 1);
+    }
+
+    #[test]
+    fn ignores_prefilled_transactions_out_of_range() {
+        let block = block(1);
+        let mut compact = CompactBlock::new(&block);
+        compact.prefilled.push((7, transaction(100)));
+        let slots = compact.reconstruct(&block.transactions);
+        assert_eq!(slots.len(), 2);
+        assert_eq!(compact.complete(slots).unwrap().hash(), block.hash());
+    }
+
+    #[test]
+    fn turns_u256_into_the_closest_float() {
+        assert_eq!(u256_to_f64(U256::zero()), 0.0);
+        assert_eq!(u256_to_f64(U256::from(u64::MAX) + 1), 2f64.powi(64));
+        assert_eq!(u256_to_f64(U256::one() << 200), 2f64.powi(200));
+        assert_eq!(u256_to_f64(U256::MAX), 2f64.powi(256));
+    }
+}
@@ -1,32 +1,124 @@
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use bigdecimal::BigDecimal;
 use chrono::{DateTime, Utc};
 use primitive_types::U256;
 use serde::{Deserialize, Serialize};
 use log::{error, warn};
-use crate::crypto::{Hash, MerkleRoot};
+use crate::crypto::{Hash, MerkleRoot, PublicKey};
 use crate::error::BtcError;
-use crate::MAX_MEMPOOL_TRANSACTION_AGE;
+use crate::{MAX_MEMPOOL_TRANSACTION_AGE, MAX_ORPHAN_BLOCK_AGE, MAX_ORPHAN_BLOCKS};
 use crate::types::block::Block;
 use crate::types::transaction::{Transaction, TransactionOutput};
 use crate::util::Saveable;
 
+/// Where a confirmed transaction sits in the chain
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TxLocation {
+    pub block_hash: Hash,
+    pub height: u64,
+    /// Index of the transaction in its block, the coinbase being 0
+    pub position: u32,
+}
+
+/// A confirmed transaction paying or spending from a public key
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryEntry {
+    pub tx_hash: Hash,
+    pub location: TxLocation,
+    /// Outputs of the transaction paying the key, by hash with their value
+    pub received: Vec<(Hash, u64)>,
+    /// Outputs of the key the transaction spent, by hash with their value
+    pub spent: Vec<(Hash, u64)>,
+}
+impl HistoryEntry {
+    /// Net change of the key's balance
+    pub fn delta(&self) -> i128 {
+        let received: u64 = self.received.iter().map(|(_, value)| value).sum();
+        let spent: u64 = self.spent.iter().map(|(_, value)| value).sum();
+        received as i128 - spent as i128
+    }
+}
+
+/// Something that happened to the chain or the mempool, as told to subscribers
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ChainEvent {
+    /// A block extended the chain
+    BlockConnected { hash: Hash, height: u64 },
+    /// A block left the chain, replaced by a branch with more work
+    BlockDisconnected { hash: Hash, height: u64 },
+    /// A transaction entered the mempool
+    TransactionAccepted { hash: Hash },
+    /// A transaction left the mempool without being confirmed
+    TransactionEvicted { hash: Hash, reason: EvictionReason },
+}
+impl ChainEvent {
+    pub fn topic(&self) -> EventTopic {
+        match self {
+            ChainEvent::BlockConnected { .. } | ChainEvent::BlockDisconnected { .. } => EventTopic::Blocks,
+            ChainEvent::TransactionAccepted { .. } | ChainEvent::TransactionEvicted { .. } => EventTopic::Transactions,
+        }
+    }
+}
+
+/// Why a transaction left the mempool
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionReason {
+    /// Older than MAX_MEMPOOL_TRANSACTION_AGE
+    Expired,
+    /// Another transaction spent the same outputs
+    Replaced,
+    /// The outputs it spent left the chain with a reorganization
+    Reorganized,
+}
+
+/// Group of events a subscriber can ask for
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EventTopic {
+    Blocks,
+    Transactions,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Blockchain {
     utxos: HashMap<Hash, (bool, TransactionOutput)>,
     target: U256,
     blocks: Vec<Block>,
+    /// Height of every block in the chain by hash, rebuilt on load
+    #[serde(default, skip_serializing)]
+    heights: HashMap<Hash, u64>,
+    #[serde(default, skip_serializing)]
+    mempool: Vec<(DateTime<Utc>, Transaction)>,
+    /// Blocks whose parent has not arrived yet and when they arrived, keyed by their own hash
     #[serde(default, skip_serializing)]
-    mempool: Vec<(DateTime<Utc>, Transaction)>
+    orphans: HashMap<Hash, (DateTime<Utc>, Block)>,
+    /// Optional index of confirmed transactions by hash, rebuilt from the blocks when enabled
+    #[serde(default, skip_serializing)]
+    tx_index: Option<HashMap<Hash, TxLocation>>,
+    /// Optional index of every transaction touching a public key, in chain order
+    #[serde(default, skip_serializing)]
+    address_index: Option<BTreeMap<PublicKey, Vec<HistoryEntry>>>,
+    /// Events not collected yet with `take_events`, only recorded once enabled
+    #[serde(default, skip_serializing)]
+    events: Option<Vec<ChainEvent>>
+}
+impl Default for Blockchain {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl Blockchain {
     pub fn new() -> Self {
         Blockchain {
             utxos: HashMap::new(),
             blocks: vec![],
+            heights: HashMap::new(),
             target: crate::MIN_TARGET,
-            mempool: vec![]
+            mempool: vec![],
+            orphans: HashMap::new(),
+            tx_index: None,
+            address_index: None,
+            events: None
         }
     }
 
@@ -42,6 +134,69 @@ impl Blockchain {
         self.blocks.len() as u64
     }
 
+    /// Hash of the last block, or the zero hash for an empty chain
+    pub fn tip_hash(&self) -> Hash {
+        self.blocks.last().map(|block| block.hash()).unwrap_or(Hash::zero())
+    }
+
+    /// Cumulative proof of work, the chain with the most work is the best one
+    pub fn chain_work(&self) -> U256 {
+        self.work_since(0)
+    }
+
+    /// Proof of work of the blocks from `height` on
+    pub fn work_since(&self, height: u64) -> U256 {
+        total_work(self.blocks.iter().skip(height as usize))
+    }
+
+    /// Hashes of blocks back from the tip, one by one at first and then further and further apart,
+    /// down to the genesis block. Another node finds the last block we have in common among them
+    pub fn locator(&self) -> Vec<Hash> {
+        let mut locator = vec![];
+        let mut step = 1;
+        let mut height = self.blocks.len();
+        while height > 0 {
+            height = height.saturating_sub(step);
+            locator.push(self.blocks[height].hash());
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+        }
+        locator
+    }
+
+    /// Number of blocks we have in common with the chain `locator` was made from, the height the
+    /// two chains start to differ at
+    pub fn fork_point(&self, locator: &[Hash]) -> u64 {
+        locator.iter().find_map(|hash| self.height_of(hash)).map_or(0, |height| height + 1)
+    }
+
+    /// Block at the given height, counting from zero
+    pub fn block_at(&self, height: u64) -> Option<&Block> {
+        self.blocks.get(usize::try_from(height).ok()?)
+    }
+
+    pub fn contains_block(&self, hash: &Hash) -> bool {
+        self.block_by_hash(hash).is_some()
+    }
+
+    pub fn block_by_hash(&self, hash: &Hash) -> Option<&Block> {
+        self.block_at(self.height_of(hash)?)
+    }
+
+    /// Height of a block in the chain, looked up without hashing any block
+    pub fn height_of(&self, hash: &Hash) -> Option<u64> {
+        self.heights.get(hash).copied()
+    }
+
+    fn index_heights(&mut self) {
+        self.heights = self.blocks.iter().enumerate().map(|(height, block)| (block.hash(), height as u64)).collect();
+    }
+
+    pub fn contains_orphan(&self, hash: &Hash) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
     pub fn target(&self) -> U256 {
         self.target
     }
@@ -50,8 +205,16 @@ impl Blockchain {
         &self.mempool
     }
 
+    pub fn mempool_transaction(&self, hash: &Hash) -> Option<&Transaction> {
+        self.mempool.iter().map(|(_, tx)| tx).find(|tx| tx.hash() == *hash)
+    }
+
     /// Add a transaction to mempool
     pub fn add_to_mempool(&mut self, transaction: Transaction) -> crate::error::Result<()> {
+        // Coinbase data belongs in blocks only
+        if transaction.coinbase.is_some() {
+            return Err(BtcError::InvalidTransaction);
+        }
         // All inputs must match known UTXOs, and must be unique
         let mut known_inputs = HashSet::new();
         for input in &transaction.inputs {
@@ -85,7 +248,8 @@ impl Blockchain {
                         });
                     }
                     // remove the transaction from the mempool
-                    self.mempool.remove(idx);
+                    let (_, replaced) = self.mempool.remove(idx);
+                    self.record(ChainEvent::TransactionEvicted { hash: replaced.hash(), reason: EvictionReason::Replaced });
                 } else {
                     // If somehow there is no matching transaction, set this utxo to false
                     self.utxos.entry(input.prev_transaction_output_hash).and_modify(|(marked, _)| {
@@ -112,6 +276,7 @@ impl Blockchain {
                 *marked = true;
             });
         }
+        self.record(ChainEvent::TransactionAccepted { hash: transaction.hash() });
         self.mempool.push((Utc::now(), transaction));
         // Sort by miner fee
         self.mempool.sort_by_key(|(_, transaction)| {
@@ -123,8 +288,7 @@ impl Blockchain {
                 })
                 .sum::<u64>();
             let all_outputs: u64 = transaction.outputs.iter().map(|output| output.value).sum();
-            let miner_fee = all_inputs - all_outputs;
-            miner_fee
+            all_inputs - all_outputs
         });
         Ok(())
     }
@@ -133,12 +297,14 @@ impl Blockchain {
     pub fn cleanup_mempool(&mut self) {
         let now = Utc::now();
         let mut utxo_hashes_to_unmark: Vec<Hash> = vec![];
+        let mut expired: Vec<Hash> = vec![];
         self.mempool.retain(|(timestamp, transaction)| {
             if now - *timestamp > chrono::Duration::seconds(MAX_MEMPOOL_TRANSACTION_AGE as i64) {
                 // Push all utxos to unmark to the vector so we can unmark them later
                 utxo_hashes_to_unmark.extend(transaction.inputs.iter().map(|input| {
                     input.prev_transaction_output_hash
                 }));
+                expired.push(transaction.hash());
                 false
             } else {
                 true
@@ -150,6 +316,9 @@ impl Blockchain {
                 *marked = false;
             });
         }
+        for hash in expired {
+            self.record(ChainEvent::TransactionEvicted { hash, reason: EvictionReason::Expired });
+        }
     }
 
     pub fn add_block(&mut self, block: Block) -> crate::error::Result<()> {
@@ -157,13 +326,18 @@ impl Blockchain {
         if self.blocks.is_empty() {
             // If first block, check if the prev block hash is all zeroes
             if block.header.prev_block_hash != Hash::zero() {
-                warn!("Zero hash");
-                return Err(BtcError::InvalidBlock);
+                warn!("Not a genesis block, parent unknown");
+                return Err(BtcError::OrphanBlock);
             }
         } else {
             // If not the first block, check if the prev block hash is the hash of the last block
             let last_block = self.blocks.last().unwrap();
             if block.header.prev_block_hash != last_block.hash() {
+                // A parent we have never seen means we are behind, not that the block is bad
+                if !self.contains_block(&block.header.prev_block_hash) {
+                    warn!("Parent block unknown");
+                    return Err(BtcError::OrphanBlock);
+                }
                 error!("Wrong prev hash");
                 return Err(BtcError::InvalidBlock);
             }
@@ -185,61 +359,345 @@ impl Blockchain {
             // Verify all transactions in the block
             block.verify_transactions(self.block_height(), &self.utxos)?;
         }
-        // Remove transactions from the mempool that are now in the block
+        // Remove transactions from the mempool that are now in the block, or spend what it spent
         let block_transactions: HashSet<_> = block.transactions.iter().map(|tx| tx.hash()).collect();
+        let spent: HashSet<_> = block.transactions.iter()
+            .flat_map(|tx| tx.inputs.iter().map(|input| input.prev_transaction_output_hash))
+            .collect();
+        let mut conflicting = vec![];
         self.mempool.retain(|(_, tx)| {
-            !block_transactions.contains(&tx.hash())
+            if block_transactions.contains(&tx.hash()) {
+                return false;
+            }
+            let conflicts = tx.inputs.iter().any(|input| spent.contains(&input.prev_transaction_output_hash));
+            if conflicts {
+                conflicting.push(tx.clone());
+            }
+            !conflicts
         });
+        for tx in conflicting {
+            for input in &tx.inputs {
+                self.utxos.entry(input.prev_transaction_output_hash).and_modify(|(marked, _)| *marked = false);
+            }
+            self.record(ChainEvent::TransactionEvicted { hash: tx.hash(), reason: EvictionReason::Replaced });
+        }
+        if let Some(index) = self.address_index.as_mut() {
+            Self::index_addresses(index, &self.utxos, &block, self.blocks.len() as u64);
+        }
+        // Spend the inputs and register the outputs, so the next block can be verified right away
+        Self::apply_utxos(&mut self.utxos, &block);
+        if let Some(index) = self.tx_index.as_mut() {
+            Self::index_block(index, &block, self.blocks.len() as u64);
+        }
+        let hash = block.hash();
+        self.record(ChainEvent::BlockConnected { hash, height: self.blocks.len() as u64 });
+        self.heights.insert(hash, self.blocks.len() as u64);
         self.blocks.push(block);
         self.try_adjust_target();
         Ok(())
     }
 
+    /// Replace the blocks from height `fork` on with `branch`, provided it has more work. Should
+    /// a block of the branch be invalid, the chain is put back the way it was
+    pub fn reorganize(&mut self, fork: u64, branch: Vec<Block>) -> crate::error::Result<()> {
+        if fork > self.block_height() || total_work(&branch) <= self.work_since(fork) {
+            return Err(BtcError::InvalidBlock);
+        }
+        let replaced = self.disconnect_to(fork);
+        for block in branch {
+            if let Err(e) = self.add_block(block) {
+                self.disconnect_to(fork);
+                for block in replaced {
+                    self.add_block(block).expect("BUG: the block was connected before");
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the blocks from `height` on, tip first, and hand them back. Their transactions return
+    /// to the mempool, unless they spend outputs that no longer exist
+    fn disconnect_to(&mut self, height: u64) -> Vec<Block> {
+        let disconnected = self.blocks.split_off(height as usize);
+        for (offset, block) in disconnected.iter().enumerate().rev() {
+            let hash = block.hash();
+            self.heights.remove(&hash);
+            self.record(ChainEvent::BlockDisconnected { hash, height: height + offset as u64 });
+        }
+        // Replaying the chain is simpler than undoing blocks, and reorganizations are rare
+        self.utxos.clear();
+        self.rebuild_utxos();
+        self.recompute_target();
+        if self.tx_index.is_some() {
+            self.enable_tx_index();
+        }
+        if self.address_index.is_some() {
+            self.enable_address_index();
+        }
+        // Whatever stays in the mempool marks its inputs again
+        for (_, tx) in std::mem::take(&mut self.mempool) {
+            if tx.inputs.iter().all(|input| self.utxos.contains_key(&input.prev_transaction_output_hash)) {
+                for input in &tx.inputs {
+                    self.utxos.entry(input.prev_transaction_output_hash).and_modify(|(marked, _)| *marked = true);
+                }
+                self.mempool.push((Utc::now(), tx));
+            } else {
+                self.record(ChainEvent::TransactionEvicted { hash: tx.hash(), reason: EvictionReason::Reorganized });
+            }
+        }
+        for block in &disconnected {
+            for tx in block.transactions.iter().skip(1) {
+                // Spending a coinbase or another transaction of the dropped blocks leaves it out
+                let _ = self.add_to_mempool(tx.clone());
+            }
+        }
+        disconnected
+    }
+
+    /// Start recording chain and mempool events, to be collected with `take_events`
+    pub fn enable_events(&mut self) {
+        self.events.get_or_insert_with(Vec::new);
+    }
+
+    /// Hand over the events recorded since the last call
+    pub fn take_events(&mut self) -> Vec<ChainEvent> {
+        self.events.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    fn record(&mut self, event: ChainEvent) {
+        if let Some(events) = self.events.as_mut() {
+            events.push(event);
+        }
+    }
+
+    /// Index every confirmed transaction by hash, making lookups independent of the chain length
+    pub fn enable_tx_index(&mut self) {
+        let mut index = HashMap::new();
+        for (height, block) in self.blocks.iter().enumerate() {
+            Self::index_block(&mut index, block, height as u64);
+        }
+        self.tx_index = Some(index);
+    }
+
+    pub fn has_tx_index(&self) -> bool {
+        self.tx_index.is_some()
+    }
+
+    fn index_block(index: &mut HashMap<Hash, TxLocation>, block: &Block, height: u64) {
+        let block_hash = block.hash();
+        for (position, transaction) in block.transactions.iter().enumerate() {
+            index.insert(transaction.hash(), TxLocation { block_hash, height, position: position as u32 });
+        }
+    }
+
+    /// Find a confirmed transaction, through the index if enabled or by scanning the blocks otherwise
+    pub fn locate_transaction(&self, hash: &Hash) -> Option<TxLocation> {
+        if let Some(index) = &self.tx_index {
+            return index.get(hash).copied();
+        }
+        self.blocks.iter().enumerate().find_map(|(height, block)| {
+            block.transactions.iter().position(|tx| tx.hash() == *hash).map(|position| TxLocation {
+                block_hash: block.hash(),
+                height: height as u64,
+                position: position as u32,
+            })
+        })
+    }
+
+    pub fn transaction_at(&self, location: &TxLocation) -> Option<&Transaction> {
+        self.block_at(location.height)?.transactions.get(location.position as usize)
+    }
+
+    /// Number of blocks on top of a transaction's block, that block included
+    pub fn confirmations(&self, location: &TxLocation) -> u64 {
+        self.block_height().saturating_sub(location.height)
+    }
+
+    /// Index the history of every public key, replaying the chain to find out whose outputs got spent
+    pub fn enable_address_index(&mut self) {
+        let mut index = BTreeMap::new();
+        let mut utxos = HashMap::new();
+        for (height, block) in self.blocks.iter().enumerate() {
+            Self::index_addresses(&mut index, &utxos, block, height as u64);
+            Self::apply_utxos(&mut utxos, block);
+        }
+        self.address_index = Some(index);
+    }
+
+    pub fn has_address_index(&self) -> bool {
+        self.address_index.is_some()
+    }
+
+    /// Record the transactions of a block under the keys they pay or spend from. `utxos` must be the
+    /// set from before the block
+    fn index_addresses(
+        index: &mut BTreeMap<PublicKey, Vec<HistoryEntry>>,
+        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        block: &Block,
+        height: u64
+    ) {
+        let block_hash = block.hash();
+        for (position, transaction) in block.transactions.iter().enumerate() {
+            let tx_hash = transaction.hash();
+            let location = TxLocation { block_hash, height, position: position as u32 };
+            let mut touched: BTreeMap<PublicKey, HistoryEntry> = BTreeMap::new();
+            let new_entry = || HistoryEntry { tx_hash, location, received: vec![], spent: vec![] };
+            for input in &transaction.inputs {
+                if let Some((_, output)) = utxos.get(&input.prev_transaction_output_hash) {
+                    touched.entry(output.public_key.clone()).or_insert_with(new_entry)
+                        .spent.push((input.prev_transaction_output_hash, output.value));
+                }
+            }
+            for output in &transaction.outputs {
+                touched.entry(output.public_key.clone()).or_insert_with(new_entry)
+                    .received.push((output.hash(), output.value));
+            }
+            for (key, entry) in touched {
+                index.entry(key).or_default().push(entry);
+            }
+        }
+    }
+
+    /// A page of the history of a key, newest first, along with the total number of entries.
+    /// None if the address index is disabled
+    pub fn history(&self, key: &PublicKey, offset: usize, limit: usize) -> Option<(Vec<HistoryEntry>, usize)> {
+        let index = self.address_index.as_ref()?;
+        let entries = index.get(key).map(Vec::as_slice).unwrap_or_default();
+        let page = entries.iter().rev().skip(offset).take(limit).cloned().collect();
+        Some((page, entries.len()))
+    }
+
+    /// Unspent outputs of a key and whether a mempool transaction spends them. Uses the address
+    /// index when enabled, scans the whole UTXO set otherwise
+    pub fn utxos_of(&self, key: &PublicKey) -> Vec<(TransactionOutput, bool)> {
+        match &self.address_index {
+            Some(index) => index.get(key).into_iter().flatten()
+                .flat_map(|entry| entry.received.iter())
+                .filter_map(|(hash, _)| self.utxos.get(hash))
+                .map(|(marked, output)| (output.clone(), *marked))
+                .collect(),
+            None => self.utxos.values()
+                .filter(|(_, output)| output.public_key == *key)
+                .map(|(marked, output)| (output.clone(), *marked))
+                .collect(),
+        }
+    }
+
+    /// Park a block whose parent is unknown until the missing blocks arrive
+    pub fn add_orphan(&mut self, block: Block) {
+        self.cleanup_orphans();
+        let hash = block.hash();
+        if !self.orphans.contains_key(&hash) && self.orphans.len() >= MAX_ORPHAN_BLOCKS {
+            // Make room by dropping the oldest orphan, it will be fetched again when syncing
+            if let Some(oldest) = self.orphans
+                .iter()
+                .min_by_key(|(_, (received, _))| *received)
+                .map(|(hash, _)| *hash) {
+                self.orphans.remove(&oldest);
+            }
+        }
+        self.orphans.entry(hash).or_insert((Utc::now(), block));
+    }
+
+    /// Drop orphans older than MAX_ORPHAN_BLOCK_AGE
+    pub fn cleanup_orphans(&mut self) {
+        let now = Utc::now();
+        self.orphans.retain(|_, (received, _)| {
+            now - *received <= chrono::Duration::seconds(MAX_ORPHAN_BLOCK_AGE as i64)
+        });
+    }
+
+    /// Connect every orphan that now extends the tip, returns the hashes of the blocks added
+    pub fn connect_orphans(&mut self) -> Vec<Hash> {
+        if self.orphans.is_empty() {
+            return vec![];
+        }
+        let mut connected = vec![];
+        loop {
+            let tip_hash = self.tip_hash();
+            let Some(hash) = self.orphans
+                .iter()
+                .find(|(_, (_, block))| block.header.prev_block_hash == tip_hash)
+                .map(|(hash, _)| *hash) else {
+                break;
+            };
+            let (_, block) = self.orphans.remove(&hash).expect("BUG: impossible");
+            if let Err(e) = self.add_block(block) {
+                warn!("Dropping invalid orphan block {}: {}", hash, e);
+                continue;
+            }
+            connected.push(hash);
+        }
+        // Forget orphans that made it into the chain some other way
+        let heights = &self.heights;
+        self.orphans.retain(|hash, _| !heights.contains_key(hash));
+        connected
+    }
+
     /// Adjust the target if needed
     pub fn try_adjust_target(&mut self) {
-        if self.blocks.is_empty() {
-            return;
+        self.target = Self::adjusted_target(&self.blocks, self.target);
+    }
+
+    /// Work the target out again from the genesis block on
+    fn recompute_target(&mut self) {
+        self.target = crate::MIN_TARGET;
+        for height in 1..=self.blocks.len() {
+            self.target = Self::adjusted_target(&self.blocks[..height], self.target);
+        }
+    }
+
+    /// The target after the last of `blocks`, which was `target` before it
+    fn adjusted_target(blocks: &[Block], target: U256) -> U256 {
+        if blocks.is_empty() {
+            return target;
         }
-        if self.blocks.len() % crate::DIFFICULTY_UPDATE_INTERVAL as usize != 0 {
-            return;
+        if !blocks.len().is_multiple_of(crate::DIFFICULTY_UPDATE_INTERVAL as usize) {
+            return target;
         }
         // Measure the time it took to mine the last crate::DIFFICULTY_UPDATE_INTERVAL blocks with chrono
-        let start_time = self.blocks[self.blocks.len() - crate::DIFFICULTY_UPDATE_INTERVAL as usize].header.timestamp;
-        let end_time = self.blocks.last().unwrap().header.timestamp;
+        let start_time = blocks[blocks.len() - crate::DIFFICULTY_UPDATE_INTERVAL as usize].header.timestamp;
+        let end_time = blocks.last().unwrap().header.timestamp;
         let time_diff = end_time - start_time;
         let time_diff_seconds = time_diff.num_seconds();
         // Calculate the ideal number of seconds
         let target_seconds = crate::IDEAL_BLOCK_TIME * crate::DIFFICULTY_UPDATE_INTERVAL;
         // Multiply the current target by actual time divided by ideal time
-        let new_target = BigDecimal::parse_bytes(&self.target.to_string().as_bytes(), 10)
+        let new_target = BigDecimal::parse_bytes(target.to_string().as_bytes(), 10)
             .expect("This should never happen")
             * (BigDecimal::from(time_diff_seconds)
             / BigDecimal::from(target_seconds));
         // Cut off the decimal point and everything after it from string representation of new_target
         let new_target_str = new_target.to_string().split(".").next().expect("Expected a decimal point").to_owned();
         let new_target: U256 = U256::from_str_radix(&new_target_str, 10).expect("Should never happen");
-        // Clamp new_target to be within the range of 4 * self.target and self.target / 4
-        let new_target = if new_target < self.target / 4 {
-            self.target / 4
-        } else if new_target > self.target * 4 {
-            self.target * 4
+        // Clamp new_target to be within the range of 4 * target and target / 4
+        let new_target = if new_target < target / 4 {
+            target / 4
+        } else if new_target > target * 4 {
+            target * 4
         } else {
             new_target
         };
         // If the new target is more than the minimum target, set it to the minimum target
-        self.target = new_target.min(crate::MIN_TARGET);
+        new_target.min(crate::MIN_TARGET)
     }
 
     /// Rebuild UTXO set from the blockchain
     pub fn rebuild_utxos(&mut self) {
         for block in &self.blocks {
-            for transaction in &block.transactions {
-                for input in &transaction.inputs {
-                    self.utxos.remove(&input.prev_transaction_output_hash);
-                }
-                for output in transaction.outputs.iter() {
-                    self.utxos.insert(output.hash(), (false, output.clone()));
-                }
+            Self::apply_utxos(&mut self.utxos, block);
+        }
+    }
+
+    /// Remove the outputs spent by a block and add the ones it creates
+    fn apply_utxos(utxos: &mut HashMap<Hash, (bool, TransactionOutput)>, block: &Block) {
+        for transaction in &block.transactions {
+            for input in &transaction.inputs {
+                utxos.remove(&input.prev_transaction_output_hash);
+            }
+            for output in transaction.outputs.iter() {
+                utxos.insert(output.hash(), (false, output.clone()));
             }
         }
     }
@@ -250,12 +708,24 @@ impl Blockchain {
         (crate::INITIAL_REWARD * 10u64.pow(8)) >> halvings
     }
 }
+fn total_work<'a>(blocks: impl IntoIterator<Item = &'a Block>) -> U256 {
+    blocks.into_iter().fold(U256::zero(), |work, block| work.saturating_add(block.header.work()))
+}
+
 /// Save and load expecting CBOR from ciborium as format
 impl Saveable for Blockchain {
+    const HEADER: bool = true;
+    /// 1: CBOR of the UTXOs, target and blocks. The mempool, orphans and indexes are not saved.
+    /// 2: same, with blocks and outputs in their canonical encoding
+    /// 3: same, coinbases may carry data
+    const FORMAT_VERSION: u32 = 3;
+
     fn load<I: Read>(reader: I) -> IoResult<Self> {
-        ciborium::de::from_reader(reader).map_err(|_| {
-            IoError::new(IoErrorKind::InvalidData, "Failed to deserialize Blockchain")
-        })
+        let mut blockchain: Self = ciborium::de::from_reader(reader).map_err(|e| {
+            IoError::new(IoErrorKind::InvalidData, format!("Failed to deserialize Blockchain: {}", e))
+        })?;
+        blockchain.index_heights();
+        Ok(blockchain)
     }
 
     fn save<O: Write>(&self, writer: O) -> IoResult<()> {
@@ -263,4 +733,194 @@ impl Saveable for Blockchain {
             IoError::new(IoErrorKind::InvalidData, "Failed to serialize")
         })
     }
-}
\ No newline at end of file
+
+    fn migrate<I: Read>(version: u32, reader: I) -> IoResult<Self> {
+        match version {
+            // Blocks link to their parents and prove their work by hash, rehashing would break both
+            0 | 1 => Err(crate::util::not_migratable(version, "block hashes changed with the canonical encoding, sync the chain again")),
+            // Encoded the same, there was just no coinbase data yet
+            2 => Self::load(reader),
+            _ => Err(crate::util::unsupported_version(version, Self::FORMAT_VERSION)),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use uuid::Uuid;
+    use crate::crypto::{PrivateKey, Signature};
+    use crate::types::{BlockHeader, TransactionInput};
+    use super::*;
+
+    fn output(value: u64, key: &PublicKey) -> TransactionOutput {
+        TransactionOutput { value, unique_id: Uuid::new_v4(), public_key: key.clone() }
+    }
+
+    /// Extend `blocks` by one block paying the reward to `miner`, with any target met
+    fn extend(blocks: &mut Vec<Block>, miner: &PublicKey, transactions: Vec<Transaction>) {
+        let height = blocks.len() as u64;
+        let reward = (crate::INITIAL_REWARD * 10u64.pow(8)) >> (height / crate::HALVING_INTERVAL);
+        let mut transactions = transactions;
+        transactions.insert(0, Transaction::new(vec![], vec![output(reward, miner)]));
+        let header = BlockHeader::new(
+            DateTime::from_timestamp(1_700_000_000 + height as i64 * 10, 0).unwrap(),
+            0,
+            blocks.last().map_or(Hash::zero(), Block::hash),
+            MerkleRoot::calculate(&transactions),
+            U256::MAX,
+        );
+        blocks.push(Block::new(header, transactions));
+    }
+
+    fn chain_of(blocks: &[Block]) -> Blockchain {
+        let mut chain = Blockchain::new();
+        for block in blocks {
+            chain.add_block(block.clone()).unwrap();
+        }
+        chain
+    }
+
+    /// Three blocks in common, then two mined by `ours` against three by `theirs`. Our first block
+    /// after the fork spends the coinbase of the genesis block
+    fn diverging_chains() -> (Vec<Block>, Vec<Block>, PrivateKey, Transaction) {
+        let (ours, theirs) = (PrivateKey::new_key(), PrivateKey::new_key());
+        let mut shared = vec![];
+        for _ in 0..3 {
+            extend(&mut shared, &ours.public_key(), vec![]);
+        }
+        let coinbase = &shared[0].transactions[0].outputs[0];
+        let spend = Transaction::new(
+            vec![TransactionInput {
+                prev_transaction_output_hash: coinbase.hash(),
+                signature: Signature::sign_output(&coinbase.hash(), &ours),
+            }],
+            vec![output(coinbase.value, &theirs.public_key())],
+        );
+        let mut our_chain = shared.clone();
+        extend(&mut our_chain, &ours.public_key(), vec![spend.clone()]);
+        extend(&mut our_chain, &ours.public_key(), vec![]);
+        let mut their_chain = shared;
+        for _ in 0..3 {
+            extend(&mut their_chain, &theirs.public_key(), vec![]);
+        }
+        (our_chain, their_chain, ours, spend)
+    }
+
+    #[test]
+    fn locator_finds_the_fork_point() {
+        let (ours, theirs, ..) = diverging_chains();
+        let (ours, theirs) = (chain_of(&ours), chain_of(&theirs));
+        assert_eq!(theirs.fork_point(&ours.locator()), 3);
+        assert_eq!(ours.fork_point(&theirs.locator()), 3);
+        assert_eq!(ours.fork_point(&ours.locator()), ours.block_height());
+        assert_eq!(ours.fork_point(&[Hash::zero()]), 0);
+    }
+
+    #[test]
+    fn locator_thins_out_but_reaches_genesis() {
+        let key = PrivateKey::new_key().public_key();
+        let mut blocks = vec![];
+        for _ in 0..40 {
+            extend(&mut blocks, &key, vec![]);
+        }
+        let locator = chain_of(&blocks).locator();
+        assert_eq!(locator.first(), Some(&blocks[39].hash()));
+        assert_eq!(locator.last(), Some(&blocks[0].hash()));
+        assert!(locator.len() < 20);
+    }
+
+    #[test]
+    fn reorganizes_onto_a_branch_with_more_work() {
+        let (our_blocks, their_blocks, _, spend) = diverging_chains();
+        let mut chain = chain_of(&our_blocks);
+        chain.enable_tx_index();
+        chain.enable_events();
+        // Their branch does not fit on our tip, it takes a reorganization
+        assert!(chain.add_block(their_blocks[3].clone()).is_err());
+        chain.reorganize(3, their_blocks[3..].to_vec()).unwrap();
+        assert_eq!(chain.block_height(), 6);
+        assert_eq!(chain.tip_hash(), their_blocks[5].hash());
+        assert_eq!(chain.chain_work(), chain_of(&their_blocks).chain_work());
+        assert_eq!(chain.height_of(&our_blocks[4].hash()), None);
+        assert_eq!(chain.height_of(&their_blocks[4].hash()), Some(4));
+        assert_eq!(chain.locate_transaction(&spend.hash()), None);
+        // Both branches paid their miners in coinbases, only the kept one counts
+        let utxos = chain_of(&their_blocks).utxos().keys().copied().collect::<HashSet<_>>();
+        let spent = spend.inputs[0].prev_transaction_output_hash;
+        assert_eq!(chain.utxos().keys().copied().collect::<HashSet<_>>(), utxos);
+        assert_eq!(chain.utxos().get(&spent).map(|(marked, _)| *marked), Some(true));
+        // The transaction of the dropped branch is waiting to be mined again
+        assert_eq!(chain.mempool().len(), 1);
+        assert_eq!(chain.mempool()[0].1.hash(), spend.hash());
+        let events = chain.take_events();
+        assert_eq!(&events[..2], &[
+            ChainEvent::BlockDisconnected { hash: our_blocks[4].hash(), height: 4 },
+            ChainEvent::BlockDisconnected { hash: our_blocks[3].hash(), height: 3 },
+        ]);
+        assert!(events.contains(&ChainEvent::TransactionAccepted { hash: spend.hash() }));
+        assert_eq!(events.last(), Some(&ChainEvent::BlockConnected { hash: their_blocks[5].hash(), height: 5 }));
+    }
+
+    #[test]
+    fn keeps_the_chain_with_more_work() {
+        let (our_blocks, their_blocks, ..) = diverging_chains();
+        let mut chain = chain_of(&their_blocks);
+        assert!(chain.reorganize(3, our_blocks[3..].to_vec()).is_err());
+        assert_eq!(chain.tip_hash(), their_blocks[5].hash());
+    }
+
+    #[test]
+    fn restores_the_chain_when_the_branch_is_invalid() {
+        let (our_blocks, mut their_blocks, ..) = diverging_chains();
+        // Paying the miner one Sat too many
+        let last = their_blocks.last_mut().unwrap();
+        last.transactions[0].outputs[0].value += 1;
+        last.header.merkle_root = MerkleRoot::calculate(&last.transactions);
+        let mut chain = chain_of(&our_blocks);
+        let utxos = chain.utxos().keys().copied().collect::<HashSet<_>>();
+        assert!(chain.reorganize(3, their_blocks[3..].to_vec()).is_err());
+        assert_eq!(chain.block_height(), 5);
+        assert_eq!(chain.tip_hash(), our_blocks[4].hash());
+        assert_eq!(chain.utxos().keys().copied().collect::<HashSet<_>>(), utxos);
+        assert!(chain.mempool().is_empty());
+    }
+
+    #[test]
+    fn evicts_the_oldest_orphan() {
+        let miner = PrivateKey::new_key().public_key();
+        let mut blocks = vec![];
+        for _ in 0..=MAX_ORPHAN_BLOCKS + 1 {
+            extend(&mut blocks, &miner, vec![]);
+        }
+        let mut chain = chain_of(&blocks[..1]);
+        for block in &blocks[1..=MAX_ORPHAN_BLOCKS] {
+            chain.add_orphan(block.clone());
+        }
+        // The further from the tip, the earlier it arrived
+        let now = Utc::now();
+        for (hash, (received, _)) in chain.orphans.iter_mut() {
+            let height = blocks.iter().position(|block| block.hash() == *hash).unwrap();
+            *received = now - chrono::Duration::seconds(height as i64);
+        }
+        chain.add_orphan(blocks[MAX_ORPHAN_BLOCKS + 1].clone());
+        assert!(!chain.contains_orphan(&blocks[MAX_ORPHAN_BLOCKS].hash()));
+        assert!(chain.contains_orphan(&blocks[1].hash()));
+        assert_eq!(chain.connect_orphans().len(), MAX_ORPHAN_BLOCKS - 1);
+    }
+
+    #[test]
+    fn expires_orphans() {
+        let miner = PrivateKey::new_key().public_key();
+        let mut blocks = vec![];
+        for _ in 0..3 {
+            extend(&mut blocks, &miner, vec![]);
+        }
+        let mut chain = chain_of(&blocks[..1]);
+        chain.add_orphan(blocks[1].clone());
+        chain.add_orphan(blocks[2].clone());
+        chain.orphans.get_mut(&blocks[1].hash()).unwrap().0 -= chrono::Duration::seconds(MAX_ORPHAN_BLOCK_AGE as i64 + 1);
+        chain.cleanup_orphans();
+        assert!(!chain.contains_orphan(&blocks[1].hash()));
+        assert!(chain.contains_orphan(&blocks[2].hash()));
+    }
+}
@@ -2,27 +2,103 @@ use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResu
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use crate::crypto::{Hash, PublicKey, Signature};
+use crate::encoding::{canonical_serde, encode_bytes, encode_list, Decode, Decoder, Encode};
+use crate::error::BtcError;
 use crate::util::Saveable;
 
+/// Stands in for the input count of a coinbase carrying data
+const COINBASE_MARKER: u32 = u32::MAX;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(remote = "Self")]
 pub struct Transaction {
     pub inputs: Vec<TransactionInput>,
-    pub outputs: Vec<TransactionOutput>
+    pub outputs: Vec<TransactionOutput>,
+    /// Only ever set on a coinbase, which has no inputs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coinbase: Option<CoinbaseData>
 }
 impl Transaction {
     pub fn new(inputs: Vec<TransactionInput>, outputs: Vec<TransactionOutput>) -> Self {
-        Transaction { inputs, outputs }
+        Transaction { inputs, outputs, coinbase: None }
+    }
+
+    /// A coinbase paying `outputs`, tagged with `data`
+    pub fn coinbase(outputs: Vec<TransactionOutput>, data: CoinbaseData) -> Self {
+        Transaction { inputs: vec![], outputs, coinbase: Some(data) }
     }
 
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        Hash::digest(&self.encoded())
+    }
+
+    /// Coinbase data goes without inputs. The encoding leaves the inputs of a coinbase out, so
+    /// one with inputs would share its hash with the same transaction without them
+    pub fn check_coinbase(&self) -> crate::error::Result<()> {
+        if self.coinbase.is_some() && !self.inputs.is_empty() {
+            return Err(BtcError::InvalidEncoding("coinbase data on a transaction with inputs"));
+        }
+        Ok(())
+    }
+}
+impl Encode for Transaction {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match &self.coinbase {
+            Some(coinbase) => {
+                // No inputs to leave out, decoding and verification refuse those, see `check_coinbase`
+                out.extend_from_slice(&COINBASE_MARKER.to_le_bytes());
+                coinbase.encode(out);
+            }
+            None => encode_list(&self.inputs, out),
+        }
+        encode_list(&self.outputs, out);
+    }
+}
+impl Decode for Transaction {
+    fn decode(decoder: &mut Decoder) -> crate::error::Result<Self> {
+        let (inputs, coinbase) = match decoder.u32()? {
+            COINBASE_MARKER => (vec![], Some(CoinbaseData::decode(decoder)?)),
+            count => (decoder.items(count)?, None),
+        };
+        let transaction = Transaction { inputs, outputs: decoder.list()?, coinbase };
+        transaction.check_coinbase()?;
+        Ok(transaction)
+    }
+}
+canonical_serde!(Transaction, Transaction::check_coinbase);
+
+/// What a miner puts in its coinbase besides the outputs
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CoinbaseData {
+    /// Changes the Merkle root, and so the header, once the nonces run out. The node picks the
+    /// upper 32 bits of every template it hands out and miners count up the lower ones, so no two
+    /// of them ever search the same work
+    pub extra_nonce: u64,
+    /// Anything the miner likes, up to `MAX_COINBASE_DATA` bytes, like a tag naming it
+    pub data: Vec<u8>,
+}
+impl Encode for CoinbaseData {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.extra_nonce.to_le_bytes());
+        encode_bytes(&self.data, out);
+    }
+}
+impl Decode for CoinbaseData {
+    fn decode(decoder: &mut Decoder) -> crate::error::Result<Self> {
+        Ok(CoinbaseData { extra_nonce: decoder.u64()?, data: decoder.bytes()? })
     }
 }
 /// Save and load expecting CBOR from ciborium as format
 impl Saveable for Transaction {
+    const HEADER: bool = true;
+    /// 1: CBOR of the inputs and outputs.
+    /// 2: CBOR byte string holding the canonical encoding
+    /// 3: same, coinbases may carry data
+    const FORMAT_VERSION: u32 = 3;
+
     fn load<I: Read>(reader: I) -> IoResult<Self> {
-        ciborium::de::from_reader(reader).map_err(|_| {
-            IoError::new(IoErrorKind::InvalidData, "Failed to deserialize Transaction")
+        ciborium::de::from_reader(reader).map_err(|e| {
+            IoError::new(IoErrorKind::InvalidData, format!("Failed to deserialize Transaction: {}", e))
         })
     }
 
@@ -31,9 +107,20 @@ impl Saveable for Transaction {
             IoError::new(IoErrorKind::InvalidData, "Failed to serialize Transaction")
         })
     }
+
+    fn migrate<I: Read>(version: u32, reader: I) -> IoResult<Self> {
+        match version {
+            // Inputs signed the old output hashes
+            0 | 1 => Err(crate::util::not_migratable(version, "signatures changed with the canonical encoding")),
+            // Encoded the same, there was just no coinbase data yet
+            2 => Self::load(reader),
+            _ => Err(crate::util::unsupported_version(version, Self::FORMAT_VERSION)),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(remote = "Self")]
 pub struct TransactionInput {
     /// The hash of the transaction output, which we are linking into this transaction as input
     pub prev_transaction_output_hash: Hash,
@@ -42,7 +129,21 @@ pub struct TransactionInput {
     pub signature: Signature
 }
 
+impl Encode for TransactionInput {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.prev_transaction_output_hash.encode(out);
+        self.signature.encode(out);
+    }
+}
+impl Decode for TransactionInput {
+    fn decode(decoder: &mut Decoder) -> crate::error::Result<Self> {
+        Ok(TransactionInput { prev_transaction_output_hash: Hash::decode(decoder)?, signature: Signature::decode(decoder)? })
+    }
+}
+canonical_serde!(TransactionInput);
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(remote = "Self")]
 pub struct TransactionOutput {
     pub value: u64,
     pub unique_id: Uuid,
@@ -50,6 +151,40 @@ pub struct TransactionOutput {
 }
 impl TransactionOutput {
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        Hash::digest(&self.encoded())
     }
-}
\ No newline at end of file
+}
+impl Encode for TransactionOutput {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.value.to_le_bytes());
+        self.unique_id.encode(out);
+        self.public_key.encode(out);
+    }
+}
+impl Decode for TransactionOutput {
+    fn decode(decoder: &mut Decoder) -> crate::error::Result<Self> {
+        Ok(TransactionOutput { value: decoder.u64()?, unique_id: Uuid::decode(decoder)?, public_key: PublicKey::decode(decoder)? })
+    }
+}
+canonical_serde!(TransactionOutput);
+#[cfg(test)]
+mod tests {
+    use crate::crypto::PrivateKey;
+    use super::*;
+
+    #[test]
+    fn refuses_coinbase_data_with_inputs() {
+        let key = PrivateKey::new_key();
+        let output = TransactionOutput { value: 50, unique_id: Uuid::new_v4(), public_key: key.public_key() };
+        let input = TransactionInput { prev_transaction_output_hash: output.hash(), signature: Signature::sign_output(&output.hash(), &key) };
+        let mut transaction = Transaction::coinbase(vec![output], CoinbaseData::default());
+        assert!(transaction.check_coinbase().is_ok());
+        let json = serde_json::to_value(&transaction).unwrap();
+        assert!(serde_json::from_value::<Transaction>(json).is_ok());
+        transaction.inputs.push(input);
+        assert!(matches!(transaction.check_coinbase(), Err(BtcError::InvalidEncoding(_))));
+        // The RPC server reads transactions field by field, where the inputs can be spelled out
+        let json = serde_json::to_value(&transaction).unwrap();
+        assert!(serde_json::from_value::<Transaction>(json).is_err());
+    }
+}
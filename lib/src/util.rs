@@ -1,22 +1,184 @@
-use std::fs::File;
-use std::io::{Read, Result as IoResult, Write};
-use std::path::Path;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use sha2::{Digest, Sha256};
+use crate::error::FileFormatError;
+
+/// First bytes of a file written with a header, CBOR and PEM content never starts like this
+pub const FILE_MAGIC: [u8; 4] = *b"BTCF";
+/// Magic, format version, content length and SHA-256 of the content
+const HEADER_LEN: usize = 4 + 4 + 8 + 32;
 
 pub trait Saveable
 where
     Self: Sized
 {
+    /// Whether files start with a header holding the format version and a checksum of the content.
+    /// Files without header are still loaded, as written before headers existed
+    const HEADER: bool = false;
+
+    /// Format version written in the header. Older versions go through `migrate`, newer ones are refused
+    const FORMAT_VERSION: u32 = 1;
+
+    /// Read content in the current format version
     fn load<I: Read>(reader: I) -> IoResult<Self>;
 
     fn save<O: Write>(&self, writer: O) -> IoResult<()>;
 
+    /// Read content written by an older build in an older format version, converting it to the current one.
+    /// Version 0 stands for files written before headers existed. When changing the format, bump
+    /// `FORMAT_VERSION` and keep a way to read every older version here
+    fn migrate<I: Read>(version: u32, _reader: I) -> IoResult<Self> {
+        Err(unsupported_version(version, Self::FORMAT_VERSION))
+    }
+
+    /// Write to a temporary file next to the destination, flush it to disk and move it in place,
+    /// so a crash leaves either the old file or the new one, never half of one
     fn save_to_file<P: AsRef<Path>>(&self, path: P) -> IoResult<()> {
-        let file = File::create(&path)?;
-        self.save(file)
+        let path = path.as_ref();
+        let mut content = Vec::new();
+        self.save(&mut content)?;
+        let temporary_path = temporary_path(path);
+        let written = (|| {
+            let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&temporary_path)?;
+            if Self::HEADER {
+                file.write_all(&header(Self::FORMAT_VERSION, &content))?;
+            }
+            file.write_all(&content)?;
+            file.sync_all()?;
+            drop(file);
+            fs::rename(&temporary_path, path)
+        })();
+        if let Err(e) = written {
+            // Do not leave a temporary file behind for every failed save
+            let _ = fs::remove_file(&temporary_path);
+            return Err(e);
+        }
+        // Make the rename itself durable, not every platform lets us open a directory so this is best effort
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty())
+            && let Ok(directory) = File::open(parent) {
+            let _ = directory.sync_all();
+        }
+        Ok(())
     }
 
+    /// Load a file written in any format version up to the current one
     fn load_from_file<P: AsRef<Path>>(path: P) -> IoResult<Self> {
-        let file = File::open(&path)?;
-        Self::load(file)
+        let mut bytes = Vec::new();
+        File::open(&path)?.read_to_end(&mut bytes)?;
+        let (version, content) = read_header(&bytes).map_err(|e| IoError::new(IoErrorKind::InvalidData, e))?;
+        match version {
+            None if Self::HEADER => Self::migrate(0, content),
+            None => Self::load(content),
+            Some(version) if version == Self::FORMAT_VERSION => Self::load(content),
+            Some(version) if version < Self::FORMAT_VERSION => Self::migrate(version, content),
+            Some(version) => Err(unsupported_version(version, Self::FORMAT_VERSION)),
+        }
+    }
+}
+
+/// Format version of a saved file, None if it has no header
+pub fn file_version<P: AsRef<Path>>(path: P) -> IoResult<Option<u32>> {
+    let mut bytes = Vec::new();
+    File::open(&path)?.read_to_end(&mut bytes)?;
+    let (version, _) = read_header(&bytes).map_err(|e| IoError::new(IoErrorKind::InvalidData, e))?;
+    Ok(version)
+}
+
+/// Error for a format version a type cannot read
+pub fn unsupported_version(found: u32, supported: u32) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, FileFormatError::UnsupportedVersion { found, supported })
+}
+
+/// Error for an older format version whose content cannot be carried over
+pub fn not_migratable(found: u32, reason: &'static str) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, FileFormatError::NotMigratable { found, reason })
+}
+
+/// Whether loading failed because the file is in an older format version that cannot be carried over
+pub fn is_not_migratable(error: &IoError) -> bool {
+    matches!(
+        error.get_ref().and_then(|e| e.downcast_ref::<FileFormatError>()),
+        Some(FileFormatError::NotMigratable { .. })
+    )
+}
+
+/// A name no other save uses at the same time, in this process or another one writing the same file
+fn temporary_path(path: &Path) -> PathBuf {
+    static SAVES: AtomicU64 = AtomicU64::new(0);
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.{}.tmp", std::process::id(), SAVES.fetch_add(1, Ordering::Relaxed)));
+    path.with_file_name(name)
+}
+
+fn header(version: u32, content: &[u8]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(&FILE_MAGIC);
+    header.extend_from_slice(&version.to_be_bytes());
+    header.extend_from_slice(&(content.len() as u64).to_be_bytes());
+    header.extend_from_slice(&Sha256::digest(content));
+    header
+}
+
+/// Check the header of a file, if it has one, and return its format version and the content following it
+fn read_header(bytes: &[u8]) -> Result<(Option<u32>, &[u8]), FileFormatError> {
+    if !bytes.starts_with(&FILE_MAGIC) {
+        return Ok((None, bytes));
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err(FileFormatError::Corrupted("header is truncated".to_string()));
+    }
+    let version = u32::from_be_bytes(bytes[4..8].try_into().expect("BUG: impossible"));
+    let length = u64::from_be_bytes(bytes[8..16].try_into().expect("BUG: impossible"));
+    let content = &bytes[HEADER_LEN..];
+    if content.len() as u64 != length {
+        return Err(FileFormatError::Corrupted(format!("expected {} bytes of content, found {}", length, content.len())));
+    }
+    if Sha256::digest(content)[..] != bytes[16..HEADER_LEN] {
+        return Err(FileFormatError::Corrupted("checksum mismatch".to_string()));
+    }
+    Ok((Some(version), content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Bytes(Vec<u8>);
+    impl Saveable for Bytes {
+        const HEADER: bool = true;
+
+        fn load<I: Read>(mut reader: I) -> IoResult<Self> {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes)?;
+            Ok(Bytes(bytes))
+        }
+
+        fn save<O: Write>(&self, mut writer: O) -> IoResult<()> {
+            writer.write_all(&self.0)
+        }
+    }
+
+    #[test]
+    fn temporary_paths_are_unique_and_next_to_the_file() {
+        let path = Path::new("data/chain.cbor");
+        let (first, second) = (temporary_path(path), temporary_path(path));
+        assert_ne!(first, second);
+        assert_eq!(first.parent(), path.parent());
+        assert!(first.to_string_lossy().starts_with("data/chain.cbor."));
+    }
+
+    #[test]
+    fn saves_leave_only_the_file() {
+        let directory = std::env::temp_dir().join(format!("btclib-save-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("bytes");
+        Bytes(b"first".to_vec()).save_to_file(&path).unwrap();
+        Bytes(b"second".to_vec()).save_to_file(&path).unwrap();
+        assert_eq!(Bytes::load_from_file(&path).unwrap().0, b"second");
+        let files = fs::read_dir(&directory).unwrap().count();
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(files, 1);
     }
-}
\ No newline at end of file
+}
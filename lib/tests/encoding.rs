@@ -0,0 +1,123 @@
+//! Checks the committed encoding_vectors.json, which other implementations test against, without
+//! rebuilding its items: `encoding_vectors --check` compares the generator with the file
+use std::collections::BTreeMap;
+use btclib::crypto::{Hash, MerkleRoot};
+use btclib::encoding::{Decode, Encode};
+use btclib::types::{Block, BlockHeader, CoinbaseData, Transaction, TransactionInput, TransactionOutput};
+use chrono::DateTime;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct Vector {
+    encoding: String,
+    hash: String,
+}
+
+fn vectors() -> BTreeMap<String, Vector> {
+    serde_json::from_str(include_str!("../encoding_vectors.json")).unwrap()
+}
+
+fn encoding_of(name: &str) -> Vec<u8> {
+    hex::decode(&vectors()[name].encoding).unwrap()
+}
+
+fn hash_of(name: &str) -> Hash {
+    Hash::decode_all(&hex::decode(&vectors()[name].hash).unwrap()).unwrap()
+}
+
+/// The item of a vector, checking it encodes back to the very same bytes
+fn decoded<T: Decode + Encode>(name: &str) -> T {
+    let bytes = encoding_of(name);
+    let item = T::decode_all(&bytes).unwrap_or_else(|e| panic!("decoding {}: {}", name, e));
+    assert_eq!(item.encoded(), bytes, "round trip of {}", name);
+    item
+}
+
+#[test]
+fn has_every_vector() {
+    let names = vectors().into_keys().collect::<Vec<_>>();
+    assert_eq!(names, ["block", "coinbase", "header", "input", "output", "spend", "tagged_coinbase"]);
+}
+
+#[test]
+fn decodes_the_vectors_back_to_the_same_bytes() {
+    decoded::<TransactionOutput>("output");
+    decoded::<TransactionInput>("input");
+    for name in ["coinbase", "tagged_coinbase", "spend"] {
+        decoded::<Transaction>(name);
+    }
+    decoded::<BlockHeader>("header");
+    decoded::<Block>("block");
+}
+
+#[test]
+fn hashes_are_the_sha256_of_the_encoding() {
+    for name in ["output", "input", "coinbase", "tagged_coinbase", "spend", "header"] {
+        assert_eq!(hash_of(name), Hash::digest(&encoding_of(name)), "hash of {}", name);
+    }
+    assert_eq!(decoded::<TransactionOutput>("output").hash(), hash_of("output"));
+    assert_eq!(decoded::<Transaction>("spend").hash(), hash_of("spend"));
+    assert_eq!(decoded::<BlockHeader>("header").hash(), hash_of("header"));
+    // A block goes by the hash of its header
+    assert_eq!(decoded::<Block>("block").hash(), hash_of("header"));
+    assert_eq!(hash_of("block"), hash_of("header"));
+}
+
+#[test]
+fn builds_the_block_from_the_other_vectors() {
+    let block = decoded::<Block>("block");
+    assert_eq!(block.header.encoded(), encoding_of("header"));
+    let transactions = block.transactions.iter().map(Encode::encoded).collect::<Vec<_>>();
+    assert_eq!(transactions, [encoding_of("coinbase"), encoding_of("spend")]);
+    assert_eq!(block.header.merkle_root, MerkleRoot::calculate(&block.transactions));
+    // The spend signs away the coinbase output
+    let (output, spend) = (decoded::<TransactionOutput>("output"), decoded::<Transaction>("spend"));
+    assert_eq!(spend.inputs[0].encoded(), encoding_of("input"));
+    assert_eq!(spend.inputs[0].prev_transaction_output_hash, output.hash());
+    assert!(spend.inputs[0].signature.verify(&output.hash(), &output.public_key));
+}
+
+#[test]
+fn decodes_every_field() {
+    let header = decoded::<BlockHeader>("header");
+    assert_eq!(header.timestamp, DateTime::from_timestamp(1_700_000_000, 123_456_789).unwrap());
+    assert_eq!(header.nonce, 42);
+    assert_eq!(header.prev_block_hash, Hash::zero());
+    assert_eq!(header.target, btclib::MIN_TARGET);
+    let coinbase = decoded::<Transaction>("tagged_coinbase");
+    assert!(coinbase.inputs.is_empty());
+    assert_eq!(coinbase.coinbase, Some(CoinbaseData { extra_nonce: 0x0000_0007_0000_0001, data: b"miner".to_vec() }));
+    assert_eq!(coinbase.outputs[0].encoded(), encoding_of("output"));
+}
+
+#[test]
+fn lays_out_the_header_in_116_bytes() {
+    let bytes = encoding_of("header");
+    assert_eq!(bytes.len(), 116);
+    assert_eq!(bytes[0..8], 1_700_000_000i64.to_le_bytes());
+    assert_eq!(bytes[8..12], 123_456_789u32.to_le_bytes());
+    assert_eq!(bytes[12..44], [0; 32]);
+    assert_eq!(bytes[44..76], decoded::<Block>("block").header.merkle_root.encoded()[..]);
+    assert_eq!(bytes[76..108], btclib::MIN_TARGET.encoded()[..]);
+    assert_eq!(bytes[108..116], 42u64.to_le_bytes());
+}
+
+#[test]
+fn marks_coinbase_data_in_place_of_the_input_count() {
+    let bytes = encoding_of("tagged_coinbase");
+    assert_eq!(bytes[0..4], [0xFF; 4]);
+    assert_eq!(bytes[4..12], 0x0000_0007_0000_0001u64.to_le_bytes());
+    assert_eq!(bytes[12..16], 5u32.to_le_bytes());
+    assert_eq!(&bytes[16..21], b"miner");
+    assert_eq!(bytes[21..25], 1u32.to_le_bytes());
+    // Without data a coinbase is a transaction with no inputs
+    assert_eq!(encoding_of("coinbase")[0..4], 0u32.to_le_bytes());
+}
+
+#[test]
+fn refuses_trailing_and_missing_bytes() {
+    let mut bytes = encoding_of("header");
+    bytes.push(0);
+    assert!(BlockHeader::decode_all(&bytes).is_err());
+    assert!(BlockHeader::decode_all(&bytes[..115]).is_err());
+}
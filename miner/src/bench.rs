@@ -0,0 +1,54 @@
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use primitive_types::U256;
+use btclib::crypto::{Hash, MerkleRoot};
+use btclib::types::{BlockHeader, CoinbaseData, Transaction};
+use crate::engine::format_hashrate;
+
+/// Nonces tried between two looks at the clock
+const STEPS: usize = 1 << 16;
+
+/// Hash synthetic headers with `BlockHeader::mine` for `duration` on each number of threads, and
+/// print how fast it went
+pub fn run(thread_counts: &[usize], duration: Duration) {
+    println!("Mining synthetic headers for {}s per run", duration.as_secs_f64());
+    println!("{:>8} {:>14} {:>14} {:>8}", "threads", "total", "per thread", "scaling");
+    let mut single = None;
+    for &threads in thread_counts {
+        let rate = hashrate(threads.max(1), duration);
+        let single = *single.get_or_insert(rate / threads.max(1) as f64);
+        println!(
+            "{:>8} {:>14} {:>14} {:>7.2}x",
+            threads,
+            format_hashrate(rate),
+            format_hashrate(rate / threads.max(1) as f64),
+            rate / single,
+        );
+    }
+}
+
+/// Hashes per second over `threads` threads, each on a header of its own
+fn hashrate(threads: usize, duration: Duration) -> f64 {
+    let started = Instant::now();
+    let workers: Vec<_> = (0..threads)
+        .map(|index| thread::spawn(move || {
+            let mut header = synthetic_header(index as u64);
+            let mut hashes = 0u64;
+            while started.elapsed() < duration {
+                // A zero target is never met, so every step is a hash, after the one checking
+                // whether the header was already mined
+                header.mine(STEPS);
+                hashes += STEPS as u64 + 1;
+            }
+            hashes
+        }))
+        .collect();
+    let hashes: u64 = workers.into_iter().map(|worker| worker.join().expect("Benchmark thread panicked")).sum();
+    hashes as f64 / started.elapsed().as_secs_f64()
+}
+
+fn synthetic_header(index: u64) -> BlockHeader {
+    let coinbase = Transaction::coinbase(vec![], CoinbaseData { extra_nonce: index, data: b"bench".to_vec() });
+    BlockHeader::new(Utc::now(), 0, Hash::zero(), MerkleRoot::calculate(&[coinbase]), U256::zero())
+}
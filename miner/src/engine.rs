@@ -0,0 +1,350 @@
+use std::ops::Range;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Instant;
+use flume::Sender;
+use log::info;
+use primitive_types::U256;
+use btclib::types::{u256_to_f64, Block, BlockHeader, HeaderHasher};
+
+/// Something that can hash headers, the CPU by default. Other backends, a GPU for instance, only
+/// have to search the nonces they are handed
+pub trait MiningBackend: Send + Sync + 'static {
+    fn name(&self) -> &'static str;
+
+    /// Try the nonces in order, returning the first one giving the header a hash within `target`
+    fn search(&self, header: &BlockHeader, target: U256, nonces: Range<u64>) -> Option<u64>;
+
+    /// How many nonces to hand out at once. Workers notice a new template between batches, so
+    /// a batch should take a few milliseconds at most
+    fn batch_size(&self) -> u64 {
+        1 << 14
+    }
+}
+
+/// Plain SHA-256 on the CPU, one worker per thread
+pub struct CpuBackend;
+impl MiningBackend for CpuBackend {
+    fn name(&self) -> &'static str {
+        "cpu"
+    }
+
+    fn search(&self, header: &BlockHeader, target: U256, nonces: Range<u64>) -> Option<u64> {
+        let mut hasher = HeaderHasher::new(header);
+        nonces.into_iter().find(|nonce| hasher.hash_with_nonce(*nonce).matches_target(target))
+    }
+}
+
+/// A template being mined, `stop` is raised once it is replaced or solved
+struct Job {
+    block: Block,
+    target: U256,
+    nonces: Range<u64>,
+    /// Report every nonce within the target rather than stopping at the first, as pools want shares
+    shares: bool,
+    stop: AtomicBool,
+}
+
+struct State {
+    job: Option<Arc<Job>>,
+    shutdown: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Wakes up the parked workers when there is a template again
+    wakeup: Condvar,
+    hashes: AtomicU64,
+}
+impl Shared {
+    /// Drop a solved `job` if it is still the current one, parking the workers
+    fn finish(&self, job: &Arc<Job>) {
+        let mut state = self.state.lock().unwrap();
+        if state.job.as_ref().is_some_and(|current| Arc::ptr_eq(current, job)) {
+            state.job = None;
+        }
+    }
+}
+
+/// Mines templates on several threads, each searching its own slice of the nonces. Workers park
+/// while there is nothing to mine and solved blocks come out of the channel given at start
+pub struct MiningEngine {
+    shared: Arc<Shared>,
+    threads: usize,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+impl MiningEngine {
+    pub fn start(threads: usize, backend: Arc<dyn MiningBackend>, found: Sender<Block>) -> Self {
+        let threads = threads.max(1);
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State { job: None, shutdown: false }),
+            wakeup: Condvar::new(),
+            hashes: AtomicU64::new(0),
+        });
+        let workers = (0..threads)
+            .map(|index| {
+                let (shared, backend, found) = (shared.clone(), backend.clone(), found.clone());
+                thread::Builder::new()
+                    .name(format!("miner-{}", index))
+                    .spawn(move || Self::work(&shared, backend.as_ref(), (index, threads), &found))
+                    .expect("Failed to spawn mining thread")
+            })
+            .collect();
+        info!("⛏️ Mining on {} {} thread(s)", threads, backend.name());
+        MiningEngine { shared, threads, workers }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Start mining `block`, abandoning the template mined so far
+    pub fn mine(&self, block: Block) {
+        let target = block.header.target;
+        self.start_job(Job { block, target, nonces: 0..u64::MAX, shares: false, stop: AtomicBool::new(false) });
+    }
+
+    /// Look for hashes within `target` among `nonces`, handing out every one found until told otherwise
+    pub fn mine_shares(&self, block: Block, target: U256, nonces: Range<u64>) {
+        self.start_job(Job { block, target, nonces, shares: true, stop: AtomicBool::new(false) });
+    }
+
+    fn start_job(&self, job: Job) {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(previous) = state.job.replace(Arc::new(job)) {
+            previous.stop.store(true, Ordering::Relaxed);
+        }
+        self.shared.wakeup.notify_all();
+    }
+
+    /// Abandon the current template and park the workers
+    pub fn stop(&self) {
+        if let Some(job) = self.shared.state.lock().unwrap().job.take() {
+            job.stop.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Target of the block being mined, whatever the shares asked for
+    pub fn block_target(&self) -> Option<U256> {
+        self.shared.state.lock().unwrap().job.as_ref().map(|job| job.block.header.target)
+    }
+
+    /// Hashes computed by all workers since the start
+    pub fn hashes(&self) -> u64 {
+        self.shared.hashes.load(Ordering::Relaxed)
+    }
+
+    /// Worker `index` of `count`
+    fn work(shared: &Shared, backend: &dyn MiningBackend, (index, count): (usize, usize), found: &Sender<Block>) {
+        let batch = backend.batch_size().max(1);
+        // Template whose slice this worker went through without luck, the others may still be at it
+        let mut exhausted: Option<Arc<Job>> = None;
+        loop {
+            let job = {
+                let mut state = shared.state.lock().unwrap();
+                loop {
+                    if state.shutdown {
+                        return;
+                    }
+                    match &state.job {
+                        Some(job) if !job.stop.load(Ordering::Relaxed)
+                            && !exhausted.as_ref().is_some_and(|done| Arc::ptr_eq(done, job)) => break job.clone(),
+                        _ => state = shared.wakeup.wait(state).unwrap(),
+                    }
+                }
+            };
+            let slice = slice(&job.nonces, index, count);
+            let mut block = job.block.clone();
+            let mut start = slice.start;
+            let mut rounds = 0;
+            while !job.stop.load(Ordering::Relaxed) {
+                if start >= slice.end {
+                    // Out of nonces, a fresh extra nonce makes for headers nobody tried yet
+                    rounds += 1;
+                    if !block.set_extra_nonce(extra_nonce(&job.block, rounds, index, count)) {
+                        exhausted = Some(job);
+                        break;
+                    }
+                    start = slice.start;
+                }
+                let end = start.saturating_add(batch).min(slice.end);
+                match backend.search(&block.header, job.target, start..end) {
+                    Some(nonce) if job.shares => {
+                        shared.hashes.fetch_add(nonce - start + 1, Ordering::Relaxed);
+                        let mut share = block.clone();
+                        share.header.nonce = nonce;
+                        let _ = found.send(share);
+                        start = nonce + 1;
+                    }
+                    Some(nonce) => {
+                        shared.hashes.fetch_add(nonce - start + 1, Ordering::Relaxed);
+                        // Another worker may have solved it in the meantime
+                        if !job.stop.swap(true, Ordering::Relaxed) {
+                            block.header.nonce = nonce;
+                            let _ = found.send(block);
+                            shared.finish(&job);
+                        }
+                        break;
+                    }
+                    None => {
+                        shared.hashes.fetch_add(end - start, Ordering::Relaxed);
+                        start = end;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Extra nonce for worker `index` of `count` after running out of nonces `rounds` times. Only the
+/// lower half counts up, the upper one is the node's
+fn extra_nonce(template: &Block, rounds: u64, index: usize, count: usize) -> u64 {
+    let first = template.transactions.first().and_then(|coinbase| coinbase.coinbase.as_ref()).map_or(0, |coinbase| coinbase.extra_nonce);
+    let step = (rounds - 1) * count as u64 + index as u64 + 1;
+    (first & !0xFFFF_FFFF) | (first as u32).wrapping_add(step as u32) as u64
+}
+
+/// Part `index` of `count` roughly equal parts of `nonces`
+fn slice(nonces: &Range<u64>, index: usize, count: usize) -> Range<u64> {
+    let size = (nonces.end - nonces.start) / count as u64;
+    let start = nonces.start + size * index as u64;
+    start..if index + 1 == count { nonces.end } else { start + size }
+}
+
+impl Drop for MiningEngine {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.wakeup.notify_all();
+        self.stop();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Logs how fast the engine hashed since the last report
+pub struct HashrateMeter {
+    since: Instant,
+    hashes: u64,
+}
+impl HashrateMeter {
+    pub fn new(engine: &MiningEngine) -> Self {
+        HashrateMeter { since: Instant::now(), hashes: engine.hashes() }
+    }
+
+    pub fn report(&mut self, engine: &MiningEngine) {
+        let (now, hashes) = (Instant::now(), engine.hashes());
+        let rate = (hashes - self.hashes) as f64 / now.duration_since(self.since).as_secs_f64();
+        if rate > 0.0 {
+            match engine.block_target() {
+                Some(target) => info!(
+                    "⛏️ Hashrate: {} over {} thread(s), a block expected every {}",
+                    format_hashrate(rate), engine.threads(), format_duration(expected_hashes(target) / rate)
+                ),
+                None => info!("⛏️ Hashrate: {} over {} thread(s)", format_hashrate(rate), engine.threads()),
+            }
+        }
+        (self.since, self.hashes) = (now, hashes);
+    }
+}
+
+/// Hashes it takes on average to get one within `target`
+fn expected_hashes(target: U256) -> f64 {
+    2f64.powi(256) / (u256_to_f64(target) + 1.0)
+}
+
+/// Seconds in the two largest units that apply, like "3h 12m"
+fn format_duration(seconds: f64) -> String {
+    if !seconds.is_finite() {
+        return "never".to_string();
+    }
+    let seconds = seconds.round() as u64;
+    match seconds {
+        0 => "less than a second".to_string(),
+        1..60 => format!("{}s", seconds),
+        60..3600 => format!("{}m {}s", seconds / 60, seconds % 60),
+        3600..86400 => format!("{}h {}m", seconds / 3600, seconds % 3600 / 60),
+        _ => format!("{}d {}h", seconds / 86400, seconds % 86400 / 3600),
+    }
+}
+
+/// Hashes per second with a unit prefix, like "12.34 MH/s"
+pub fn format_hashrate(rate: f64) -> String {
+    let mut rate = rate;
+    for unit in ["H/s", "kH/s", "MH/s", "GH/s"] {
+        if rate < 1000.0 {
+            return format!("{:.2} {}", rate, unit);
+        }
+        rate /= 1000.0;
+    }
+    format!("{:.2} TH/s", rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::time::Duration;
+    use chrono::Utc;
+    use btclib::crypto::{Hash, MerkleRoot};
+    use btclib::types::{CoinbaseData, Transaction};
+    use super::*;
+
+    /// A template whose coinbase starts at `extra_nonce`
+    fn template(extra_nonce: u64) -> Block {
+        let transactions = vec![Transaction::coinbase(vec![], CoinbaseData { extra_nonce, data: vec![] })];
+        let header = BlockHeader::new(Utc::now(), 0, Hash::zero(), MerkleRoot::calculate(&transactions), U256::MAX);
+        Block::new(header, transactions)
+    }
+
+    #[test]
+    fn slices_cover_the_nonces_without_overlapping() {
+        for nonces in [0..u64::MAX, 0x1234_0000..0x1235_0000, 7..1007, 5..8, 3..3] {
+            for count in [1, 2, 3, 7, 16] {
+                let slices = (0..count).map(|index| slice(&nonces, index, count)).collect::<Vec<_>>();
+                assert_eq!(slices[0].start, nonces.start);
+                assert_eq!(slices[count - 1].end, nonces.end);
+                // Every slice picks up where the previous one ended
+                for pair in slices.windows(2) {
+                    assert_eq!(pair[0].end, pair[1].start, "{:?} over {} threads", nonces, count);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn extra_nonces_never_repeat_across_threads_and_rounds() {
+        let template = template(0xABCD_0000_FFFF_FFF0);
+        let count = 4;
+        let mut seen = HashSet::new();
+        for rounds in 1..=50 {
+            for index in 0..count {
+                let extra_nonce = extra_nonce(&template, rounds, index, count);
+                // The node's half is left alone, even when the miner's half wraps around
+                assert_eq!(extra_nonce >> 32, 0xABCD_0000);
+                assert!(seen.insert(extra_nonce), "round {} thread {}", rounds, index);
+            }
+        }
+        assert!(!seen.contains(&0xABCD_0000_FFFF_FFF0));
+    }
+
+    #[test]
+    fn threads_search_each_nonce_once() {
+        let (sender, shares) = flume::unbounded();
+        let engine = MiningEngine::start(3, Arc::new(CpuBackend), sender);
+        // No coinbase to change, so every thread stops at the end of its slice
+        let mut block = template(0);
+        block.transactions.clear();
+        engine.mine_shares(block, U256::MAX, 100..1100);
+        let mut nonces = HashSet::new();
+        while let Ok(share) = shares.recv_timeout(Duration::from_secs(5)) {
+            assert!(nonces.insert(share.header.nonce), "nonce {} found twice", share.header.nonce);
+            if nonces.len() == 1000 {
+                break;
+            }
+        }
+        assert_eq!(nonces, (100..1100).collect());
+        assert!(shares.recv_timeout(Duration::from_millis(200)).is_err());
+        assert_eq!(engine.hashes(), 1000);
+    }
+}
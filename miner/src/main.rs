@@ -1,28 +1,110 @@
+mod bench;
+mod engine;
 mod miner;
+mod stratum;
 
+use std::time::Duration;
 use anyhow::{anyhow, Result};
-use btclib::crypto::PublicKey;
+use base64::Engine;
+use base64::engine::general_purpose;
+use btclib::crypto::{PrivateKey, PublicKey};
+use btclib::network::CoinbaseSpec;
 use btclib::util::Saveable;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use env_logger::Env;
 use miner::Miner;
+use stratum::PoolMiner;
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Node to mine for. Repeat it, or separate them with commas, for nodes to fail over to
+    #[arg(short, long, required_unless_present = "pool", value_delimiter = ',')]
+    node: Vec<String>,
+    /// Mine for a pool speaking Stratum instead of a node, rewards going to the public key in
+    /// proportion to the shares found
+    #[arg(long, conflicts_with = "node")]
+    pool: Option<String>,
+    /// Name of this rig at the pool
+    #[arg(long, requires = "pool")]
+    worker: Option<String>,
+    /// Public key the rewards go to. Repeat it to split them, with an optional ':WEIGHT' after the file
+    /// for uneven parts. Pools pay the first
+    #[arg(short, long, required = true)]
+    public_key_file: Vec<String>,
+    /// Key signing the blocks submitted, nodes may only take blocks from the miners they know
+    #[arg(short = 'k', long, required_unless_present = "pool", conflicts_with = "pool")]
+    private_key_file: Option<String>,
+    /// Text to put in the coinbase of the blocks mined, like a tag naming the miner
+    #[arg(long, conflicts_with = "pool")]
+    coinbase_data: Option<String>,
+    /// Encrypt the connection to the node
+    #[arg(long)]
+    encrypt: bool,
+    /// Public key the nodes must prove to own, implies --encrypt
+    #[arg(long)]
+    node_key_file: Option<String>,
+    /// Mining threads, one per CPU core by default
     #[arg(short, long)]
-    node: String,
-    #[arg(short, long)]
-    public_key_file: String
+    threads: Option<usize>
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Measure how many hashes per second this machine does, on more and more threads
+    Bench {
+        /// Thread counts to try, separated by commas. Powers of two up to one per CPU core by default
+        #[arg(short, long, value_delimiter = ',')]
+        threads: Vec<usize>,
+        /// Seconds each thread count is measured for
+        #[arg(short, long, default_value_t = 5)]
+        seconds: u64,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
     let cli = Cli::parse();
-    let public_key = PublicKey::load_from_file(&cli.public_key_file).map_err(|e| {
-        anyhow!("Error reading public key: {}", e)
-    })?;
-    let miner: Miner = Miner::new(cli.node, public_key).await?;
-    miner.run().await
+    let cores = std::thread::available_parallelism().map(|cores| cores.get()).unwrap_or(1);
+    if let Some(Command::Bench { mut threads, seconds }) = cli.command {
+        if threads.is_empty() {
+            threads = (0..).map(|power| 1 << power).take_while(|count| *count < cores).chain([cores]).collect();
+        }
+        bench::run(&threads, Duration::from_secs(seconds));
+        return Ok(());
+    }
+    let payees = cli.public_key_file.iter().map(|file| payee(file)).collect::<Result<Vec<_>>>()?;
+    let coinbase = CoinbaseSpec { payees, data: cli.coinbase_data.unwrap_or_default().into_bytes() };
+    if coinbase.data.len() > btclib::MAX_COINBASE_DATA {
+        return Err(anyhow!("Coinbase data takes at most {} bytes", btclib::MAX_COINBASE_DATA));
+    }
+    let node_key = cli.node_key_file
+        .map(|file| PublicKey::load_from_file(&file).map_err(|e| anyhow!("Error reading node key: {}", e)))
+        .transpose()?;
+    let threads = cli.threads.unwrap_or(cores);
+    if let Some(pool) = cli.pool {
+        // Pools know workers by the key their rewards go to
+        let mut worker = general_purpose::STANDARD.encode(coinbase.payees[0].0.to_bytes());
+        if let Some(rig) = cli.worker {
+            worker = format!("{}.{}", worker, rig);
+        }
+        return PoolMiner::new(pool, worker, threads).run().await;
+    }
+    let key_file = cli.private_key_file.expect("BUG: clap requires a private key without a pool");
+    let key = PrivateKey::load_from_file(&key_file).map_err(|e| anyhow!("Error reading private key: {}", e))?;
+    Miner::new(cli.node, coinbase, key, cli.encrypt, node_key, threads).run().await;
+    Ok(())
+}
+
+/// A public key file with an optional ':WEIGHT' after it
+fn payee(argument: &str) -> Result<(PublicKey, u32)> {
+    let (file, weight) = match argument.rsplit_once(':').map(|(file, weight)| (file, weight.parse::<u32>())) {
+        Some((file, Ok(weight))) => (file, weight),
+        _ => (argument, 1),
+    };
+    let public_key = PublicKey::load_from_file(file).map_err(|e| anyhow!("Error reading public key '{}': {}", file, e))?;
+    Ok((public_key, weight))
 }
\ No newline at end of file
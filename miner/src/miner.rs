@@ -1,133 +1,170 @@
+use std::collections::VecDeque;
+use std::io::Result as IoResult;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread;
 use std::time::Duration;
 use anyhow::anyhow;
-use flume::{Receiver, Sender};
-use tokio::net::TcpStream;
-use tokio::sync::Mutex;
-use tokio::time::interval;
-use btclib::crypto::PublicKey;
-use btclib::network::Message;
+use flume::Receiver;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::mpsc;
+use tokio::time::{interval, sleep, timeout};
+use btclib::crypto::{PrivateKey, PublicKey, Signature};
+use btclib::network::{CoinbaseSpec, Message};
+use btclib::transport::{Connection, MessageWriter};
 use btclib::types::Block;
 use log::{info, warn};
+use crate::engine::{CpuBackend, HashrateMeter, MiningEngine};
+
+/// How often to log the hashrate
+pub(crate) const HASHRATE_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a node may take to accept the connection and finish the handshake
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait before going through the nodes, or trying the pool, again once none could be reached, doubled every round
+pub(crate) const BASE_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest wait between two rounds of connection attempts
+pub(crate) const MAX_BACKOFF: Duration = Duration::from_secs(60);
 
 pub struct Miner {
-    public_key: PublicKey,
-    conn: Mutex<TcpStream>,
-    current_template: Arc<std::sync::Mutex<Option<Block>>>,
-    mining: Arc<AtomicBool>,
+    /// Nodes to mine for, in order of preference
+    nodes: Vec<String>,
+    encrypt: bool,
+    node_key: Option<PublicKey>,
+    coinbase: CoinbaseSpec,
+    /// Signs the blocks submitted
+    key: PrivateKey,
+    engine: MiningEngine,
     mined_block_receiver: Receiver<Block>,
-    mined_block_sender: Sender<Block>
+    /// Blocks found and not handed to a node yet, oldest first
+    unsubmitted: VecDeque<Block>,
+    /// Nodes tried in a row without getting a template out of them
+    failures: usize,
+    backoff: Duration,
 }
 impl Miner {
-    pub(crate) async fn new(address: String, public_key: PublicKey) -> anyhow::Result<Self> {
-        let stream = TcpStream::connect(&address).await?;
+    /// Mine for one of `nodes`, encrypted if `encrypt` is set. `node_key` pins the identity the nodes
+    /// must prove during the handshake, which implies encryption
+    pub(crate) fn new(nodes: Vec<String>, coinbase: CoinbaseSpec, key: PrivateKey, encrypt: bool, node_key: Option<PublicKey>, threads: usize) -> Self {
         let (mined_block_sender, mined_block_receiver) = flume::unbounded();
-        info!("🔗 Connected to node: [{}]", address);
-        Ok(Self {
-            public_key,
-            conn: Mutex::new(stream),
-            current_template: Arc::new(std::sync::Mutex::new(None)),
-            mining: Arc::new(AtomicBool::new(false)),
-            mined_block_sender,
-            mined_block_receiver
-        })
-    }
-
-    pub(crate) async fn run(&self) -> anyhow::Result<()> {
-        self.spawn_mining_thread();
-        let mut template_interval = interval(Duration::from_secs(5));
-        loop {
-            let receiver_clone = self.mined_block_receiver.clone();
-            tokio::select! {
-                _ = template_interval.tick() => {
-                    self.fetch_and_validate_template().await?;
-                }
-                Ok(mined_block) = receiver_clone.recv_async() => {
-                    self.submit_block(mined_block).await?;
-                }
-            }
+        Self {
+            nodes,
+            encrypt,
+            node_key,
+            coinbase,
+            key,
+            engine: MiningEngine::start(threads, Arc::new(CpuBackend), mined_block_sender),
+            mined_block_receiver,
+            unsubmitted: VecDeque::new(),
+            failures: 0,
+            backoff: BASE_BACKOFF,
         }
     }
 
-    fn spawn_mining_thread(&self) -> thread::JoinHandle<()> {
-        let template = self.current_template.clone();
-        let mining = self.mining.clone();
-        let sender = self.mined_block_sender.clone();
-        thread::spawn(move || loop {
-            if mining.load(Ordering::Relaxed) {
-                if let Some(mut block) = template.lock().unwrap().clone() {
-                    if block.header.mine(2_000_000) {
-                        info!("📦️Block mined: {}{}", " ".repeat(22), block.hash());
-                        sender.send(block).expect("Failed to send mined block", );
-                        mining.store(false, Ordering::Relaxed);
-                    }
+    /// Mine for the first node that answers, failing over to the next one whenever it cannot be
+    /// reached or the connection drops. Runs until the process is killed
+    pub(crate) async fn run(mut self) {
+        let nodes = self.nodes.clone();
+        for node in nodes.iter().cycle() {
+            match self.connect(node).await {
+                Ok(conn) => {
+                    let e = self.mine_for(node, conn).await;
+                    warn!("🔌 Connection to node [{}] lost: {}", node, e);
                 }
+                Err(e) => warn!("⚠️ Failed to connect to node [{}]: {}", node, e),
+            }
+            self.failures += 1;
+            if self.failures >= nodes.len() {
+                info!("⏳ No node to mine for, trying again in {}s", self.backoff.as_secs());
+                sleep(self.backoff).await;
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                self.failures = 0;
             }
-            thread::yield_now();
-        })
+        }
     }
 
-    async fn fetch_and_validate_template(&self) -> anyhow::Result<()> {
-        if !self.mining.load(Ordering::Relaxed) {
-            self.fetch_template().await?;
-        } else {
-            self.validate_template().await?;
+    async fn connect(&self, node: &str) -> anyhow::Result<Connection> {
+        // Miners need no lasting identity, a throwaway key is enough for the handshake
+        let identity = (self.encrypt || self.node_key.is_some()).then(PrivateKey::new_key);
+        let conn = timeout(CONNECT_TIMEOUT, Connection::connect(node, identity.as_ref(), self.node_key.as_ref()))
+            .await
+            .map_err(|_| anyhow!("timed out"))??;
+        match conn.remote_key() {
+            Some(key) => info!("🔒 Connected to node: [{}], identity {}", node, key.fingerprint()),
+            None => info!("🔗 Connected to node: [{}]", node),
         }
-        Ok(())
+        Ok(conn)
     }
 
-    async fn fetch_template(&self) -> anyhow::Result<()> {
-        info!("Fetching new template");
-        let message = Message::FetchTemplate(self.public_key.clone());
-        let mut conn_lock = self.conn.lock().await;
-        message.send_async(&mut *conn_lock).await?;
-        drop(conn_lock);
-        let mut conn_lock = self.conn.lock().await;
-        match Message::receive_async(&mut *conn_lock).await? {
-            Message::Template(template) => {
-                drop(conn_lock);
-                info!("↪️ Received new template with target: {}", template.header.target);
-                *self.current_template.lock().unwrap() = Some(template);
-                self.mining.store(true, Ordering::Relaxed);
-                Ok(())
+    /// Mine the templates the node pushes, switching as soon as a new one arrives, and submit the
+    /// blocks found over the same connection, those left over from earlier connections first.
+    /// Only returns once the connection is lost
+    async fn mine_for(&mut self, node: &str, conn: Connection) -> anyhow::Error {
+        let (mut reader, mut writer) = conn.into_split();
+        if let Err(e) = writer.send(&Message::SubscribeTemplates(self.coinbase.clone())).await {
+            return e.into();
+        }
+        // Blocks found while no node was there to take them
+        self.unsubmitted.extend(self.mined_block_receiver.drain());
+        if !self.unsubmitted.is_empty() {
+            info!("🚚 Resubmitting {} block(s) to [{}]", self.unsubmitted.len(), node);
+            if let Err(e) = submit(&mut self.unsubmitted, &self.key, &mut writer).await {
+                return e.into();
             }
-            _ => Err(anyhow!("Unexpected message received when fetching template")),
         }
-    }
-
-    async fn validate_template(&self) -> anyhow::Result<()> {
-        if let Some(template) = self.current_template.lock().unwrap().clone() {
-            let message = Message::ValidateTemplate(template);
-            let mut conn_lock = self.conn.lock().await;
-            message.send_async(&mut *conn_lock).await?;
-            drop(conn_lock);
-            let mut conn_lock = self.conn.lock().await;
-            match Message::receive_async(&mut *conn_lock).await? {
-                Message::TemplateValidity(valid) => {
-                    drop(conn_lock);
-                    if !valid {
-                        warn!("Current template is no longer valid");
-                        self.mining.store(false, Ordering::Relaxed);
-                    } else {
-                        info!("Current template is still valid");
+        // Reading in a task of its own, so submitting a block never cuts an incoming template in half
+        let (template_sender, mut templates) = mpsc::unbounded_channel();
+        let reading = tokio::spawn(async move {
+            loop {
+                match reader.receive().await {
+                    Ok(Message::Template(template)) => {
+                        if template_sender.send(template).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(Message::Goodbye) => break,
+                    Ok(message) => warn!("Unexpected message from node: {}", message.kind()),
+                    Err(e) => {
+                        warn!("🔌 Connection to node lost: {}", e);
+                        break;
                     }
-                    Ok(())
                 }
-                _ => Err(anyhow!("Unexpected message received when validating template")),
             }
-        } else {
-            Ok(())
-        }
+        });
+        let mined_blocks = self.mined_block_receiver.clone();
+        let mut hashrate_interval = interval(HASHRATE_INTERVAL);
+        let mut hashrate = HashrateMeter::new(&self.engine);
+        let error = loop {
+            tokio::select! {
+                template = templates.recv() => match template {
+                    Some(template) => {
+                        info!("↪️ Received new template with target: {}", template.header.target);
+                        // The node works, start over with a short wait the next time it does not
+                        (self.failures, self.backoff) = (0, BASE_BACKOFF);
+                        self.engine.mine(template);
+                    }
+                    None => break anyhow!("Node closed the connection"),
+                },
+                Ok(mined_block) = mined_blocks.recv_async() => {
+                    info!("📦️Block mined: {}{}", " ".repeat(22), mined_block.hash());
+                    info!("🚚 Submitting mined block");
+                    // Kept until sent, for the next node should this one be gone
+                    self.unsubmitted.push_back(mined_block);
+                    if let Err(e) = submit(&mut self.unsubmitted, &self.key, &mut writer).await {
+                        break e.into();
+                    }
+                }
+                _ = hashrate_interval.tick() => hashrate.report(&self.engine),
+            }
+        };
+        reading.abort();
+        error
     }
+}
 
-    async fn submit_block(&self, block: Block) -> anyhow::Result<()> {
-        info!("🚚 Submitting mined block");
-        let message = Message::SubmitTemplate(block, self.public_key.clone());
-        let mut conn_lock = self.conn.lock().await;
-        message.send_async(&mut *conn_lock).await?;
-        self.mining.store(false, Ordering::Relaxed);
-        Ok(())
+/// Send the blocks in order, each one signed, dropping them from the queue once sent
+async fn submit(blocks: &mut VecDeque<Block>, key: &PrivateKey, writer: &mut MessageWriter<OwnedWriteHalf>) -> IoResult<()> {
+    while let Some(block) = blocks.front() {
+        let signature = Signature::sign_block(&block.hash(), key);
+        writer.send(&Message::SubmitTemplate(block.clone(), key.public_key(), signature)).await?;
+        blocks.pop_front();
     }
-}
\ No newline at end of file
+    Ok(())
+}
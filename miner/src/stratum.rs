@@ -0,0 +1,274 @@
+use std::collections::VecDeque;
+use std::io::Result as IoResult;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use flume::Receiver;
+use log::{info, warn};
+use primitive_types::U256;
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::mpsc;
+use tokio::time::{interval, sleep, timeout};
+use btclib::encoding::Encode;
+use btclib::stratum::{self, Call, Job, Line, LineReader, Reply, Share, Subscription};
+use btclib::types::{Block, BlockHeader};
+use crate::engine::{CpuBackend, HashrateMeter, MiningEngine};
+use crate::miner::{BASE_BACKOFF, CONNECT_TIMEOUT, HASHRATE_INTERVAL, MAX_BACKOFF};
+
+/// Jobs remembered for the shares found on them after a newer one arrived
+const MAX_JOBS: usize = 4;
+/// Shares kept for the pool while it cannot be reached, the oldest are dropped first
+const MAX_UNSUBMITTED: usize = 4096;
+const SUBSCRIBE_ID: u64 = 0;
+const AUTHORIZE_ID: u64 = 1;
+
+/// Mines for a pool, which pays out in proportion to the shares found rather than per block
+pub struct PoolMiner {
+    address: String,
+    worker: String,
+    engine: MiningEngine,
+    shares: Receiver<Block>,
+    /// Kept across connections, so shares found on older jobs can still be told apart
+    work: Work,
+    /// Shares found and not handed to the pool yet, oldest first
+    unsubmitted: VecDeque<Share>,
+    backoff: Duration,
+}
+impl PoolMiner {
+    /// `worker` names the public key rewards go to, see `btclib::stratum`
+    pub(crate) fn new(address: String, worker: String, threads: usize) -> Self {
+        let (share_sender, shares) = flume::unbounded();
+        PoolMiner {
+            address,
+            worker,
+            engine: MiningEngine::start(threads, Arc::new(CpuBackend), share_sender),
+            shares,
+            work: Work::default(),
+            unsubmitted: VecDeque::new(),
+            backoff: BASE_BACKOFF,
+        }
+    }
+
+    /// Mine for the pool, connecting again whenever it cannot be reached or the connection drops.
+    /// Only returns if the pool refuses us
+    pub(crate) async fn run(mut self) -> Result<()> {
+        loop {
+            match timeout(CONNECT_TIMEOUT, TcpStream::connect(&self.address)).await {
+                Ok(Ok(stream)) => {
+                    info!("🔗 Connected to pool: [{}]", self.address);
+                    let e = self.mine_for(stream).await;
+                    if self.work.refused {
+                        return Err(e);
+                    }
+                    warn!("🔌 Connection to pool [{}] lost: {}", self.address, e);
+                }
+                Ok(Err(e)) => warn!("⚠️ Failed to connect to pool [{}]: {}", self.address, e),
+                Err(_) => warn!("⚠️ Failed to connect to pool [{}]: timed out", self.address),
+            }
+            info!("⏳ Trying the pool again in {}s", self.backoff.as_secs());
+            sleep(self.backoff).await;
+            self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Mine the jobs the pool pushes and submit the shares found, those left over from earlier
+    /// connections first. Only returns once the connection is lost
+    async fn mine_for(&mut self, stream: TcpStream) -> anyhow::Error {
+        let (reader, mut writer) = stream.into_split();
+        // Reading in a task of its own, so submitting a share never cuts an incoming job in half
+        let (line_sender, mut lines) = mpsc::unbounded_channel();
+        let reading = tokio::spawn(async move {
+            let mut reader = LineReader::new(reader);
+            loop {
+                match reader.receive().await {
+                    Ok(Some(line)) => {
+                        if line_sender.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("🔌 Connection to pool lost: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+        let error = self.session(&mut writer, &mut lines).await;
+        reading.abort();
+        error
+    }
+
+    /// Subscribe, authorize and mine until the connection fails
+    async fn session(&mut self, writer: &mut OwnedWriteHalf, lines: &mut mpsc::UnboundedReceiver<Line>) -> anyhow::Error {
+        // Asking for the nonces we had, which the shares found meanwhile were mined with
+        let resume = self.work.subscription.map(|subscription| json!(subscription)).into_iter().collect();
+        if let Err(e) = stratum::send_line(writer, &Call { id: Some(SUBSCRIBE_ID), method: stratum::SUBSCRIBE.to_string(), params: resume }).await {
+            return e.into();
+        }
+        let authorize = vec![self.worker.clone().into(), Value::String(String::new())];
+        if let Err(e) = stratum::send_line(writer, &Call { id: Some(AUTHORIZE_ID), method: stratum::AUTHORIZE.to_string(), params: authorize }).await {
+            return e.into();
+        }
+        let mut next_id = AUTHORIZE_ID + 1;
+        let shares = self.shares.clone();
+        // Shares found while no pool was there to take them, matched to their jobs before a new
+        // job can drop the old ones. The pool handles calls in order, so they follow the authorization
+        let found = shares.drain().collect::<Vec<_>>();
+        let dropped = found.len().saturating_sub(MAX_UNSUBMITTED);
+        if dropped > 0 {
+            warn!("🗑️ Dropping {} share(s) found while the pool was away", dropped);
+        }
+        self.queue(found.into_iter().skip(dropped));
+        if !self.unsubmitted.is_empty() {
+            info!("🚚 Resubmitting {} share(s)", self.unsubmitted.len());
+            if let Err(e) = submit(&mut self.unsubmitted, &mut next_id, writer).await {
+                return e.into();
+            }
+        }
+        let mut hashrate_interval = interval(HASHRATE_INTERVAL);
+        let mut hashrate = HashrateMeter::new(&self.engine);
+        loop {
+            tokio::select! {
+                line = lines.recv() => {
+                    let Some(line) = line else {
+                        return anyhow!("Pool closed the connection");
+                    };
+                    if let Err(e) = self.work.handle(line) {
+                        return e;
+                    }
+                    if self.work.changed {
+                        self.work.changed = false;
+                        // The pool works, start over with a short wait the next time it does not
+                        self.backoff = BASE_BACKOFF;
+                        self.work.start(&self.engine);
+                    }
+                }
+                Ok(share) = shares.recv_async() => {
+                    // Kept until sent, for the next connection should this one be gone
+                    self.queue([share]);
+                    if let Err(e) = submit(&mut self.unsubmitted, &mut next_id, writer).await {
+                        return e.into();
+                    }
+                }
+                _ = hashrate_interval.tick() => hashrate.report(&self.engine),
+            }
+        }
+    }
+
+    fn queue(&mut self, shares: impl IntoIterator<Item = Block>) {
+        for share in shares {
+            let Some(job_id) = self.work.job_of(&share.header) else {
+                continue;
+            };
+            if share.hash().matches_target(share.header.target) {
+                info!("📦️Block mined: {}{}", " ".repeat(22), share.hash());
+            }
+            self.unsubmitted.push_back(Share { worker: self.worker.clone(), job_id, nonce: share.header.nonce });
+            if self.unsubmitted.len() > MAX_UNSUBMITTED {
+                self.unsubmitted.pop_front();
+            }
+        }
+    }
+}
+
+/// Send the shares in order, dropping them from the queue once sent
+async fn submit(shares: &mut VecDeque<Share>, next_id: &mut u64, writer: &mut OwnedWriteHalf) -> IoResult<()> {
+    while let Some(share) = shares.front() {
+        stratum::send_line(writer, &share.to_call(*next_id)).await?;
+        *next_id += 1;
+        shares.pop_front();
+    }
+    Ok(())
+}
+
+/// What the pool asked for so far
+#[derive(Default)]
+struct Work {
+    subscription: Option<Subscription>,
+    target: Option<U256>,
+    /// Newest last, with the nonce left out of their headers
+    jobs: VecDeque<(String, Vec<u8>, BlockHeader)>,
+    /// Whether the engine should start over with the newest job
+    changed: bool,
+    /// Whether the pool refused the subscription or the worker, no use connecting again
+    refused: bool,
+    accepted: u64,
+    rejected: u64,
+}
+impl Work {
+    fn handle(&mut self, line: Line) -> Result<()> {
+        match line {
+            Line::Reply(Reply { id: SUBSCRIBE_ID, result, error }) => {
+                let subscription: Subscription = match error {
+                    Some(error) => {
+                        self.refused = true;
+                        return Err(anyhow!("Pool refused the subscription: {}", error));
+                    }
+                    None => serde_json::from_value(result)?,
+                };
+                if self.subscription.is_some_and(|previous| previous.nonce_prefix != subscription.nonce_prefix) {
+                    warn!("📋 Pool gave us other nonces, the shares found on earlier jobs will not count");
+                }
+                info!("📋 Subscribed to pool, nonces {:#x}..{:#x}", subscription.nonces().start, subscription.nonces().end);
+                self.subscription = Some(subscription);
+                self.changed = true;
+            }
+            Line::Reply(Reply { id: AUTHORIZE_ID, result, error }) => {
+                if error.is_some() || result != Value::Bool(true) {
+                    self.refused = true;
+                    return Err(anyhow!("Pool refused the worker: {}", error.map(|e| e.to_string()).unwrap_or_default()));
+                }
+                info!("👷 Worker authorized");
+            }
+            Line::Reply(Reply { error: Some(error), .. }) => {
+                self.rejected += 1;
+                warn!("❌ Share rejected: {} ({} accepted, {} rejected)", error, self.accepted, self.rejected);
+            }
+            Line::Reply(_) => {
+                self.accepted += 1;
+                info!("✅ Share accepted ({} accepted, {} rejected)", self.accepted, self.rejected);
+            }
+            Line::Call(call) => match call.method.as_str() {
+                stratum::SET_TARGET => {
+                    self.target = Some(stratum::target_from_params(&call.params).map_err(|e| anyhow!("{}", e))?);
+                    self.changed = true;
+                }
+                stratum::NOTIFY => {
+                    let job = Job::from_params(&call.params).map_err(|e| anyhow!("{}", e))?;
+                    if job.clean {
+                        self.jobs.clear();
+                    }
+                    self.jobs.push_back((job.id, without_nonce(&job.header), job.header));
+                    if self.jobs.len() > MAX_JOBS {
+                        self.jobs.pop_front();
+                    }
+                    self.changed = true;
+                }
+                method => warn!("Unexpected call from pool: {}", method),
+            },
+        }
+        Ok(())
+    }
+
+    /// Mine the newest job, once the pool told us everything needed
+    fn start(&self, engine: &MiningEngine) {
+        if let (Some(subscription), Some(target), Some((id, _, header))) = (self.subscription, self.target, self.jobs.back()) {
+            info!("↪️ Received job {} with share target: {}", id, target);
+            engine.mine_shares(Block::new(header.clone(), vec![]), target, subscription.nonces());
+        }
+    }
+
+    fn job_of(&self, header: &BlockHeader) -> Option<String> {
+        let header = without_nonce(header);
+        self.jobs.iter().find(|(_, job, _)| *job == header).map(|(id, ..)| id.clone())
+    }
+}
+
+fn without_nonce(header: &BlockHeader) -> Vec<u8> {
+    let mut header = header.clone();
+    header.nonce = 0;
+    header.encoded()
+}
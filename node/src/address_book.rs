@@ -0,0 +1,264 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use btclib::util::Saveable;
+
+/// Delay before retrying an address that failed once, doubled on every further failure
+const BASE_BACKOFF_SECS: i64 = 10;
+/// Longest delay between two attempts to reach an address
+const MAX_BACKOFF_SECS: i64 = 3600;
+/// Addresses failing this many times in a row are forgotten, unless they were seen recently
+const MAX_FAILURES: u32 = 10;
+/// How long a once reachable address is kept despite failures
+const FORGET_AFTER_HOURS: i64 = 24;
+/// Most addresses sent in one Addr message
+pub const MAX_ADDR_ENTRIES: usize = 100;
+
+/// What we know about an address of another node
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AddressInfo {
+    /// Last time we had a working connection with it
+    pub last_seen: Option<DateTime<Utc>>,
+    /// Last time connecting to it failed
+    pub last_failed: Option<DateTime<Utc>>,
+    /// Failed attempts since it was last seen
+    pub failures: u32,
+}
+impl AddressInfo {
+    /// Whether enough time passed since the last failure to try again
+    fn can_retry(&self, now: DateTime<Utc>) -> bool {
+        let Some(last_failed) = self.last_failed else {
+            return true;
+        };
+        let backoff = BASE_BACKOFF_SECS
+            .saturating_mul(1 << self.failures.saturating_sub(1).min(16))
+            .min(MAX_BACKOFF_SECS);
+        now - last_failed >= Duration::seconds(backoff)
+    }
+}
+
+/// Every node address we heard of, persisted so a restarted node does not depend on its seeds only
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AddressBook {
+    addresses: HashMap<String, AddressInfo>,
+    /// Addresses leading to ourselves or to a node we already reach under another address
+    #[serde(default, skip_serializing)]
+    ignored: HashSet<String>,
+    /// Addresses or bare hosts we refuse to talk to, until the given time
+    #[serde(default)]
+    banned: HashMap<String, DateTime<Utc>>,
+}
+impl AddressBook {
+    pub fn new() -> Self {
+        AddressBook { addresses: HashMap::new(), ignored: HashSet::new(), banned: HashMap::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    /// Add an address we heard of, keeping what we already know about it
+    pub fn add(&mut self, address: &str) {
+        if self.ignored.contains(address) || self.is_banned(address) {
+            return;
+        }
+        self.addresses.entry(address.to_string()).or_default();
+    }
+
+    /// Stop dialing an address, because it is our own or an alias of a connected node
+    pub fn ignore(&mut self, address: &str) {
+        self.addresses.remove(address);
+        self.ignored.insert(address.to_string());
+    }
+
+    /// Refuse an address until the given time. A host without port bans every port on it
+    pub fn ban(&mut self, address: &str, until: DateTime<Utc>) {
+        self.addresses.remove(address);
+        self.banned.insert(address.to_string(), until);
+    }
+
+    /// Lift a ban, returns false if the address was not banned
+    pub fn unban(&mut self, address: &str) -> bool {
+        self.banned.remove(address).is_some()
+    }
+
+    pub fn is_banned(&self, address: &str) -> bool {
+        let now = Utc::now();
+        let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+        [address, host].iter().any(|key| self.banned.get(*key).is_some_and(|until| *until > now))
+    }
+
+    /// Active bans and when they end
+    pub fn bans(&self) -> impl Iterator<Item = (&String, &DateTime<Utc>)> {
+        let now = Utc::now();
+        self.banned.iter().filter(move |(_, until)| **until > now)
+    }
+
+    pub fn mark_seen(&mut self, address: &str) {
+        if self.ignored.contains(address) {
+            return;
+        }
+        let info = self.addresses.entry(address.to_string()).or_default();
+        info.last_seen = Some(Utc::now());
+        info.failures = 0;
+    }
+
+    pub fn mark_failed(&mut self, address: &str) {
+        if self.ignored.contains(address) {
+            return;
+        }
+        let info = self.addresses.entry(address.to_string()).or_default();
+        info.last_failed = Some(Utc::now());
+        info.failures = info.failures.saturating_add(1);
+    }
+
+    /// Addresses worth dialing, the most recently seen first
+    pub fn candidates(&self, exclude: impl Fn(&str) -> bool) -> Vec<String> {
+        let now = Utc::now();
+        let mut candidates = self.addresses
+            .iter()
+            .filter(|(address, info)| !exclude(address) && !self.is_banned(address) && info.can_retry(now))
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|(_, info)| std::cmp::Reverse(info.last_seen));
+        candidates.into_iter().map(|(address, _)| address.clone()).collect()
+    }
+
+    /// Addresses that worked at some point, to be shared with other nodes
+    pub fn recently_seen(&self, limit: usize) -> Vec<String> {
+        let mut seen = self.addresses
+            .iter()
+            .filter_map(|(address, info)| info.last_seen.map(|last_seen| (address, last_seen)))
+            .collect::<Vec<_>>();
+        seen.sort_by_key(|(_, last_seen)| std::cmp::Reverse(*last_seen));
+        seen.into_iter().take(limit).map(|(address, _)| address.clone()).collect()
+    }
+
+    /// Forget addresses that keep failing and have not worked for a long time
+    pub fn prune(&mut self) -> usize {
+        let now = Utc::now();
+        self.banned.retain(|_, until| *until > now);
+        let before = self.addresses.len();
+        self.addresses.retain(|_, info| {
+            let seen_recently = info.last_seen
+                .is_some_and(|last_seen| now - last_seen < Duration::hours(FORGET_AFTER_HOURS));
+            info.failures < MAX_FAILURES || seen_recently
+        });
+        before - self.addresses.len()
+    }
+}
+/// Save and load expecting CBOR from ciborium as format
+impl Saveable for AddressBook {
+    const HEADER: bool = true;
+    const FORMAT_VERSION: u32 = 1;
+
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        ciborium::de::from_reader(reader).map_err(|_| {
+            IoError::new(IoErrorKind::InvalidData, "Failed to deserialize AddressBook")
+        })
+    }
+
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer).map_err(|_| {
+            IoError::new(IoErrorKind::InvalidData, "Failed to serialize AddressBook")
+        })
+    }
+
+    fn migrate<I: Read>(version: u32, reader: I) -> IoResult<Self> {
+        match version {
+            // Peers files from before headers, bans were added with a serde default so they still parse
+            0 => Self::load(reader),
+            _ => Err(btclib::util::unsupported_version(version, Self::FORMAT_VERSION)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failed(failures: u32, now: DateTime<Utc>) -> AddressInfo {
+        AddressInfo { last_seen: None, last_failed: Some(now), failures }
+    }
+
+    #[test]
+    fn backs_off_twice_as_long_after_every_failure() {
+        let now = Utc::now();
+        for (failures, backoff) in [(1, 10), (2, 20), (3, 40), (6, 320), (9, 2560)] {
+            let info = failed(failures, now);
+            assert!(!info.can_retry(now + Duration::seconds(backoff - 1)), "{} failures", failures);
+            assert!(info.can_retry(now + Duration::seconds(backoff)), "{} failures", failures);
+        }
+        // Capped, however many failures
+        for failures in [10, 17, 40, u32::MAX] {
+            assert!(failed(failures, now).can_retry(now + Duration::seconds(MAX_BACKOFF_SECS)));
+        }
+        assert!(AddressInfo::default().can_retry(now));
+    }
+
+    #[test]
+    fn skips_failed_addresses_and_starts_over_once_seen() {
+        let mut book = AddressBook::new();
+        book.add("a:9000");
+        book.add("b:9000");
+        for _ in 0..5 {
+            book.mark_failed("a:9000");
+        }
+        assert_eq!(book.candidates(|_| false), vec!["b:9000".to_string()]);
+        assert!(book.candidates(|address| address == "b:9000").is_empty());
+        // Working again, the next failure only waits the base backoff
+        book.mark_seen("a:9000");
+        book.mark_failed("a:9000");
+        let info = &book.addresses["a:9000"];
+        let last_failed = info.last_failed.unwrap();
+        assert_eq!(info.failures, 1);
+        assert!(info.can_retry(last_failed + Duration::seconds(BASE_BACKOFF_SECS)));
+    }
+
+    #[test]
+    fn bans_an_address_or_a_whole_host() {
+        let mut book = AddressBook::new();
+        let until = Utc::now() + Duration::hours(1);
+        book.add("a:9000");
+        book.ban("a:9000", until);
+        assert!(book.is_banned("a:9000"));
+        assert!(!book.is_banned("a:9001"));
+        assert!(book.candidates(|_| false).is_empty());
+        // Heard of again, still refused
+        book.add("a:9000");
+        assert!(book.is_empty());
+        book.ban("b", until);
+        assert!(book.is_banned("b:9000") && book.is_banned("b:1234"));
+        assert!(book.unban("b"));
+        assert!(!book.unban("b"));
+        assert!(!book.is_banned("b:9000"));
+    }
+
+    #[test]
+    fn bans_expire() {
+        let mut book = AddressBook::new();
+        book.ban("a:9000", Utc::now() - Duration::seconds(1));
+        assert!(!book.is_banned("a:9000"));
+        assert_eq!(book.bans().count(), 0);
+        book.add("a:9000");
+        assert_eq!(book.len(), 1);
+    }
+
+    #[test]
+    fn forgets_addresses_failing_for_long() {
+        let mut book = AddressBook::new();
+        for _ in 0..MAX_FAILURES {
+            book.mark_failed("gone:9000");
+            book.mark_failed("flaky:9000");
+        }
+        book.addresses.get_mut("flaky:9000").unwrap().last_seen = Some(Utc::now() - Duration::hours(1));
+        book.mark_failed("new:9000");
+        assert_eq!(book.prune(), 1);
+        assert!(!book.addresses.contains_key("gone:9000"));
+        assert_eq!(book.len(), 2);
+    }
+}
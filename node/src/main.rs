@@ -1,17 +1,33 @@
 mod util;
+mod address_book;
 mod message_handler;
+mod metrics;
+mod miners;
+mod peer;
+mod rpc;
 
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::AtomicU32;
+use std::time::{Duration, Instant};
 use clap::Parser;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use dashmap::DashMap;
 use env_logger::Env;
 use static_init::dynamic;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{Mutex, RwLock};
-use btclib::types::Blockchain;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, watch, Notify, RwLock};
+use tokio::task::JoinSet;
+use btclib::network::{Inventory, NodeInfo};
+use btclib::crypto::{Hash, PublicKey};
+use btclib::types::{Blockchain, ChainEvent, CompactBlock, Transaction};
 use log::{info, warn};
+use uuid::Uuid;
+use btclib::util::Saveable;
+use address_book::AddressBook;
+use miners::Miners;
+use peer::{Peer, Transport};
 
 
 #[derive(Parser)]
@@ -19,17 +35,107 @@ use log::{info, warn};
 struct Cli {
     #[arg(short, long, default_value_t = 9000)]
     port: u16,
+    /// Address other nodes should use to reach this one, defaults to localhost and the listening port
+    #[arg(short, long)]
+    external_address: Option<String>,
     #[arg(short = 'f', long)]
     blockchain_file: String,
+    /// Where to keep the addresses of other nodes, defaults to the blockchain file with a '.peers' suffix
+    #[arg(long)]
+    peers_file: Option<String>,
+    /// Number of connections to other nodes we try to keep open
+    #[arg(long, default_value_t = 8)]
+    outbound: usize,
+    /// Encrypt connections to other nodes and refuse plaintext callers. Leave off for local development
+    #[arg(long)]
+    encrypt: bool,
+    /// Key identifying this node in encryption handshakes, created if missing along with a '.pub.pem'
+    /// copy of its public key for miners and wallets. Defaults to the blockchain file with a '.key' suffix
+    #[arg(long)]
+    identity_file: Option<String>,
+    /// Index transactions by hash, so looking them up does not scan the whole chain
+    #[arg(long)]
+    txindex: bool,
+    /// Index the history of every public key, for wallets to list their transactions
+    #[arg(long)]
+    addrindex: bool,
+    /// Serve JSON-RPC over HTTP on this port
+    #[arg(long)]
+    rpc_port: Option<u16>,
+    /// Interface the JSON-RPC server binds to. Anyone holding the token can stop the node, think twice
+    /// before exposing it beyond localhost
+    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    rpc_bind: IpAddr,
+    /// Token JSON-RPC clients send as 'Authorization: Bearer <token>'. Without it, a random token
+    /// is written to the blockchain file with a '.rpc-token' suffix
+    #[arg(long)]
+    rpc_token: Option<String>,
+    /// Serve Prometheus metrics at /metrics on this port
+    #[arg(long)]
+    metrics_port: Option<u16>,
+    /// Interface the metrics server binds to, no token is asked for
+    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    metrics_bind: IpAddr,
+    /// Public key of a miner allowed to submit blocks, repeat for more. Anyone may if none is given
+    #[arg(long)]
+    allow_miner: Vec<String>,
     #[arg()]
     nodes: Vec<String>
 }
 
+pub static NODE_INFO: OnceLock<NodeInfo> = OnceLock::new();  // How we introduce ourselves, set once at startup
+
+pub static TRANSPORT: OnceLock<Transport> = OnceLock::new();  // Identity key and encryption policy, set once at startup
+
+pub static EXTRA_NONCES: AtomicU32 = AtomicU32::new(0);  // Upper halves of the extra nonces handed out with templates
+
+/// Events kept for a subscriber that falls behind, older ones are skipped
+const EVENT_BUFFER: usize = 1024;
+/// How long connections still being served may take to finish when shutting down
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[dynamic]
 pub static BLOCKCHAIN: RwLock<Blockchain> = RwLock::new(Blockchain::new());  // RwLock for sync
 
 #[dynamic]
-pub static NODES: DashMap<String, Arc<Mutex<TcpStream>>> = DashMap::new();  // Immutable map of address and stream
+pub static NODES: DashMap<String, Arc<Peer>> = DashMap::new();  // Immutable map of address and peer connection
+
+#[dynamic]
+pub static NODE_IDS: DashMap<u64, Arc<Peer>> = DashMap::new();  // Registered peer of every node id, so one node is only kept once
+
+#[dynamic]
+pub static ADDRESS_BOOK: RwLock<AddressBook> = RwLock::new(AddressBook::new());  // Every node address we heard of
+
+#[dynamic]
+pub static REQUESTED: DashMap<Inventory, Instant> = DashMap::new();  // Items asked for with GetData and not received yet
+
+#[dynamic]
+pub static PARTIAL_BLOCKS: DashMap<Hash, (CompactBlock, Vec<Option<Transaction>>, Instant)> = DashMap::new();  // Compact blocks waiting for missing transactions
+
+#[dynamic]
+pub static MINERS: RwLock<Miners> = RwLock::new(Miners::new());  // Who may submit blocks, and how their submissions went
+
+#[dynamic]
+pub static SYNC: Notify = Notify::new();  // Wakes up the sync task ahead of schedule
+
+#[dynamic]
+pub static EVENTS: broadcast::Sender<ChainEvent> = broadcast::channel(EVENT_BUFFER).0;  // Chain and mempool events for subscribers
+
+#[dynamic]
+pub static SHUTDOWN: Notify = Notify::new();  // Asks the node to save and exit, from a signal or over JSON-RPC
+
+#[dynamic]
+pub static STOPPING: watch::Sender<bool> = watch::channel(false).0;  // Set once shutting down, connection handlers stop reading
+
+/// Our advertised address and id
+pub fn node_info() -> &'static NodeInfo {
+    NODE_INFO.get().expect("Node info is set at startup")
+}
+
+/// How we secure our connections
+pub fn transport() -> &'static Transport {
+    TRANSPORT.get().expect("Transport is set at startup")
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -40,6 +146,8 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
     let port = cli.port;
     let blockchain_file = cli.blockchain_file;
+    let peers_file = cli.peers_file.unwrap_or_else(|| format!("{}.peers", blockchain_file));
+    let identity_file = cli.identity_file.unwrap_or_else(|| format!("{}.key", blockchain_file));
     let nodes = cli.nodes;
 
     // Start the listener
@@ -47,9 +155,34 @@ async fn main() -> Result<()> {
     let listener = TcpListener::bind(&bind_addr).await?;
     info!("👂 Listening on {}", bind_addr);
 
-    // Node discovery
-    let node_addr = format!("localhost:{}", port);
-    util::populate_connections(&node_addr, &nodes).await?;
+    let node_addr = cli.external_address.unwrap_or_else(|| format!("localhost:{}", port));
+    info!("📢 Advertising ourselves as [{}]", node_addr);
+    NODE_INFO.set(NodeInfo { address: node_addr, id: Uuid::new_v4().as_u64_pair().0 }).expect("Node info set twice");
+    let identity = util::load_identity(&identity_file)?;
+    if cli.encrypt {
+        info!("🔒 Encrypted connections only");
+    } else {
+        warn!("🔓 Dialing other nodes in plaintext, use --encrypt outside of local development");
+    }
+    if TRANSPORT.set(Transport::new(identity, cli.encrypt)).is_err() {
+        panic!("Transport set twice");
+    }
+
+    for file in &cli.allow_miner {
+        let miner = PublicKey::load_from_file(file).with_context(|| format!("reading miner key '{}'", file))?;
+        MINERS.write().await.allow([miner]);
+    }
+    if MINERS.read().await.is_restricted() {
+        info!("⛏️ Blocks accepted from {} allowed miners only", cli.allow_miner.len());
+    }
+
+    // Load the addresses we knew last time
+    if Path::new(&peers_file).exists() {
+        match util::load_address_book(&peers_file) {
+            Ok(address_book) => *ADDRESS_BOOK.write().await = address_book,
+            Err(e) => warn!("⚠️ Failed to load address book '{}': {}", peers_file, e),
+        }
+    }
 
     // Check if the blockchain_file exists
     if Path::new(&blockchain_file).exists() {
@@ -57,27 +190,94 @@ async fn main() -> Result<()> {
         util::load_blockchain(&blockchain_file).await?;
     } else {
         warn!("❌  Blockchain file '{}' does not exist", blockchain_file);
-        if nodes.is_empty() {
+        if nodes.is_empty() && ADDRESS_BOOK.read().await.is_empty() {
             info!("No nodes provided, starting as a seed node");
-        } else {
-            let (longest_node, longest_count) = util::find_longest_chain_node().await?;
-            // Request the blockchain from the node with the longest blockchain
-            util::download_blockchain(&longest_node, longest_count).await?;
-            info!("↪️ Blockchain downloaded from [{}]", longest_node);
-            {
-                let mut blockchain = BLOCKCHAIN.write().await;
-                blockchain.rebuild_utxos();     // Recalculate utxos
-                blockchain.try_adjust_target(); // Adjust difficulty
-            }
         }
     }
 
-    // Start a task to periodically clean up the mempool
-    tokio::spawn(util::mempool_cleanup());
-    // and a task to periodically save the blockchain
-    tokio::spawn(util::save(blockchain_file.clone()));
+    if cli.txindex {
+        BLOCKCHAIN.write().await.enable_tx_index();
+        info!("🗂️ Transaction index enabled");
+    }
+    if cli.addrindex {
+        BLOCKCHAIN.write().await.enable_address_index();
+        info!("🗂️ Address index enabled");
+    }
+    BLOCKCHAIN.write().await.enable_events();
+
+    // Ctrl-C and SIGTERM shut down cleanly instead of killing us mid-save
+    tokio::spawn(util::wait_for_signals());
+    // Serve other nodes while we dial out, they may well be dialing us too
+    let mut accepting = tokio::spawn(accept_connections(listener));
+    if let Some(rpc_port) = cli.rpc_port {
+        let token = util::rpc_token(cli.rpc_token, &format!("{}.rpc-token", blockchain_file))?;
+        tokio::spawn(rpc::serve(SocketAddr::new(cli.rpc_bind, rpc_port), token));
+    }
+    if let Some(metrics_port) = cli.metrics_port {
+        tokio::spawn(metrics::serve(SocketAddr::new(cli.metrics_bind, metrics_port)));
+    }
+
+    // Node discovery
+    util::populate_connections(&nodes, cli.outbound).await;
+    // Fetch whatever we missed while offline from the node with the longest blockchain
+    if let Err(e) = util::catch_up().await {
+        warn!("⚠️ Initial sync failed: {}", e);
+    }
+
+    let background = [
+        // Start a task to periodically clean up the mempool
+        tokio::spawn(util::mempool_cleanup()),
+        // a task to periodically save the blockchain
+        tokio::spawn(util::save(blockchain_file.clone())),
+        // a task to keep up with the network
+        tokio::spawn(util::sync()),
+        // and a task to keep enough peers around
+        tokio::spawn(util::maintain_connections(peers_file.clone(), cli.outbound)),
+    ];
+    tokio::select! {
+        result = &mut accepting => return result?,
+        _ = SHUTDOWN.notified() => {}
+    }
+
+    info!("🛑 Shutting down");
+    STOPPING.send_replace(true);
+    // No more periodic saves, syncing or dialing out. None of them can be stopped halfway through a save,
+    // tasks are only aborted while waiting
+    for task in background {
+        task.abort();
+    }
+    util::disconnect_peers().await;
+    // Wait for the connections still being served
+    accepting.await??;
+    info!("💾 Saving blockchain to disk before stopping");
+    util::save_blockchain(&blockchain_file).await?;
+    ADDRESS_BOOK.read().await.save_to_file(&peers_file)?;
+    info!("👋 Bye");
+    Ok(())
+}
+
+/// Serve callers until shutting down, then give the connections being served some time to finish
+async fn accept_connections(listener: TcpListener) -> Result<()> {
+    let mut handlers = JoinSet::new();
+    let mut stopping = STOPPING.subscribe();
     loop {
-        let (socket, _) = listener.accept().await?;
-        tokio::spawn(message_handler::handle(socket));
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, _) = accepted?;
+                handlers.spawn(message_handler::handle(socket));
+            }
+            // Forget the handlers that are done
+            Some(_) = handlers.join_next(), if !handlers.is_empty() => {}
+            _ = stopping.wait_for(|stopping| *stopping) => break,
+        }
+    }
+    drop(listener);
+    if !handlers.is_empty() {
+        info!("⏳ Waiting for {} connections to finish", handlers.len());
+    }
+    if tokio::time::timeout(DRAIN_TIMEOUT, async { while handlers.join_next().await.is_some() {} }).await.is_err() {
+        warn!("⚠️ {} connections still busy, dropping them", handlers.len());
+        handlers.abort_all();
     }
+    Ok(())
 }
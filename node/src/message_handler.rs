@@ -1,201 +1,523 @@
 use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use anyhow::{anyhow, Result};
 use base64::Engine;
 use base64::engine::general_purpose;
 use chrono::Utc;
-use log::{error, info, warn};
-use uuid::Uuid;
+use log::{debug, error, info, warn};
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::mpsc;
 use btclib::crypto::{Hash, MerkleRoot};
-use btclib::network::Message;
-use btclib::types::{Block, BlockHeader, Transaction, TransactionOutput};
+use btclib::network::{ChainTip, CoinbaseSpec, HistoryPage, Inventory, Message, TransactionInfo, MAX_HISTORY_PAGE};
+use btclib::transport::Connection;
+use btclib::types::{Block, BlockHeader, Blockchain, CoinbaseData, CompactBlock, EventTopic, Transaction};
 use btclib::network::Message::*;
+use crate::address_book::MAX_ADDR_ENTRIES;
+use crate::miners::Submission;
+use crate::peer::Peer;
 
 pub async fn handle(stream: TcpStream) {
-    let stream = Arc::new(Mutex::new(stream));
+    if let Ok(observed) = stream.peer_addr() && crate::ADDRESS_BOOK.read().await.is_banned(&observed.ip().to_string()) {
+        info!("🚫 Refused connection from banned [{}]", observed);
+        return;
+    }
+    let mut connection = match crate::transport().accept(stream).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            warn!("Refused connection: {e}");
+            return;
+        }
+    };
+    let mut stopping = crate::STOPPING.subscribe();
     loop {
-        // Read a message from the socket
-        let mut locked_stream = stream.lock().await;
-        let message = match Message::receive_async(&mut *locked_stream).await {
-            Ok(message) => message,
+        // Read a message from the socket, unless we are shutting down. A message being processed is finished first
+        let message = tokio::select! {
+            message = connection.receive() => match message {
+                Ok(message) => {
+                    crate::metrics::message_received(&message);
+                    message
+                }
+                Err(e) => {
+                    error!("Invalid message from peer: {e}, closing connection");
+                    return;
+                }
+            },
+            _ = stopping.wait_for(|stopping| *stopping) => return,
+        };
+        if let Goodbye = message {
+            return;
+        }
+        // A node introducing itself turns this connection into a peer connection
+        if let Request(id, request) = &message && let DiscoverNodes(dialing_node, current_node) = request.as_ref() {
+            let address = match connection.peer_addr() {
+                Ok(observed) => reachable_address(&dialing_node.address, observed),
+                Err(_) => dialing_node.address.clone(),
+            };
+            info!("📞 [{}] receiving call from [{}]", current_node, address);
+            let reply = Response(*id, Box::new(NodeList(crate::node_info().clone(), known_nodes(&address))));
+            crate::metrics::message_sent(&reply);
+            if let Err(e) = connection.send(&reply).await {
+                error!("Failed to reply to peer: {e}, closing connection");
+                return;
+            }
+            let peer = Peer::spawn(address.clone(), connection, true);
+            peer.set_node_id(dialing_node.id);
+            if crate::util::register_peer(peer).await {
+                crate::ADDRESS_BOOK.write().await.mark_seen(&address);
+                info!("🌐 Known network nodes: [{}]", crate::NODES.len());
+            }
+            return;
+        }
+        // So does a subscriber, which only listens from now on
+        if let Subscribe(topics) = message {
+            stream_events(connection, topics).await;
+            return;
+        }
+        if let SubscribeTemplates(miner) = message {
+            stream_templates(connection, miner).await;
+            return;
+        }
+        match respond(message, None).await {
+            Ok(Some(reply)) => {
+                crate::metrics::message_sent(&reply);
+                if let Err(e) = connection.send(&reply).await {
+                    error!("Failed to reply to peer: {e}, closing connection");
+                    return;
+                }
+            }
+            Ok(None) => {}
             Err(e) => {
-                error!("Invalid message from peer: {e}, closing connection");
+                error!("{e}, closing connection");
                 return;
             }
+        }
+    }
+}
+
+/// Process a message and build the reply, if any. Replies to a `Request` carry the same id.
+/// `peer` is set when the message comes from another node rather than a miner or a wallet
+pub async fn respond(message: Message, peer: Option<&Arc<Peer>>) -> Result<Option<Message>> {
+    match message {
+        Request(id, message) => {
+            let reply = process(*message, peer).await?;
+            Ok(reply.map(|reply| Response(id, Box::new(reply))))
+        }
+        message => process(message, peer).await,
+    }
+}
+
+/// Push the events about the given topics until the subscriber hangs up
+async fn stream_events(connection: Connection, topics: Vec<EventTopic>) {
+    let address = connection.peer_addr().map(|address| address.to_string()).unwrap_or_default();
+    info!("📡 [{}] subscribed to {:?}", address, topics);
+    let mut events = crate::EVENTS.subscribe();
+    let mut stopping = crate::STOPPING.subscribe();
+    let (mut reader, mut writer) = connection.into_split();
+    // Subscribers have nothing more to say, reading only tells when they are gone
+    let mut hung_up = tokio::spawn(async move { while reader.receive().await.is_ok() {} });
+    loop {
+        let event = tokio::select! {
+            _ = &mut hung_up => break,
+            _ = stopping.wait_for(|stopping| *stopping) => break,
+            event = events.recv() => event,
         };
-        match message {
-            AskDifference(height) => {
-                let blockchain = crate::BLOCKCHAIN.read().await;
-                let count = blockchain.block_height() as i32 - height as i32;
-                let message = Difference(count);
-                message.send_async(&mut *locked_stream).await.unwrap();
-            }
-            DiscoverNodes(dialing_node, current_node) => {
-                // Here, the responding node is the dialed one
-                info!("📞 [{}] receiving call from [{}]", current_node, dialing_node);
-                match TcpStream::connect(&dialing_node).await {
-                    Ok(s) => {
-                        let stream = Arc::new(Mutex::new(s));
-                        crate::NODES.insert(dialing_node.clone(), stream);
-                        info!("➕  Added node [{}]", dialing_node);
-                        info!("🌐 Known network nodes: [{}]", crate::NODES.len());
-                    },
-                    Err(e) => {
-                        error!("⚠️ Failed to connect to {}: {}", dialing_node, e);
-                        return;
-                    }
+        match event {
+            Ok(event) => {
+                if !topics.is_empty() && !topics.contains(&event.topic()) {
+                    continue;
+                }
+                let event = Event(event);
+                crate::metrics::message_sent(&event);
+                if let Err(e) = writer.send(&event).await {
+                    warn!("Failed to send event to [{}]: {}", address, e);
+                    break;
                 }
-                let nodes: HashSet<String> = crate::NODES.iter().map(|x| x.key().clone()).collect();
-                let message = NodeList(nodes);
-                message.send_async(&mut *locked_stream).await.unwrap();
             }
-            FetchBlock(height) => {
-                let blockchain = crate::BLOCKCHAIN.read().await;
-                let Some(block) = blockchain.blocks().nth(height).cloned() else {
-                    return;
-                };
-                let message = NewBlock(block);
-                message.send_async(&mut *locked_stream).await.unwrap();
+            Err(RecvError::Lagged(skipped)) => warn!("📡 [{}] fell behind, skipped {} events", address, skipped),
+            Err(RecvError::Closed) => break,
+        }
+    }
+    hung_up.abort();
+    info!("📡 [{}] unsubscribed", address);
+}
+
+/// Push a template to a miner now and again whenever the tip or the mempool changes, so it never
+/// works on a stale one. Whatever else the miner sends, its blocks above all, is answered as usual
+async fn stream_templates(connection: Connection, miner: CoinbaseSpec) {
+    let address = connection.peer_addr().map(|address| address.to_string()).unwrap_or_default();
+    info!("⛏️ [{}] subscribed to templates", address);
+    let mut events = crate::EVENTS.subscribe();
+    let mut stopping = crate::STOPPING.subscribe();
+    let (mut reader, mut writer) = connection.into_split();
+    // Reading happens in a task of its own so pushing a template never cuts a message in half
+    let (incoming_sender, mut incoming) = mpsc::unbounded_channel();
+    let reading = tokio::spawn(async move {
+        while let Ok(message) = reader.receive().await {
+            crate::metrics::message_received(&message);
+            if incoming_sender.send(message).is_err() {
+                break;
             }
-            FetchTemplate(pubkey) => {
-                let blockchain = crate::BLOCKCHAIN.read().await;
-                let mut transactions = vec![];
-                // Insert transactions from mempool
-                transactions.extend(
-                    blockchain
-                        .mempool()
-                        .iter()
-                        .take(btclib::BLOCK_TRANSACTION_CAP)
-                        .map(|(_, tx)| tx)
-                        .cloned()
-                        .collect::<Vec<_>>(),
-                );
-                // Insert coinbase tx with a pubkey
-                transactions.insert(0, Transaction {
-                    inputs: vec![],
-                    outputs: vec![TransactionOutput {
-                        public_key: pubkey,
-                        unique_id: Uuid::new_v4(),
-                        value: 0,
-                    }]
-                });
-                let merkle_root = MerkleRoot::calculate(&transactions);
-                let mut block = Block::new(
-                    BlockHeader {
-                        timestamp: Utc::now(),
-                        prev_block_hash: blockchain.blocks().last().map(|last_block| {
-                            last_block.hash()
-                        }).unwrap_or(Hash::zero()),
-                        nonce: 0,
-                        target: blockchain.target(),
-                        merkle_root
-                    },
-                    transactions
-                );
-                let miner_fees = match block.calculate_miner_fees(blockchain.utxos()) {
-                    Ok(fees) => fees,
-                    Err(e) => {
-                        error!("{e}");
-                        return;
+        }
+    });
+    let mut stale = true;
+    loop {
+        if stale {
+            stale = false;
+            let template = template(&*crate::BLOCKCHAIN.read().await, &miner);
+            let template = match template {
+                Ok(template) => Template(template),
+                Err(e) => {
+                    error!("Failed to build a template for [{}]: {}", address, e);
+                    break;
+                }
+            };
+            crate::metrics::message_sent(&template);
+            if let Err(e) = writer.send(&template).await {
+                warn!("Failed to send template to [{}]: {}", address, e);
+                break;
+            }
+        }
+        let message = tokio::select! {
+            message = incoming.recv() => match message {
+                None | Some(Goodbye) => break,
+                Some(message) => message,
+            },
+            event = events.recv() => {
+                match event {
+                    Ok(_) | Err(RecvError::Lagged(_)) => {
+                        // One block brings a burst of events, a single template covers them all
+                        while !matches!(events.try_recv(), Err(TryRecvError::Empty | TryRecvError::Closed)) {}
+                        stale = true;
                     }
-                };
-                let reward =  blockchain.calculate_block_reward();
-                // Update coinbase tx with reward
-                block.transactions[0].outputs[0].value = reward + miner_fees;
-                // Recalculate merkle root
-                block.header.merkle_root = MerkleRoot::calculate(&block.transactions);
-                let message = Template(block);
-                message.send_async(&mut *locked_stream).await.unwrap();
-            }
-            FetchUTXOs(key) => {
-                println!("Received request to fetch UTXOs");
-                let blockchain = crate::BLOCKCHAIN.read().await;
-                let utxos = blockchain.utxos().iter().filter(|(_, (_, tx_out))| {
-                    tx_out.public_key == key
-                }).map(|(_, (marked, tx_out))| {
-                    (tx_out.clone(), *marked)
-                }).collect::<Vec<_>>();
-                let message = UTXOs(utxos);
-                message.send_async(&mut *locked_stream).await.unwrap();
-            }
-            NewBlock(block) => {
-                let mut blockchain = crate::BLOCKCHAIN.write().await;
-                info!("📦 Received new block");
-                if blockchain.add_block(block).is_err() {
-                    error!("❌  Block rejected");
+                    Err(RecvError::Closed) => break,
                 }
+                continue;
             }
-            NewTransaction(tx) => {
-                let mut blockchain = crate::BLOCKCHAIN.write().await;
-                println!("Received transaction from friend");
-                if blockchain.add_to_mempool(tx).is_err() {
-                    error!("❌ Transaction rejected, closing connection");
-                    return;
+            _ = stopping.wait_for(|stopping| *stopping) => break,
+        };
+        match respond(message, None).await {
+            Ok(Some(reply)) => {
+                crate::metrics::message_sent(&reply);
+                if let Err(e) = writer.send(&reply).await {
+                    warn!("Failed to reply to [{}]: {}", address, e);
+                    break;
                 }
             }
-            SubmitTemplate(block, miner) => {
-                let encoded_point = miner.0.to_encoded_point(true);
-                let miner_id = general_purpose::STANDARD.encode(encoded_point.as_bytes());
-                info!("Received allegedly mined template from: 👷{}", miner_id);
-                let mut blockchain = crate::BLOCKCHAIN.write().await;
-                if let Err(e) = blockchain.add_block(block.clone()) {
-                    error!("❌  Block rejected: {e}, closing connection");
-                    return;
+            Ok(None) => {}
+            // A block found on a template that went stale on its way is no reason to hang up
+            Err(e) => warn!("⛏️ [{}] {}", address, e),
+        }
+    }
+    reading.abort();
+    info!("⛏️ [{}] unsubscribed from templates", address);
+}
+
+/// The best block we can think of with the coinbase `miner` asked for, with as many mempool
+/// transactions as fit
+fn template(blockchain: &Blockchain, miner: &CoinbaseSpec) -> Result<Block> {
+    let mut transactions = vec![];
+    // Insert transactions from mempool
+    transactions.extend(
+        blockchain
+            .mempool()
+            .iter()
+            .take(btclib::BLOCK_TRANSACTION_CAP)
+            .map(|(_, tx)| tx)
+            .cloned()
+            .collect::<Vec<_>>(),
+    );
+    // Insert the coinbase, paying nothing until the fees are known
+    let outputs = miner.outputs(0).ok_or_else(|| anyhow!("Invalid coinbase: no payee or too much data"))?;
+    // Miners count up the lower half of the extra nonce, the upper half tells templates apart
+    let extra_nonce = (crate::EXTRA_NONCES.fetch_add(1, Ordering::Relaxed) as u64) << 32;
+    transactions.insert(0, Transaction::coinbase(outputs, CoinbaseData { extra_nonce, data: miner.data.clone() }));
+    let merkle_root = MerkleRoot::calculate(&transactions);
+    let mut block = Block::new(
+        BlockHeader {
+            timestamp: Utc::now(),
+            prev_block_hash: blockchain.blocks().last().map(|last_block| {
+                last_block.hash()
+            }).unwrap_or(Hash::zero()),
+            nonce: 0,
+            target: blockchain.target(),
+            merkle_root
+        },
+        transactions
+    );
+    let miner_fees = block.calculate_miner_fees(blockchain.utxos())?;
+    let reward =  blockchain.calculate_block_reward();
+    // Update coinbase tx with reward
+    block.transactions[0].outputs = miner.outputs(reward + miner_fees).expect("BUG: checked above");
+    // Recalculate merkle root
+    block.header.merkle_root = MerkleRoot::calculate(&block.transactions);
+    Ok(block)
+}
+
+/// Address to dial a node that called us. That is the address it advertises, unless the advertised
+/// host only makes sense on its own machine, then the host it called us from is used instead
+fn reachable_address(advertised: &str, observed: SocketAddr) -> String {
+    let Some((host, port)) = advertised.rsplit_once(':') else {
+        return advertised.to_string();
+    };
+    let Ok(port) = port.parse::<u16>() else {
+        return advertised.to_string();
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let local_only = host == "localhost" || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback() || ip.is_unspecified());
+    if local_only && !observed.ip().is_loopback() {
+        SocketAddr::new(observed.ip(), port).to_string()
+    } else {
+        advertised.to_string()
+    }
+}
+
+/// Hand a block over to the blockchain and relay whatever got connected to the rest of the network
+async fn receive_block(block: Block, peer: Option<&Arc<Peer>>) {
+    let item = Inventory::Block(block.hash());
+    crate::REQUESTED.remove(&item);
+    if let Some(peer) = peer {
+        peer.mark_known(item);
+    }
+    match crate::util::accept_block(block).await {
+        Ok(connected) => crate::util::announce(&connected.into_iter().map(Inventory::Block).collect::<Vec<_>>()),
+        Err(e) => error!("❌  Block rejected: {e}"),
+    }
+}
+
+/// Put a compact block together, falling back to the full block if it does not match its header
+async fn complete_compact_block(compact: CompactBlock, slots: Vec<Option<Transaction>>, peer: &Arc<Peer>) -> Result<()> {
+    match compact.complete(slots) {
+        Ok(block) => {
+            info!("🧱 Rebuilt block {} from its compact form", compact.hash);
+            receive_block(block, Some(peer)).await
+        }
+        Err(e) => {
+            warn!("Could not rebuild compact block {}: {}, fetching it in full", compact.hash, e);
+            peer.send(GetData(vec![Inventory::Block(compact.hash)]))?;
+        }
+    }
+    Ok(())
+}
+
+/// Addresses of all the nodes we know, except the one asking
+fn known_nodes(asking_node: &str) -> HashSet<String> {
+    crate::NODES.iter().map(|x| x.key().clone()).filter(|node| node != asking_node).collect()
+}
+
+async fn process(message: Message, peer: Option<&Arc<Peer>>) -> Result<Option<Message>> {
+    match message {
+        AskTip(theirs) => {
+            let ours = ChainTip::of(&*crate::BLOCKCHAIN.read().await);
+            // The asking node being ahead of us is a good hint that we should sync
+            if theirs.work > ours.work {
+                crate::SYNC.notify_one();
+            }
+            Ok(Some(Tip(ours)))
+        }
+        FindFork(locator) => {
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            Ok(Some(ForkPoint(blockchain.fork_point(&locator))))
+        }
+        DiscoverNodes(dialing_node, _) => {
+            Ok(Some(NodeList(crate::node_info().clone(), known_nodes(&dialing_node.address))))
+        }
+        FetchBlock(height) => {
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            let block = blockchain.block_at(height).cloned().ok_or_else(|| {
+                anyhow!("No block at height {}", height)
+            })?;
+            Ok(Some(NewBlock(block)))
+        }
+        GetAddr => {
+            let mut addresses = crate::ADDRESS_BOOK.read().await.recently_seen(MAX_ADDR_ENTRIES);
+            for node in crate::NODES.iter() {
+                if addresses.len() >= MAX_ADDR_ENTRIES {
+                    break;
                 }
-                blockchain.rebuild_utxos();
-                info!("Block looks good, broadcasting📡️");
-                // Send block to all friend nodes
-                let nodes = crate::NODES.iter().map(|x| x.key().clone()).collect::<Vec<_>>();
-                for node in nodes {
-                    if let Some(stream) = crate::NODES.get_mut(&node) {
-                        let message = NewBlock(block.clone());
-                        let mut locked_stream = stream.lock().await;
-                        if message.send_async(&mut *locked_stream).await.is_err() {
-                            error!("⚠️ Failed to send block to {}", node);
-                        }
-                    }
+                if !addresses.contains(node.key()) {
+                    addresses.push(node.key().clone());
                 }
             }
-            SubmitTransaction(tx) => {
-                println!("Submit tx");
-                let mut blockchain = crate::BLOCKCHAIN.write().await;
-                if let Err(e) = blockchain.add_to_mempool(tx.clone()) {
-                    error!("❌ Transaction rejected, closing connection: {e}");
-                    return;
+            Ok(Some(Addr(addresses)))
+        }
+        Addr(addresses) => {
+            let mut address_book = crate::ADDRESS_BOOK.write().await;
+            for address in addresses.iter().take(MAX_ADDR_ENTRIES) {
+                address_book.add(address);
+            }
+            Ok(None)
+        }
+        GetData(items) => {
+            let peer = peer.ok_or_else(|| anyhow!("GetData is only served to nodes"))?;
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            for item in items {
+                let message = match item {
+                    Inventory::Block(hash) => blockchain.block_by_hash(&hash).cloned().map(NewBlock),
+                    Inventory::CompactBlock(hash) => blockchain.block_by_hash(&hash).map(|block| NewCompactBlock(CompactBlock::new(block))),
+                    Inventory::Transaction(hash) => blockchain.mempool_transaction(&hash).cloned().map(NewTransaction),
+                };
+                match message {
+                    Some(message) => peer.send(message)?,
+                    None => warn!("Asked for unknown {:?} by [{}]", item, peer.address()),
                 }
-                info!("🗃️ Added transaction to mempool");
-                // Send transaction to all friend nodes
-                let nodes = crate::NODES
-                    .iter()
-                    .map(|x| x.key().clone())
-                    .collect::<Vec<_>>();
-                for node in nodes {
-                    info!("Sending to friend: [{node}]");
-                    if let Some(stream) = crate::NODES.get_mut(&node) {
-                        let message = NewTransaction(tx.clone());
-                        let mut locked_stream = stream.lock().await;
-                        if message.send_async(&mut *locked_stream).await.is_err() {
-                            error!("⚠️ Failed to send transaction to {}", node);
-                        }
+            }
+            Ok(None)
+        }
+        Inv(items) => {
+            let peer = peer.ok_or_else(|| anyhow!("Inv is only accepted from nodes"))?;
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            let missing = items.into_iter().filter(|item| {
+                peer.mark_known(*item);
+                let known = match item {
+                    Inventory::Block(hash) | Inventory::CompactBlock(hash) => {
+                        blockchain.contains_block(hash) || blockchain.contains_orphan(hash)
                     }
+                    Inventory::Transaction(hash) => blockchain.mempool_transaction(hash).is_some(),
+                };
+                // Only one peer at a time is asked for the same item
+                !known && crate::util::mark_requested(*item)
+            }).map(|item| match item {
+                // Blocks come compact, we most likely have their transactions already
+                Inventory::Block(hash) => Inventory::CompactBlock(hash),
+                item => item,
+            }).collect::<Vec<_>>();
+            if !missing.is_empty() {
+                peer.send(GetData(missing))?;
+            }
+            Ok(None)
+        }
+        FetchTemplate(miner) => {
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            Ok(Some(Template(template(&blockchain, &miner)?)))
+        }
+        FetchUTXOs(key) => {
+            debug!("Received request to fetch UTXOs");
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            Ok(Some(UTXOs(blockchain.utxos_of(&key))))
+        }
+        FetchHistory(key, offset, limit) => {
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            let page = blockchain.history(&key, offset as usize, limit.min(MAX_HISTORY_PAGE) as usize).map(|(entries, total)| {
+                HistoryPage { entries, total: total as u64, height: blockchain.block_height() }
+            });
+            Ok(Some(History(page)))
+        }
+        GetTransaction(hash) => {
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            Ok(Some(FoundTransaction(TransactionInfo::find(&blockchain, &hash))))
+        }
+        NewBlock(block) => {
+            info!("📦 Received new block");
+            receive_block(block, peer).await;
+            Ok(None)
+        }
+        NewCompactBlock(compact) => {
+            let peer = peer.ok_or_else(|| anyhow!("Compact blocks are only accepted from nodes"))?;
+            info!("📦 Received compact block with {} transactions", compact.short_ids.len());
+            peer.mark_known(Inventory::Block(compact.hash));
+            let slots = {
+                let blockchain = crate::BLOCKCHAIN.read().await;
+                if blockchain.contains_block(&compact.hash) || blockchain.contains_orphan(&compact.hash) {
+                    crate::REQUESTED.remove(&Inventory::Block(compact.hash));
+                    return Ok(None);
                 }
-                info!("💰 Transaction sent to friends");
+                compact.reconstruct(blockchain.mempool().iter().map(|(_, tx)| tx))
+            };
+            let missing = CompactBlock::missing(&slots);
+            if missing.is_empty() {
+                complete_compact_block(compact, slots, peer).await?;
+            } else {
+                info!("Fetching {} missing transactions of block {}", missing.len(), compact.hash);
+                let hash = compact.hash;
+                crate::util::add_partial_block(compact, slots);
+                peer.send(GetBlockTransactions(hash, missing))?;
             }
-            UTXOs(_) | Template(_) | Difference(_) | TemplateValidity(_) | NodeList(_) => {
-                warn!("👋 I am neither a miner nor a wallet! Goodbye");
-                return;
+            Ok(None)
+        }
+        GetBlockTransactions(hash, indexes) => {
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            let block = blockchain.block_by_hash(&hash).ok_or_else(|| anyhow!("No block {}", hash))?;
+            let transactions = indexes.iter().map(|index| {
+                block.transactions.get(*index as usize).cloned().ok_or_else(|| {
+                    anyhow!("No transaction {} in block {}", index, hash)
+                })
+            }).collect::<Result<Vec<_>>>()?;
+            Ok(Some(BlockTransactions(hash, transactions)))
+        }
+        BlockTransactions(hash, transactions) => {
+            let peer = peer.ok_or_else(|| anyhow!("BlockTransactions is only accepted from nodes"))?;
+            let Some((_, (compact, mut slots, _))) = crate::PARTIAL_BLOCKS.remove(&hash) else {
+                warn!("Unexpected transactions for block {} from [{}]", hash, peer.address());
+                return Ok(None);
+            };
+            let mut transactions = transactions.into_iter();
+            for slot in slots.iter_mut().filter(|slot| slot.is_none()) {
+                *slot = transactions.next();
             }
-            ValidateTemplate(block_template) => {
-                let blockchain = crate::BLOCKCHAIN.read().await;
-                let status = block_template.header.prev_block_hash == blockchain
-                    .blocks()
-                    .last()
-                    .map(|last_block| last_block.hash())
-                    .unwrap_or(Hash::zero());
-                let message = TemplateValidity(status);
-                message.send_async(&mut *locked_stream).await.unwrap();
+            complete_compact_block(compact, slots, peer).await?;
+            Ok(None)
+        }
+        NewTransaction(tx) => {
+            let hash = tx.hash();
+            debug!("Received transaction {} from friend", hash);
+            crate::REQUESTED.remove(&Inventory::Transaction(hash));
+            if let Some(peer) = peer {
+                peer.mark_known(Inventory::Transaction(hash));
             }
+            if crate::util::accept_transaction(tx).await? {
+                crate::util::announce(&[Inventory::Transaction(hash)]);
+            }
+            Ok(None)
+        }
+        SubmitTemplate(block, miner, signature) => {
+            let miner_id = general_purpose::STANDARD.encode(miner.to_bytes());
+            crate::MINERS.read().await.check(&block, &miner, &signature)
+                .map_err(|e| anyhow!("❌  Block from 👷{} refused: {e}", miner_id))?;
+            info!("Received mined template from: 👷{}", miner_id);
+            // Whatever else is wrong with it, a block on an old tip was stale work
+            let stale = block.header.prev_block_hash != crate::BLOCKCHAIN.read().await.tip_hash();
+            let accepted = crate::util::accept_block(block).await;
+            let submission = match (&accepted, stale) {
+                (_, true) => Submission::Stale,
+                (Ok(_), false) => Submission::Found,
+                (Err(_), false) => Submission::Rejected,
+            };
+            crate::MINERS.write().await.record(&miner, submission);
+            let connected = accepted.map_err(|e| anyhow!("❌  Block rejected: {e}"))?;
+            info!("Block looks good, announcing📡️");
+            crate::util::announce(&connected.into_iter().map(Inventory::Block).collect::<Vec<_>>());
+            Ok(None)
+        }
+        SubmitTransaction(tx) => {
+            let hash = tx.hash();
+            info!("📝 Transaction {} submitted", hash);
+            if crate::util::accept_transaction(tx).await? {
+                info!("🗃️ Added transaction to mempool");
+                crate::util::announce(&[Inventory::Transaction(hash)]);
+                info!("💰 Transaction announced to friends");
+            }
+            Ok(None)
+        }
+        Goodbye => Ok(None),
+        Subscribe(_) | SubscribeTemplates(_) => {
+            warn!("📡 Subscriptions need a connection of their own");
+            Err(anyhow!("Unexpected subscription"))
+        }
+        UTXOs(_) | Template(_) | Tip(_) | ForkPoint(_) | TemplateValidity(_) | NodeList(..) | FoundTransaction(_) | History(_) | Event(_) | Request(..) | Response(..) => {
+            warn!("👋 I am neither a miner nor a wallet! Goodbye");
+            Err(anyhow!("Unexpected message"))
+        }
+        ValidateTemplate(block_template) => {
+            let blockchain = crate::BLOCKCHAIN.read().await;
+            let status = block_template.header.prev_block_hash == blockchain
+                .blocks()
+                .last()
+                .map(|last_block| last_block.hash())
+                .unwrap_or(Hash::zero());
+            Ok(Some(TemplateValidity(status)))
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,227 @@
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use anyhow::Result;
+use axum::Router;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use dashmap::DashMap;
+use log::info;
+use static_init::dynamic;
+use tokio::net::TcpListener;
+use btclib::error::BtcError;
+use btclib::network::Message;
+use btclib::types::{u256_to_f64, Blockchain};
+
+/// Upper bounds of the block validation latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0];
+
+/// Upper bounds of the mempool fee buckets, in Sats
+const FEE_BUCKETS: [f64; 8] = [0.0, 100.0, 1_000.0, 10_000.0, 100_000.0, 1_000_000.0, 10_000_000.0, 100_000_000.0];
+
+#[dynamic]
+static MESSAGES_RECEIVED: DashMap<&'static str, u64> = DashMap::new();  // By message variant
+
+#[dynamic]
+static MESSAGES_SENT: DashMap<&'static str, u64> = DashMap::new();  // By message variant
+
+#[dynamic]
+static VALIDATION_FAILURES: DashMap<(&'static str, &'static str), u64> = DashMap::new();  // By kind of item and reason
+
+#[dynamic]
+static BLOCK_VALIDATION: Mutex<Histogram> = Mutex::new(Histogram::new(&LATENCY_BUCKETS));
+
+/// Counts of observations below each bound, kept cumulative only when rendered
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram { bounds, counts: vec![0; bounds.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum);
+        let _ = writeln!(out, "{}_count {}", name, self.count);
+    }
+}
+
+pub fn message_received(message: &Message) {
+    *MESSAGES_RECEIVED.entry(message.kind()).or_insert(0) += 1;
+}
+
+pub fn message_sent(message: &Message) {
+    *MESSAGES_SENT.entry(message.kind()).or_insert(0) += 1;
+}
+
+/// Count a block or transaction we refused, `kind` being "block" or "transaction"
+pub fn validation_failed(kind: &'static str, reason: &BtcError) {
+    *VALIDATION_FAILURES.entry((kind, reason_label(reason))).or_insert(0) += 1;
+}
+
+/// A fixed label per error, so the reasons stay few and never carry data from the network
+fn reason_label(reason: &BtcError) -> &'static str {
+    match reason {
+        BtcError::InvalidTransaction => "invalid_transaction",
+        BtcError::InvalidBlock => "invalid_block",
+        BtcError::OrphanBlock => "orphan_block",
+        BtcError::InvalidBlockHeader => "invalid_block_header",
+        BtcError::InvalidTransactionInput => "invalid_transaction_input",
+        BtcError::InvalidTransactionOutput => "invalid_transaction_output",
+        BtcError::InvalidMerkleRoot => "invalid_merkle_root",
+        BtcError::InvalidHash => "invalid_hash",
+        BtcError::InvalidSignature => "invalid_signature",
+        BtcError::InvalidPublicKey => "invalid_public_key",
+        BtcError::InvalidPrivateKey => "invalid_private_key",
+        BtcError::InvalidEncoding(_) => "invalid_encoding",
+    }
+}
+
+/// Escape a label value as the Prometheus text format wants it
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+pub fn block_validated(elapsed: Duration) {
+    BLOCK_VALIDATION.lock().unwrap().observe(elapsed.as_secs_f64());
+}
+
+/// Serve the metrics in the Prometheus text format at `/metrics`
+pub async fn serve(address: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!("📈 Metrics at http://{}/metrics", address);
+    let app = Router::new().route("/metrics", get(metrics));
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn metrics() -> impl IntoResponse {
+    let mut out = String::new();
+    {
+        let blockchain = crate::BLOCKCHAIN.read().await;
+        render_chain(&mut out, &blockchain);
+    }
+    render_peers(&mut out);
+    render_counters(&mut out);
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out)
+}
+
+fn render_chain(out: &mut String, blockchain: &Blockchain) {
+    let _ = writeln!(out, "# HELP btc_chain_height Number of blocks in the chain");
+    let _ = writeln!(out, "# TYPE btc_chain_height gauge");
+    let _ = writeln!(out, "btc_chain_height {}", blockchain.block_height());
+    let _ = writeln!(out, "# HELP btc_target_bits Number of significant bits of the current target");
+    let _ = writeln!(out, "# TYPE btc_target_bits gauge");
+    let _ = writeln!(out, "btc_target_bits {}", blockchain.target().bits());
+    let _ = writeln!(out, "# HELP btc_difficulty How much harder than the easiest target a block currently is");
+    let _ = writeln!(out, "# TYPE btc_difficulty gauge");
+    let _ = writeln!(out, "btc_difficulty {}", u256_to_f64(btclib::MIN_TARGET) / u256_to_f64(blockchain.target()).max(1.0));
+
+    let mut fees = Histogram::new(&FEE_BUCKETS);
+    for (_, transaction) in blockchain.mempool() {
+        let inputs: u64 = transaction.inputs.iter()
+            .filter_map(|input| blockchain.utxos().get(&input.prev_transaction_output_hash))
+            .map(|(_, output)| output.value)
+            .sum();
+        let outputs: u64 = transaction.outputs.iter().map(|output| output.value).sum();
+        fees.observe(inputs.saturating_sub(outputs) as f64);
+    }
+    let _ = writeln!(out, "# HELP btc_mempool_transactions Transactions waiting in the mempool");
+    let _ = writeln!(out, "# TYPE btc_mempool_transactions gauge");
+    let _ = writeln!(out, "btc_mempool_transactions {}", blockchain.mempool().len());
+    let _ = writeln!(out, "# HELP btc_mempool_fee_sats Fees offered by the transactions in the mempool");
+    let _ = writeln!(out, "# TYPE btc_mempool_fee_sats histogram");
+    fees.render(out, "btc_mempool_fee_sats");
+}
+
+fn render_peers(out: &mut String) {
+    let inbound = crate::NODES.iter().filter(|entry| entry.value().is_inbound()).count();
+    let _ = writeln!(out, "# HELP btc_peers Connected nodes");
+    let _ = writeln!(out, "# TYPE btc_peers gauge");
+    let _ = writeln!(out, "btc_peers{{direction=\"inbound\"}} {}", inbound);
+    let _ = writeln!(out, "btc_peers{{direction=\"outbound\"}} {}", crate::NODES.len().saturating_sub(inbound));
+}
+
+fn render_counters(out: &mut String) {
+    for (name, help, counts) in [
+        ("btc_messages_received_total", "Messages received, by variant", &*MESSAGES_RECEIVED),
+        ("btc_messages_sent_total", "Messages sent, by variant", &*MESSAGES_SENT),
+    ] {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for entry in counts.iter() {
+            let _ = writeln!(out, "{}{{type=\"{}\"}} {}", name, escape_label(entry.key()), entry.value());
+        }
+    }
+    let _ = writeln!(out, "# HELP btc_validation_failures_total Blocks and transactions refused, by reason");
+    let _ = writeln!(out, "# TYPE btc_validation_failures_total counter");
+    for entry in VALIDATION_FAILURES.iter() {
+        let (kind, reason) = entry.key();
+        let _ = writeln!(out, "btc_validation_failures_total{{kind=\"{}\",reason=\"{}\"}} {}", escape_label(kind), escape_label(reason), entry.value());
+    }
+    let _ = writeln!(out, "# HELP btc_block_validation_seconds Time spent checking and connecting a block");
+    let _ = writeln!(out, "# TYPE btc_block_validation_seconds histogram");
+    BLOCK_VALIDATION.lock().unwrap().render(out, "btc_block_validation_seconds");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The counters rendered, the value of the sample named `sample` with its labels
+    fn sample(sample: &str) -> u64 {
+        let mut out = String::new();
+        render_counters(&mut out);
+        out.lines()
+            .find_map(|line| line.strip_prefix(sample)?.strip_prefix(' '))
+            .map_or(0, |value| value.parse().unwrap())
+    }
+
+    #[test]
+    fn renders_what_was_recorded() {
+        // The counters are shared by the whole process, so only what this test adds is checked
+        let received = "btc_messages_received_total{type=\"GetAddr\"}";
+        let sent = "btc_messages_sent_total{type=\"Goodbye\"}";
+        let failed = "btc_validation_failures_total{kind=\"block\",reason=\"invalid_merkle_root\"}";
+        let before = [received, sent, failed].map(sample);
+        message_received(&Message::GetAddr);
+        message_received(&Message::GetAddr);
+        message_sent(&Message::Goodbye);
+        validation_failed("block", &BtcError::InvalidMerkleRoot);
+        assert_eq!([received, sent, failed].map(sample), [before[0] + 2, before[1] + 1, before[2] + 1]);
+        let mut out = String::new();
+        render_counters(&mut out);
+        assert!(out.contains("# TYPE btc_validation_failures_total counter\n"));
+    }
+
+    #[test]
+    fn labels_reasons_without_their_data() {
+        assert_eq!(reason_label(&BtcError::InvalidSignature), "invalid_signature");
+        assert_eq!(reason_label(&BtcError::InvalidEncoding("unexpected end of data")), "invalid_encoding");
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape_label("plain"), "plain");
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
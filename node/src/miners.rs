@@ -0,0 +1,115 @@
+use std::collections::{BTreeMap, BTreeSet};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use btclib::crypto::{PublicKey, Signature};
+use btclib::types::Block;
+
+/// Miners tracked at most, so made up keys cannot fill up our memory when anyone may submit
+const MAX_TRACKED_MINERS: usize = 1024;
+
+/// How a submitted block turned out
+#[derive(Clone, Copy, Debug)]
+pub enum Submission {
+    /// Extended the chain
+    Found,
+    /// Built on a block that was no longer the tip
+    Stale,
+    /// Failed validation
+    Rejected,
+}
+
+/// Blocks submitted by a miner since the node started, not saved across restarts
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct MinerStats {
+    pub blocks: u64,
+    pub stale: u64,
+    pub rejected: u64,
+    pub last_submission: Option<DateTime<Utc>>,
+}
+
+/// Who may submit blocks, and how their submissions went
+pub struct Miners {
+    /// Keys allowed to submit, anyone if empty
+    allowed: BTreeSet<PublicKey>,
+    stats: BTreeMap<PublicKey, MinerStats>,
+}
+impl Miners {
+    pub fn new() -> Self {
+        Miners { allowed: BTreeSet::new(), stats: BTreeMap::new() }
+    }
+
+    pub fn allow(&mut self, miners: impl IntoIterator<Item = PublicKey>) {
+        self.allowed.extend(miners);
+    }
+
+    pub fn is_restricted(&self) -> bool {
+        !self.allowed.is_empty()
+    }
+
+    /// Whether `miner` signed the block and may submit it
+    pub fn check(&self, block: &Block, miner: &PublicKey, signature: &Signature) -> Result<()> {
+        if !signature.verify_block(&block.hash(), miner) {
+            return Err(anyhow!("invalid signature"));
+        }
+        if self.is_restricted() && !self.allowed.contains(miner) {
+            return Err(anyhow!("miner not allowed"));
+        }
+        Ok(())
+    }
+
+    pub fn record(&mut self, miner: &PublicKey, submission: Submission) {
+        if !self.stats.contains_key(miner) && self.stats.len() >= MAX_TRACKED_MINERS {
+            return;
+        }
+        let stats = self.stats.entry(miner.clone()).or_default();
+        match submission {
+            Submission::Found => stats.blocks += 1,
+            Submission::Stale => stats.stale += 1,
+            Submission::Rejected => stats.rejected += 1,
+        }
+        stats.last_submission = Some(Utc::now());
+    }
+
+    pub fn stats(&self) -> impl Iterator<Item = (&PublicKey, &MinerStats)> {
+        self.stats.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use btclib::crypto::{Hash, MerkleRoot, PrivateKey};
+    use btclib::types::{BlockHeader, Transaction};
+    use primitive_types::U256;
+    use super::*;
+
+    fn block() -> Block {
+        let transactions = vec![Transaction::new(vec![], vec![])];
+        let header = BlockHeader::new(Utc::now(), 0, Hash::zero(), MerkleRoot::calculate(&transactions), U256::MAX);
+        Block::new(header, transactions)
+    }
+
+    #[test]
+    fn accepts_blocks_signed_by_allowed_miners() {
+        let (key, other) = (PrivateKey::new_key(), PrivateKey::new_key());
+        let block = block();
+        let signature = Signature::sign_block(&block.hash(), &key);
+        let mut miners = Miners::new();
+        assert!(miners.check(&block, &key.public_key(), &signature).is_ok());
+        miners.allow([other.public_key()]);
+        assert!(miners.check(&block, &key.public_key(), &signature).is_err());
+        miners.allow([key.public_key()]);
+        assert!(miners.check(&block, &key.public_key(), &signature).is_ok());
+    }
+
+    #[test]
+    fn refuses_signatures_made_for_something_else() {
+        let key = PrivateKey::new_key();
+        let block = block();
+        // A signature spending an output that happens to share the hash of the block
+        let signature = Signature::sign_output(&block.hash(), &key);
+        assert!(Miners::new().check(&block, &key.public_key(), &signature).is_err());
+        let signature = Signature::sign_block(&block.hash(), &key);
+        assert!(!signature.verify(&block.hash(), &key.public_key()));
+    }
+}
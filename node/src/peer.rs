@@ -0,0 +1,262 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use log::{error, info, warn};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use btclib::crypto::PrivateKey;
+use btclib::network::{Inventory, Message};
+use btclib::transport::{Connection, MessageReader, MessageWriter};
+
+/// How long to wait for a peer to answer a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a caller gets to complete the encryption handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long saying goodbye to a peer may take
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How many announced items to remember per peer
+const MAX_KNOWN_INVENTORY: usize = 5000;
+
+/// Bounded set of the inventory a peer already has, the oldest items are forgotten first
+#[derive(Default)]
+struct KnownInventory {
+    items: HashSet<Inventory>,
+    order: VecDeque<Inventory>,
+}
+impl KnownInventory {
+    fn insert(&mut self, item: Inventory) -> bool {
+        if !self.items.insert(item) {
+            return false;
+        }
+        self.order.push_back(item);
+        if self.order.len() > MAX_KNOWN_INVENTORY && let Some(oldest) = self.order.pop_front() {
+            self.items.remove(&oldest);
+        }
+        true
+    }
+}
+
+/// How connections with nodes, miners and wallets are secured
+pub struct Transport {
+    /// Key proving who we are during encryption handshakes
+    identity: PrivateKey,
+    /// Dial other nodes encrypted and refuse plaintext callers
+    encrypt: bool,
+}
+impl Transport {
+    pub fn new(identity: PrivateKey, encrypt: bool) -> Self {
+        Transport { identity, encrypt }
+    }
+
+    pub async fn connect(&self, address: &str) -> Result<Connection> {
+        let identity = self.encrypt.then_some(&self.identity);
+        let connection = timeout(HANDSHAKE_TIMEOUT, Connection::connect(address, identity, None))
+            .await
+            .map_err(|_| anyhow!("handshake with [{}] timed out", address))??;
+        if let Some(key) = connection.remote_key() {
+            info!("🔒 Encrypted connection to [{}], identity {}", address, key.fingerprint());
+        }
+        Ok(connection)
+    }
+
+    /// Take a caller, encrypting the connection if it asks for it. Plaintext callers are refused
+    /// when we run encrypted
+    pub async fn accept(&self, stream: TcpStream) -> Result<Connection> {
+        let connection = timeout(HANDSHAKE_TIMEOUT, Connection::accept(stream, &self.identity))
+            .await
+            .map_err(|_| anyhow!("handshake timed out"))??;
+        if self.encrypt && !connection.is_encrypted() {
+            return Err(anyhow!("plaintext connections are not allowed"));
+        }
+        Ok(connection)
+    }
+}
+
+/// Connection to another node. A reader task dispatches incoming messages and a writer task
+/// owns the socket for sending, so requests, replies and broadcasts can share one connection
+pub struct Peer {
+    address: String,
+    /// Whether the peer dialed us, rather than us dialing it
+    inbound: bool,
+    encrypted: bool,
+    /// Id the node introduced itself with
+    node_id: OnceLock<u64>,
+    closing: Notify,
+    /// Set once the reader task stopped, the peer is of no use anymore
+    closed: AtomicBool,
+    outbound: mpsc::UnboundedSender<Message>,
+    pending: DashMap<u64, oneshot::Sender<Message>>,
+    next_id: AtomicU64,
+    known: Mutex<KnownInventory>,
+    /// Writer task, awaited when disconnecting so queued messages go out first
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+impl Peer {
+    pub async fn connect(address: &str) -> Result<Arc<Self>> {
+        let connection = crate::transport().connect(address).await?;
+        Ok(Self::spawn(address.to_string(), connection, false))
+    }
+
+    /// Take over a connection and start its reader and writer tasks
+    pub fn spawn(address: String, connection: Connection, inbound: bool) -> Arc<Self> {
+        let encrypted = connection.is_encrypted();
+        let (reader, writer) = connection.into_split();
+        let (outbound, outbound_receiver) = mpsc::unbounded_channel();
+        let peer = Arc::new(Peer {
+            address,
+            inbound,
+            encrypted,
+            node_id: OnceLock::new(),
+            closing: Notify::new(),
+            closed: AtomicBool::new(false),
+            outbound,
+            pending: DashMap::new(),
+            next_id: AtomicU64::new(0),
+            known: Mutex::new(KnownInventory::default()),
+            writer: Mutex::new(None),
+        });
+        let writer = tokio::spawn(Self::write_loop(peer.address.clone(), writer, outbound_receiver));
+        *peer.writer.lock().unwrap() = Some(writer);
+        tokio::spawn(Self::read_loop(peer.clone(), reader));
+        peer
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn is_inbound(&self) -> bool {
+        self.inbound
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    pub fn node_id(&self) -> Option<u64> {
+        self.node_id.get().copied()
+    }
+
+    pub fn set_node_id(&self, id: u64) {
+        let _ = self.node_id.set(id);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// Stop reading from the peer, which drops the connection once nobody holds the peer anymore
+    pub fn close(&self) {
+        self.closing.notify_one();
+    }
+
+    /// Say goodbye and close the connection once the messages already queued are sent
+    pub async fn disconnect(&self) {
+        let _ = self.send(Message::Goodbye);
+        self.close();
+        let writer = self.writer.lock().unwrap().take();
+        if let Some(writer) = writer && timeout(GOODBYE_TIMEOUT, writer).await.is_err() {
+            warn!("⚠️ Could not say goodbye to [{}] in time", self.address);
+        }
+    }
+
+    /// Remember that the peer has an item, returns false if we knew it already
+    pub fn mark_known(&self, item: Inventory) -> bool {
+        self.known.lock().unwrap().insert(item)
+    }
+
+    /// Queue a message without waiting for an answer
+    pub fn send(&self, message: Message) -> Result<()> {
+        self.outbound.send(message).map_err(|_| anyhow!("connection to [{}] is closed", self.address))
+    }
+
+    /// Send a request and wait for the response carrying the same id
+    pub async fn request(&self, message: Message) -> Result<Message> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.pending.insert(id, sender);
+        if let Err(e) = self.send(Message::Request(id, Box::new(message))) {
+            self.pending.remove(&id);
+            return Err(e);
+        }
+        match timeout(REQUEST_TIMEOUT, receiver).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(anyhow!("connection to [{}] closed while waiting for a response", self.address)),
+            Err(_) => {
+                self.pending.remove(&id);
+                Err(anyhow!("request to [{}] timed out", self.address))
+            }
+        }
+    }
+
+    async fn write_loop(address: String, mut writer: MessageWriter<OwnedWriteHalf>, mut outbound: mpsc::UnboundedReceiver<Message>) {
+        while let Some(message) = outbound.recv().await {
+            crate::metrics::message_sent(&message);
+            if let Err(e) = writer.send(&message).await {
+                error!("⚠️ Failed to send message to [{}]: {}", address, e);
+                break;
+            }
+            // Nothing may follow a goodbye, dropping the writer closes our side of the connection
+            if let Message::Goodbye = message {
+                break;
+            }
+        }
+    }
+
+    async fn read_loop(peer: Arc<Peer>, mut reader: MessageReader<OwnedReadHalf>) {
+        loop {
+            let message = tokio::select! {
+                message = reader.receive() => match message {
+                    Ok(message) => {
+                        crate::metrics::message_received(&message);
+                        message
+                    }
+                    Err(e) => {
+                        warn!("🔌 Connection to [{}] lost: {}", peer.address, e);
+                        break;
+                    }
+                },
+                _ = peer.closing.notified() => break,
+            };
+            match message {
+                Message::Goodbye => {
+                    info!("👋 [{}] is shutting down", peer.address);
+                    break;
+                }
+                Message::Response(id, response) => {
+                    match peer.pending.remove(&id) {
+                        Some((_, sender)) => {
+                            let _ = sender.send(*response);
+                        }
+                        None => warn!("Unexpected response {} from [{}]", id, peer.address),
+                    }
+                }
+                // Anything else is the peer talking to us over the same connection
+                message => match crate::message_handler::respond(message, Some(&peer)).await {
+                    Ok(Some(reply)) => {
+                        if peer.send(reply).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => error!("❌  Message from [{}] rejected: {e}", peer.address),
+                },
+            }
+        }
+        // Dropping the pending senders wakes up whoever is still waiting
+        peer.pending.clear();
+        peer.closed.store(true, Ordering::Relaxed);
+        if let Some(id) = peer.node_id() {
+            crate::NODE_IDS.remove_if(&id, |_, known| Arc::ptr_eq(known, &peer));
+        }
+        if crate::NODES.remove_if(&peer.address, |_, known| Arc::ptr_eq(known, &peer)).is_some() {
+            info!("➖  Removed node [{}]", peer.address);
+        }
+    }
+}
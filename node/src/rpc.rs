@@ -0,0 +1,439 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use anyhow::Result;
+use axum::Router;
+use axum::extract::{Query, State};
+use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use subtle::ConstantTimeEq;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use btclib::crypto::{Hash, PublicKey};
+use btclib::network::{Inventory, TransactionInfo, MAX_HISTORY_PAGE};
+use btclib::types::{ChainEvent, EventTopic, Transaction};
+
+/// Ban length when the ban command does not say
+const DEFAULT_BAN_SECS: i64 = 24 * 3600;
+
+// Error codes from the JSON-RPC 2.0 specification, plus one for requests we understood but failed
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    id: Value,
+}
+
+#[derive(Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    id: Value,
+}
+
+#[derive(Serialize, Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError { code, message: message.into() }
+    }
+}
+
+type RpcResult = std::result::Result<Value, RpcError>;
+
+/// Serve JSON-RPC 2.0 over HTTP POST, and chain events over a WebSocket at `/events`.
+/// Every call must carry `Authorization: Bearer <token>`
+pub async fn serve(address: SocketAddr, token: String) -> Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!("🛠️ JSON-RPC listening on http://{}", address);
+    let app = Router::new()
+        .route("/", post(handle))
+        .route("/events", get(subscribe))
+        .with_state(token);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+fn authorized(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        // In constant time, so response times do not give the token away byte by byte
+        .is_some_and(|given| bool::from(given.as_bytes().ct_eq(token.as_bytes())))
+}
+
+async fn handle(State(token): State<String>, headers: HeaderMap, body: String) -> Response {
+    if !authorized(&headers, &token) {
+        return (StatusCode::UNAUTHORIZED, "missing or wrong token").into_response();
+    }
+    let request: RpcRequest = match serde_json::from_str(&body) {
+        Ok(request) => request,
+        Err(e) => return reply(Value::Null, Err(RpcError::new(PARSE_ERROR, e.to_string()))),
+    };
+    if request.jsonrpc != "2.0" {
+        return reply(request.id, Err(RpcError::new(INVALID_REQUEST, "only JSON-RPC 2.0 is supported")));
+    }
+    let result = dispatch(&request.method, request.params).await;
+    reply(request.id, result)
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    /// Comma separated topics, 'blocks' and 'transactions', all of them when absent
+    topics: Option<String>,
+}
+
+async fn subscribe(
+    State(token): State<String>,
+    headers: HeaderMap,
+    Query(EventsQuery { topics }): Query<EventsQuery>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    if !authorized(&headers, &token) {
+        return (StatusCode::UNAUTHORIZED, "missing or wrong token").into_response();
+    }
+    let mut wanted = vec![];
+    for topic in topics.iter().flat_map(|topics| topics.split(',')) {
+        match topic.trim() {
+            "blocks" => wanted.push(EventTopic::Blocks),
+            "transactions" => wanted.push(EventTopic::Transactions),
+            "" => {}
+            other => return (StatusCode::BAD_REQUEST, format!("unknown topic '{}'", other)).into_response(),
+        }
+    }
+    upgrade.on_upgrade(move |socket| stream_events(socket, wanted))
+}
+
+/// Push every event as a JSON text message until the client goes away
+async fn stream_events(mut socket: WebSocket, topics: Vec<EventTopic>) {
+    info!("📡 WebSocket subscribed to {:?}", topics);
+    let mut events = crate::EVENTS.subscribe();
+    loop {
+        let event = tokio::select! {
+            // Clients have nothing to say, but closing or dropping the socket ends the subscription
+            message = socket.recv() => match message {
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+            event = events.recv() => event,
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("📡 WebSocket subscriber fell behind, skipped {} events", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        if !topics.is_empty() && !topics.contains(&event.topic()) {
+            continue;
+        }
+        if socket.send(WsMessage::Text(event_json(&event).to_string().into())).await.is_err() {
+            break;
+        }
+    }
+    info!("📡 WebSocket unsubscribed");
+}
+
+fn event_json(event: &ChainEvent) -> Value {
+    match event {
+        ChainEvent::BlockConnected { hash, height } => json!({ "event": "block_connected", "hash": hash.to_string(), "height": height }),
+        ChainEvent::BlockDisconnected { hash, height } => json!({ "event": "block_disconnected", "hash": hash.to_string(), "height": height }),
+        ChainEvent::TransactionAccepted { hash } => json!({ "event": "tx_accepted", "hash": hash.to_string() }),
+        ChainEvent::TransactionEvicted { hash, reason } => json!({ "event": "tx_evicted", "hash": hash.to_string(), "reason": format!("{:?}", reason).to_lowercase() }),
+    }
+}
+
+fn reply(id: Value, result: RpcResult) -> Response {
+    let response = match result {
+        Ok(result) => RpcResponse { jsonrpc: "2.0", result: Some(result), error: None, id },
+        Err(error) => RpcResponse { jsonrpc: "2.0", result: None, error: Some(error), id },
+    };
+    axum::Json(response).into_response()
+}
+
+async fn dispatch(method: &str, params: Value) -> RpcResult {
+    match method {
+        "getchaininfo" => chain_info().await,
+        "getblock" => block(params).await,
+        "gettransaction" => transaction(params).await,
+        "getmempool" => mempool().await,
+        "getutxos" => utxos(params).await,
+        "gethistory" => history(params).await,
+        "getpeers" => peers().await,
+        "getminerstats" => miner_stats().await,
+        "submittransaction" => submit_transaction(params).await,
+        "ban" => ban(params).await,
+        "unban" => unban(params).await,
+        "listbanned" => banned().await,
+        "stop" => {
+            info!("🛑 Stop requested over JSON-RPC");
+            crate::SHUTDOWN.notify_one();
+            Ok(json!("stopping"))
+        }
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("unknown method '{}'", method))),
+    }
+}
+
+/// Deserialize the params object, or complain about it
+fn parse_params<T: for<'de> Deserialize<'de>>(params: Value) -> std::result::Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+fn parse_hash(hash: &str) -> std::result::Result<Hash, RpcError> {
+    Hash::from_str(hash).map_err(|_| RpcError::new(INVALID_PARAMS, format!("'{}' is not a hash", hash)))
+}
+
+/// Public keys are given as their compressed SEC1 encoding in hexadecimal
+fn parse_public_key(public_key: &str) -> std::result::Result<PublicKey, RpcError> {
+    hex::decode(public_key)
+        .ok()
+        .and_then(|bytes| PublicKey::from_bytes(&bytes))
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "public_key must be a hex encoded compressed key"))
+}
+
+fn to_json<T: Serialize>(value: T) -> RpcResult {
+    serde_json::to_value(value).map_err(|e| RpcError::new(SERVER_ERROR, e.to_string()))
+}
+
+async fn chain_info() -> RpcResult {
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    Ok(json!({
+        "height": blockchain.block_height(),
+        "tip": blockchain.tip_hash().to_string(),
+        "work": blockchain.chain_work().to_string(),
+        "target": format!("{:x}", blockchain.target()),
+        "mempool": blockchain.mempool().len(),
+        "txindex": blockchain.has_tx_index(),
+        "addrindex": blockchain.has_address_index(),
+        "peers": crate::NODES.len(),
+        "node": crate::node_info(),
+    }))
+}
+
+#[derive(Deserialize)]
+struct BlockParams {
+    height: Option<u64>,
+    hash: Option<String>,
+}
+
+async fn block(params: Value) -> RpcResult {
+    let BlockParams { height, hash } = parse_params(params)?;
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    let block = match (height, hash) {
+        (Some(height), None) => blockchain.block_at(height),
+        (None, Some(hash)) => blockchain.block_by_hash(&parse_hash(&hash)?),
+        _ => return Err(RpcError::new(INVALID_PARAMS, "give either a height or a hash")),
+    };
+    let block = block.ok_or_else(|| RpcError::new(SERVER_ERROR, "block not found"))?;
+    Ok(json!({ "hash": block.hash().to_string(), "block": to_json(block)? }))
+}
+
+#[derive(Deserialize)]
+struct HashParams {
+    hash: String,
+}
+
+async fn transaction(params: Value) -> RpcResult {
+    let HashParams { hash } = parse_params(params)?;
+    let hash = parse_hash(&hash)?;
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    let info = TransactionInfo::find(&blockchain, &hash).ok_or_else(|| RpcError::new(SERVER_ERROR, "transaction not found"))?;
+    let block = info.location.map(|location| json!({
+        "hash": location.block_hash.to_string(),
+        "height": location.height,
+        "position": location.position,
+    }));
+    Ok(json!({ "transaction": to_json(&info.transaction)?, "block": block, "confirmations": info.confirmations }))
+}
+
+async fn mempool() -> RpcResult {
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    let entries = blockchain.mempool().iter().map(|(received, tx)| {
+        Ok(json!({ "hash": tx.hash().to_string(), "received": received, "transaction": to_json(tx)? }))
+    }).collect::<std::result::Result<Vec<_>, RpcError>>()?;
+    Ok(Value::Array(entries))
+}
+
+#[derive(Deserialize)]
+struct UtxosParams {
+    public_key: String,
+}
+
+async fn utxos(params: Value) -> RpcResult {
+    let UtxosParams { public_key } = parse_params(params)?;
+    let public_key = parse_public_key(&public_key)?;
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    let utxos = blockchain.utxos_of(&public_key).into_iter().map(|(output, marked)| {
+        Ok(json!({ "hash": output.hash().to_string(), "value": output.value, "spent_in_mempool": marked, "output": to_json(&output)? }))
+    }).collect::<std::result::Result<Vec<_>, RpcError>>()?;
+    Ok(Value::Array(utxos))
+}
+
+#[derive(Deserialize)]
+struct HistoryParams {
+    public_key: String,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+async fn history(params: Value) -> RpcResult {
+    let HistoryParams { public_key, offset, limit } = parse_params(params)?;
+    let public_key = parse_public_key(&public_key)?;
+    let limit = limit.unwrap_or(MAX_HISTORY_PAGE as usize).min(MAX_HISTORY_PAGE as usize);
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    let (entries, total) = blockchain.history(&public_key, offset, limit).ok_or_else(|| {
+        RpcError::new(SERVER_ERROR, "address index disabled, start the node with --addrindex")
+    })?;
+    let entries = entries.iter().map(|entry| json!({
+        "tx_hash": entry.tx_hash.to_string(),
+        "block_hash": entry.location.block_hash.to_string(),
+        "height": entry.location.height,
+        "confirmations": blockchain.confirmations(&entry.location),
+        "delta": entry.delta(),
+    })).collect::<Vec<_>>();
+    Ok(json!({ "total": total, "entries": entries }))
+}
+
+async fn peers() -> RpcResult {
+    let peers = crate::NODES.iter().map(|entry| {
+        let peer = entry.value();
+        json!({
+            "address": peer.address(),
+            "node_id": peer.node_id(),
+            "inbound": peer.is_inbound(),
+            "encrypted": peer.is_encrypted(),
+        })
+    }).collect::<Vec<_>>();
+    Ok(Value::Array(peers))
+}
+
+/// Blocks submitted by each miner. The counts live in memory only, they start over from zero
+/// whenever the node restarts
+async fn miner_stats() -> RpcResult {
+    let miners = crate::MINERS.read().await;
+    let stats = miners.stats().map(|(miner, stats)| json!({
+        "public_key": hex::encode(miner.to_bytes()),
+        "blocks": stats.blocks,
+        "stale": stats.stale,
+        "rejected": stats.rejected,
+        "last_submission": stats.last_submission,
+    })).collect();
+    Ok(Value::Array(stats))
+}
+
+#[derive(Deserialize)]
+struct SubmitParams {
+    transaction: Transaction,
+}
+
+async fn submit_transaction(params: Value) -> RpcResult {
+    let SubmitParams { transaction } = parse_params(params)?;
+    let hash = transaction.hash();
+    let added = crate::util::accept_transaction(transaction)
+        .await
+        .map_err(|e| RpcError::new(SERVER_ERROR, e.to_string()))?;
+    if added {
+        crate::util::announce(&[Inventory::Transaction(hash)]);
+    }
+    Ok(json!({ "hash": hash.to_string(), "added": added }))
+}
+
+#[derive(Deserialize)]
+struct BanParams {
+    /// Address as listed by getpeers, or a bare host to ban every port on it
+    address: String,
+    seconds: Option<i64>,
+}
+
+async fn ban(params: Value) -> RpcResult {
+    let BanParams { address, seconds } = parse_params(params)?;
+    let until = ban_end(seconds.unwrap_or(DEFAULT_BAN_SECS))?;
+    crate::ADDRESS_BOOK.write().await.ban(&address, until);
+    let address_book = crate::ADDRESS_BOOK.read().await;
+    let banned = crate::NODES.iter()
+        .filter(|entry| address_book.is_banned(entry.key()))
+        .map(|entry| entry.value().clone())
+        .collect::<Vec<_>>();
+    for peer in &banned {
+        crate::NODES.remove(peer.address());
+        peer.close();
+    }
+    info!("🚫 Banned [{}] until {}, {} peers disconnected", address, until, banned.len());
+    Ok(json!({ "banned": address, "until": until, "disconnected": banned.len() }))
+}
+
+/// When a ban of `seconds` starting now ends, refusing lengths that are not positive or overflow
+fn ban_end(seconds: i64) -> Result<DateTime<Utc>, RpcError> {
+    if seconds <= 0 {
+        return Err(RpcError::new(INVALID_PARAMS, "seconds must be positive"));
+    }
+    TimeDelta::try_seconds(seconds)
+        .and_then(|length| Utc::now().checked_add_signed(length))
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("a ban of {} seconds ends too far in the future", seconds)))
+}
+
+async fn unban(params: Value) -> RpcResult {
+    let BanParams { address, .. } = parse_params(params)?;
+    let unbanned = crate::ADDRESS_BOOK.write().await.unban(&address);
+    Ok(json!({ "unbanned": unbanned }))
+}
+
+async fn banned() -> RpcResult {
+    let address_book = crate::ADDRESS_BOOK.read().await;
+    let bans = address_book.bans().map(|(address, until)| json!({ "address": address, "until": until })).collect();
+    Ok(Value::Array(bans))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use super::*;
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", token)).unwrap());
+        headers
+    }
+
+    #[test]
+    fn checks_the_token() {
+        assert!(authorized(&bearer("secret"), "secret"));
+        assert!(!authorized(&bearer("secreT"), "secret"));
+        assert!(!authorized(&bearer("secret1"), "secret"));
+        assert!(!authorized(&bearer("secre"), "secret"));
+        assert!(!authorized(&HeaderMap::new(), "secret"));
+    }
+
+    #[test]
+    fn refuses_bans_not_ending_in_the_future() {
+        for seconds in [0, -1, i64::MIN, i64::MAX, i64::MAX / 1000] {
+            let e = ban_end(seconds).unwrap_err();
+            assert_eq!(e.code, INVALID_PARAMS);
+        }
+        let until = ban_end(DEFAULT_BAN_SECS).unwrap();
+        assert!(until > Utc::now() + TimeDelta::seconds(DEFAULT_BAN_SECS - 60));
+    }
+}
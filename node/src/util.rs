@@ -1,48 +1,247 @@
+use std::collections::HashSet;
+use std::fs::{OpenOptions, Permissions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
 use std::sync::Arc;
-use anyhow::{Context, Result};
-use log::{error, info};
-use tokio::net::TcpStream;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Context, Result};
+use dashmap::Entry;
+use log::{error, info, warn};
 use tokio::time;
-use btclib::network::Message;
-use btclib::types::Blockchain;
-use btclib::util::Saveable;
+use btclib::crypto::{Hash, PrivateKey};
+use btclib::error::BtcError;
+use btclib::network::{ChainTip, Inventory, Message};
+use btclib::types::{Block, Blockchain, CompactBlock, Transaction};
+use btclib::util::{is_not_migratable, Saveable};
+use crate::address_book::{AddressBook, MAX_ADDR_ENTRIES};
+use crate::peer::Peer;
 
-pub async fn populate_connections(node_addr: &str, known_nodes: &[String]) -> Result<()> {
+/// Seconds between two checks for peers ahead of us
+const SYNC_INTERVAL: u64 = 30;
+/// Seconds between two rounds of address gossip and reconnections
+const CONNECTIONS_INTERVAL: u64 = 30;
+/// Seconds before an item asked for with GetData may be asked for again from another peer
+const GETDATA_TIMEOUT: u64 = 10;
+
+pub async fn populate_connections(known_nodes: &[String], target: usize) {
     info!("Trying to connect to other nodes...");
-    for known_node in known_nodes {
-        info!("🔗 Connecting to [{}]", known_node);
-        let stream = Arc::new(Mutex::new(TcpStream::connect(&known_node).await?));
-        let mut locked_stream = stream.lock().await;
-        // Add first all the nodes known by each known node
-        Message::DiscoverNodes(node_addr.to_string(), known_node.to_string()).send_async(&mut *locked_stream).await?;
-        info!("Sending 'DiscoverNodes' to [{}]", known_node);
-        let message = Message::receive_async(&mut *locked_stream).await?;
-        match message {
-            Message::NodeList(nodes_response) => {
-                info!("Received 'NodeList' from [{}] with {} nodes", known_node, nodes_response.len());
-                for node in nodes_response {
-                    if node != node_addr {
-                        let stream = Arc::new(Mutex::new(TcpStream::connect(&node).await?));
-                        info!("➕  Added node [{}]", node);
-                        crate::NODES.insert(node, stream);
-                    }
+    {
+        let mut address_book = crate::ADDRESS_BOOK.write().await;
+        address_book.ignore(&crate::node_info().address);
+        for known_node in known_nodes {
+            address_book.add(known_node);
+        }
+    }
+    connect_outbound(target).await;
+    info!("🌐 Known network nodes: [{}]", crate::NODES.len());
+}
+
+/// Dial addresses from the address book until we have `target` outbound connections
+async fn connect_outbound(target: usize) {
+    let outbound = crate::NODES.iter().filter(|x| !x.value().is_inbound()).count();
+    if outbound >= target {
+        return;
+    }
+    let candidates = crate::ADDRESS_BOOK.read().await.candidates(|address| {
+        crate::NODES.contains_key(address)
+    });
+    let mut missing = target - outbound;
+    for candidate in candidates {
+        if missing == 0 {
+            break;
+        }
+        // A previous connection may have told us about this one meanwhile
+        if crate::NODES.contains_key(&candidate) {
+            continue;
+        }
+        match connect_node(&candidate).await {
+            Ok(Some(nodes)) => {
+                let mut address_book = crate::ADDRESS_BOOK.write().await;
+                address_book.mark_seen(&candidate);
+                for node in &nodes {
+                    address_book.add(node);
                 }
+                missing -= 1;
             }
-            e => {
-                error!("Unexpected message from [{}]: {:?}", known_node, e);
+            Ok(None) => {}
+            Err(e) => {
+                warn!("⚠️ Failed to connect to [{}]: {}", candidate, e);
+                crate::ADDRESS_BOOK.write().await.mark_failed(&candidate);
             }
         }
-        // Finally add each known node given as boot config
-        info!("➕  Added node [{}]", known_node);
-        crate::NODES.insert(known_node.clone(), stream.clone());
     }
-    info!("🌐 Known network nodes: [{}]", crate::NODES.len());
-    Ok(())
+}
+
+/// Connect to a node and introduce ourselves, returns the nodes it knows about.
+/// Returns None if the address turned out to lead to ourselves or to a node we are already connected to
+async fn connect_node(node: &str) -> Result<Option<HashSet<String>>> {
+    info!("🔗 Connecting to [{}]", node);
+    let peer = Peer::connect(node).await?;
+    info!("Sending 'DiscoverNodes' to [{}]", node);
+    match peer.request(Message::DiscoverNodes(crate::node_info().clone(), node.to_string())).await? {
+        Message::NodeList(info, nodes_response) => {
+            info!("Received 'NodeList' from [{}] with {} nodes", node, nodes_response.len());
+            peer.set_node_id(info.id);
+            if !register_peer(peer).await {
+                return Ok(None);
+            }
+            Ok(Some(nodes_response))
+        }
+        e => {
+            peer.close();
+            Err(anyhow!("Unexpected message from [{}]: {:?}", node, e))
+        }
+    }
+}
+
+/// Add a peer that introduced itself to the known nodes, unless it is ourselves or a node we are
+/// already connected to. Returns whether the peer was kept
+pub async fn register_peer(peer: Arc<Peer>) -> bool {
+    let our_id = crate::node_info().id;
+    let their_id = peer.node_id().expect("BUG: peer registered before introducing itself");
+    if crate::ADDRESS_BOOK.read().await.is_banned(peer.address()) {
+        info!("🚫 [{}] is banned, dropping it", peer.address());
+        peer.close();
+        return false;
+    }
+    if their_id == our_id {
+        info!("🪞 [{}] is ourselves, ignoring it", peer.address());
+        crate::ADDRESS_BOOK.write().await.ignore(peer.address());
+        peer.close();
+        return false;
+    }
+    // Looking the id up and claiming it in one step, so two connections of the same node at once
+    // cannot both get in
+    let duplicate = match crate::NODE_IDS.entry(their_id) {
+        Entry::Occupied(mut entry) if !entry.get().is_closed() && !Arc::ptr_eq(entry.get(), &peer) => {
+            let existing = entry.get().clone();
+            // When both nodes dialed each other, both keep the connection dialed by the lowest id
+            let dialer = |peer: &Peer| if peer.is_inbound() { their_id } else { our_id };
+            let keep_new = dialer(&peer) != dialer(&existing) && dialer(&peer) == our_id.min(their_id);
+            if keep_new {
+                entry.insert(peer.clone());
+            }
+            Some((existing, keep_new))
+        }
+        entry => {
+            entry.insert(peer.clone());
+            None
+        }
+    };
+    if let Some((existing, keep_new)) = duplicate {
+        let (kept, dropped) = if keep_new { (peer.clone(), existing) } else { (existing, peer.clone()) };
+        info!("♊ [{}] and [{}] are the same node, keeping [{}]", kept.address(), dropped.address(), kept.address());
+        crate::NODES.remove_if(dropped.address(), |_, known| Arc::ptr_eq(known, &dropped));
+        if dropped.address() != kept.address() {
+            crate::ADDRESS_BOOK.write().await.ignore(dropped.address());
+        }
+        dropped.close();
+        if !keep_new {
+            return false;
+        }
+    }
+    info!("➕  Added node [{}]", peer.address());
+    if let Some(replaced) = crate::NODES.insert(peer.address().to_string(), peer) {
+        replaced.close();
+    }
+    true
+}
+
+/// Ask every peer for the addresses it knows
+async fn gossip_addresses() {
+    let peers = crate::NODES.iter().map(|x| x.value().clone()).collect::<Vec<_>>();
+    for peer in peers {
+        match peer.request(Message::GetAddr).await {
+            Ok(Message::Addr(addresses)) => {
+                let mut address_book = crate::ADDRESS_BOOK.write().await;
+                for address in addresses.iter().take(MAX_ADDR_ENTRIES) {
+                    address_book.add(address);
+                }
+            }
+            Ok(e) => error!("Unexpected message from [{}]: {:?}", peer.address(), e),
+            Err(e) => warn!("⚠️ Failed to ask [{}] for addresses: {}", peer.address(), e),
+        }
+    }
+}
+
+/// Periodically exchange addresses, reconnect to reach the outbound target and save the address book
+pub async fn maintain_connections(peers_file: String, target: usize) {
+    let mut interval = time::interval(time::Duration::from_secs(CONNECTIONS_INTERVAL));
+    loop {
+        interval.tick().await;
+        gossip_addresses().await;
+        let pruned = crate::ADDRESS_BOOK.write().await.prune();
+        if pruned > 0 {
+            info!("📒 Forgot {} unreachable addresses", pruned);
+        }
+        connect_outbound(target).await;
+        let address_book = crate::ADDRESS_BOOK.read().await;
+        if let Err(e) = address_book.save_to_file(&peers_file) {
+            error!("⚠️ Failed to save address book: {}", e);
+        }
+    }
+}
+
+pub fn load_address_book(peers_file: &str) -> Result<AddressBook> {
+    let address_book = AddressBook::load_from_file(peers_file)?;
+    info!("📒 Address book loaded with {} addresses", address_book.len());
+    Ok(address_book)
+}
+
+/// Load the key identifying this node, creating it on first start
+pub fn load_identity(identity_file: &str) -> Result<PrivateKey> {
+    let identity = if Path::new(identity_file).exists() {
+        PrivateKey::load_from_file(identity_file).with_context(|| format!("reading identity key '{}'", identity_file))?
+    } else {
+        let identity = PrivateKey::new_key();
+        identity.save_to_file(identity_file).with_context(|| format!("writing identity key '{}'", identity_file))?;
+        info!("Created identity key '{}'", identity_file);
+        identity
+    };
+    // Miners and wallets pin the node identity from this file
+    let public_key_file = format!("{}.pub.pem", identity_file);
+    identity.public_key().save_to_file(&public_key_file).with_context(|| format!("writing '{}'", public_key_file))?;
+    info!("🔑 Node identity {}", identity.public_key().fingerprint());
+    Ok(identity)
+}
+
+/// Token for the JSON-RPC server, the given one or a new random one written to a file for local clients
+pub fn rpc_token(given: Option<String>, token_file: &str) -> Result<String> {
+    if let Some(token) = given {
+        return Ok(token);
+    }
+    let token = uuid::Uuid::new_v4().simple().to_string();
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // Only our user may read the token, it lets anyone stop the node
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(token_file).with_context(|| format!("writing RPC token '{}'", token_file))?;
+    // The mode only applies to new files, one left by an older build may be readable by anyone
+    #[cfg(unix)]
+    file.set_permissions(Permissions::from_mode(0o600)).with_context(|| format!("restricting RPC token '{}'", token_file))?;
+    file.write_all(token.as_bytes()).with_context(|| format!("writing RPC token '{}'", token_file))?;
+    info!("JSON-RPC token written to '{}'", token_file);
+    Ok(token)
 }
 
 pub async fn load_blockchain(blockchain_file: &str) -> Result<()> {
-    let new_blockchain = Blockchain::load_from_file(blockchain_file)?;
+    let new_blockchain = match Blockchain::load_from_file(blockchain_file) {
+        Ok(blockchain) => blockchain,
+        Err(e) if is_not_migratable(&e) => {
+            // Written before the canonical encoding, every hash changed so the blocks are only good
+            // for syncing again. Keep the file around instead of overwriting it with the new chain
+            let old_file = format!("{}.old", blockchain_file);
+            std::fs::rename(blockchain_file, &old_file)
+                .with_context(|| format!("moving blockchain '{}' out of the way", blockchain_file))?;
+            warn!("⚠️ {}", e);
+            warn!("⚠️ Moved blockchain '{}' to '{}', syncing the chain from scratch", blockchain_file, old_file);
+            return Ok(());
+        }
+        Err(e) => return Err(e).with_context(|| format!("loading blockchain '{}'", blockchain_file)),
+    };
     info!("Blockchain loaded");
     let mut blockchain = crate::BLOCKCHAIN.write().await;
     *blockchain = new_blockchain;
@@ -56,61 +255,72 @@ pub async fn load_blockchain(blockchain_file: &str) -> Result<()> {
     Ok(())
 }
 
-pub async fn find_longest_chain_node() -> Result<(String, u32)> {
-    info!("🪜 Finding nodes with the highest blockchain length...");
-    let mut longest_node = String::new();
-    let mut longest_count = 0;
+/// Send a request to a node and wait for its reply
+async fn request(node: &str, message: Message) -> Result<Message> {
+    let peer = crate::NODES.get(node).context("node not found")?.clone();
+    peer.request(message).await
+}
+
+/// Announce blocks or transactions to every friend node that does not know about them yet
+pub fn announce(items: &[Inventory]) {
+    let peers = crate::NODES.iter().map(|x| x.value().clone()).collect::<Vec<_>>();
+    for peer in peers {
+        let unknown = items.iter().copied().filter(|item| peer.mark_known(*item)).collect::<Vec<_>>();
+        if unknown.is_empty() {
+            continue;
+        }
+        info!("Announcing {} items to friend: [{}]", unknown.len(), peer.address());
+        if peer.send(Message::Inv(unknown)).is_err() {
+            error!("⚠️ Failed to announce to {}", peer.address());
+        }
+    }
+}
+
+/// Find the node whose chain has the most work, if it has more than ours
+pub async fn find_best_chain_node(ours: &ChainTip) -> Option<(String, ChainTip)> {
+    info!("🪜 Finding nodes with the most chain work...");
+    let mut best: Option<(String, ChainTip)> = None;
     let all_nodes = crate::NODES.iter().map(|x| x.key().clone()).collect::<Vec<_>>();
     for node in all_nodes {
-        info!("Asking [{}] about blockchain length", node);
-        let node_stream = crate::NODES.get(&node).context("no node")?.clone();
-        // Lock for sending
-        {
-            let message = Message::AskDifference(0);
-            let mut locked_stream = node_stream.lock().await;
-            message.send_async(&mut *locked_stream).await?;
-        }
-        info!("Sent 'AskDifference' to [{}]", node);
-        // Lock for receiving
-        let message = {
-            let mut locked_stream = node_stream.lock().await;
-            Message::receive_async(&mut *locked_stream).await?
-        };
-        match message {
-            Message::Difference(count) => {
-                info!("Received 'Difference' from [{}]", node);
-                if count > longest_count {
-                    info!("New longest blockchain: {} blocks from [{}]", count, node);
-                    longest_count = count;
-                    longest_node = node;
+        info!("Asking [{}] about its chain tip", node);
+        match request(&node, Message::AskTip(ours.clone())).await {
+            Ok(Message::Tip(tip)) => {
+                info!("Received 'Tip' from [{}]: height {}, work {}", node, tip.height, tip.work);
+                let best_work = best.as_ref().map(|(_, best)| best.work).unwrap_or(ours.work);
+                if tip.work > best_work {
+                    info!("New best chain: {} blocks from [{}]", tip.height, node);
+                    best = Some((node, tip));
                 }
             }
-            e => {
+            Ok(e) => {
                 error!("Unexpected message from [{}]: {:?}", node, e);
             }
+            Err(e) => {
+                warn!("⚠️ Failed to ask [{}] about its chain tip: {}", node, e);
+            }
         }
     }
-    Ok((longest_node, longest_count as u32))
+    best
 }
 
-pub(crate) async fn download_blockchain(node: &str, count: u32) -> Result<()> {
-    let node_stream = crate::NODES.get(node).context("node not found")?.clone();
-    for i in 0..count as usize {
-        let message = Message::FetchBlock(i);
-        // Lock for sending
-        {
-            let mut locked_stream = node_stream.lock().await;
-            message.send_async(&mut *locked_stream).await?;
+/// Download the blocks with heights in `from..to` without connecting them, a branch to switch to
+async fn download_branch(node: &str, from: u64, to: u64) -> Result<Vec<Block>> {
+    let mut branch = vec![];
+    for i in from..to {
+        match request(node, Message::FetchBlock(i)).await? {
+            Message::NewBlock(block) => branch.push(block),
+            e => return Err(anyhow!("Unexpected message from {}: {:?}", node, e)),
         }
-        // Lock for receiving
-        let message = {
-            let mut locked_stream = node_stream.lock().await;
-            Message::receive_async(&mut *locked_stream).await?
-        };
-        match message {
+    }
+    Ok(branch)
+}
+
+/// Download the blocks with heights in `from..to`
+pub(crate) async fn download_blockchain(node: &str, from: u64, to: u64) -> Result<()> {
+    for i in from..to {
+        match request(node, Message::FetchBlock(i)).await? {
             Message::NewBlock(block) => {
-                let mut blockchain = crate::BLOCKCHAIN.write().await;
-                blockchain.add_block(block)?;
+                accept_block(block).await?;
             }
             e => {
                 error!("Unexpected message from {}: {:?}", node, e);
@@ -120,13 +330,142 @@ pub(crate) async fn download_blockchain(node: &str, count: u32) -> Result<()> {
     Ok(())
 }
 
+/// Keep a compact block around until its missing transactions arrive, forgetting the ones whose
+/// transactions never came
+pub fn add_partial_block(compact: CompactBlock, slots: Vec<Option<Transaction>>) {
+    let now = Instant::now();
+    crate::PARTIAL_BLOCKS.retain(|_, (_, _, received)| now.duration_since(*received) < Duration::from_secs(GETDATA_TIMEOUT));
+    crate::PARTIAL_BLOCKS.insert(compact.hash, (compact, slots, now));
+}
+
+/// Remember that we asked for an item, returns false if a request for it is already in flight
+pub fn mark_requested(item: Inventory) -> bool {
+    let now = Instant::now();
+    match crate::REQUESTED.entry(item) {
+        Entry::Occupied(mut entry) => {
+            if now.duration_since(*entry.get()) < Duration::from_secs(GETDATA_TIMEOUT) {
+                return false;
+            }
+            entry.insert(now);
+            true
+        }
+        Entry::Vacant(entry) => {
+            entry.insert(now);
+            true
+        }
+    }
+}
+
+/// Add a block to the chain. If its parent is unknown, keep it as an orphan and trigger a sync.
+/// Returns the hashes of all the blocks that got connected
+pub async fn accept_block(block: Block) -> Result<Vec<Hash>> {
+    let hash = block.hash();
+    let mut blockchain = crate::BLOCKCHAIN.write().await;
+    let started = Instant::now();
+    let added = blockchain.add_block(block.clone());
+    crate::metrics::block_validated(started.elapsed());
+    match added {
+        Ok(()) => {
+            let mut connected = vec![hash];
+            let orphans = blockchain.connect_orphans();
+            if !orphans.is_empty() {
+                info!("🧩 Connected {} orphan blocks", orphans.len());
+            }
+            connected.extend(orphans);
+            publish_events(&mut blockchain);
+            Ok(connected)
+        }
+        Err(BtcError::OrphanBlock) => {
+            info!("👶 Parent of block {} unknown, keeping it as orphan", hash);
+            blockchain.add_orphan(block);
+            crate::SYNC.notify_one();
+            Ok(vec![])
+        }
+        Err(e) => {
+            crate::metrics::validation_failed("block", &e);
+            Err(e.into())
+        }
+    }
+}
+
+/// Add a transaction to the mempool, returns false if we already had it
+pub async fn accept_transaction(tx: Transaction) -> Result<bool> {
+    let mut blockchain = crate::BLOCKCHAIN.write().await;
+    if blockchain.mempool_transaction(&tx.hash()).is_some() {
+        return Ok(false);
+    }
+    let added = blockchain.add_to_mempool(tx).map_err(|e| {
+        crate::metrics::validation_failed("transaction", &e);
+        anyhow!("❌ Transaction rejected: {e}")
+    });
+    // A rejected transaction may still have evicted the one it conflicts with
+    publish_events(&mut blockchain);
+    added.map(|_| true)
+}
+
+/// Pass the events recorded by the blockchain on to the subscribers
+pub fn publish_events(blockchain: &mut Blockchain) {
+    for event in blockchain.take_events() {
+        // Failing only means nobody is subscribed
+        let _ = crate::EVENTS.send(event);
+    }
+}
+
+/// Fetch the blocks we are missing from the node with the most chain work, switching to its
+/// branch if our chains part ways
+pub async fn catch_up() -> Result<()> {
+    let (ours, locator) = {
+        let blockchain = crate::BLOCKCHAIN.read().await;
+        (ChainTip::of(&blockchain), blockchain.locator())
+    };
+    let Some((node, tip)) = find_best_chain_node(&ours).await else {
+        info!("✅  Blockchain is up to date at height {}", ours.height);
+        return Ok(());
+    };
+    let fork = match request(&node, Message::FindFork(locator)).await? {
+        Message::ForkPoint(fork) => fork,
+        e => return Err(anyhow!("Unexpected message from {}: {:?}", node, e)),
+    };
+    if fork < ours.height {
+        info!("🔀 [{}] is on another branch from height {}, fetching {} blocks", node, fork, tip.height.saturating_sub(fork));
+        let branch = download_branch(&node, fork, tip.height).await?;
+        let mut blockchain = crate::BLOCKCHAIN.write().await;
+        blockchain.reorganize(fork, branch)
+            .map_err(|e| anyhow!("Failed to switch to the branch of [{}]: {}", node, e))?;
+        blockchain.connect_orphans();
+        publish_events(&mut blockchain);
+        info!("🔀 Switched to the branch of [{}], now at height {}", node, blockchain.block_height());
+        return Ok(());
+    }
+    info!("↪️ Downloading {} blocks from [{}]", tip.height.saturating_sub(ours.height), node);
+    download_blockchain(&node, ours.height, tip.height).await?;
+    info!("↪️ Blockchain synced with [{}]", node);
+    Ok(())
+}
+
+/// Periodically, or whenever an orphan shows up, catch up with the network
+pub async fn sync() {
+    let mut interval = time::interval(time::Duration::from_secs(SYNC_INTERVAL));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = crate::SYNC.notified() => {}
+        }
+        if let Err(e) = catch_up().await {
+            error!("⚠️ Sync failed: {}", e);
+        }
+    }
+}
+
 pub async fn mempool_cleanup() {
     let mut interval = time::interval(time::Duration::from_secs(30));
     loop {
         interval.tick().await;
-        info!("🧹 Cleaning mempool old transactions");
+        info!("🧹 Cleaning mempool old transactions and stale orphans");
         let mut blockchain = crate::BLOCKCHAIN.write().await;
         blockchain.cleanup_mempool();
+        blockchain.cleanup_orphans();
+        publish_events(&mut blockchain);
     }
 }
 
@@ -135,7 +474,81 @@ pub async fn save(name: String) {
     loop {
         interval.tick().await;
         info!("💾 Saving blockchain to disk");
-        let blockchain = crate::BLOCKCHAIN.read().await;
-        blockchain.save_to_file(name.clone()).unwrap();
+        if let Err(e) = save_blockchain(&name).await {
+            error!("⚠️ Failed to save blockchain: {}", e);
+        }
     }
-}
\ No newline at end of file
+}
+
+pub async fn save_blockchain(blockchain_file: &str) -> Result<()> {
+    crate::BLOCKCHAIN.read().await.save_to_file(blockchain_file)?;
+    Ok(())
+}
+
+/// Ask the node to shut down on Ctrl-C, or SIGTERM on Unix
+pub async fn wait_for_signals() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(e) => {
+                warn!("⚠️ Cannot listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+    info!("🛑 Signal received");
+    crate::SHUTDOWN.notify_one();
+}
+
+/// Tell every peer we are leaving and close the connections
+pub async fn disconnect_peers() {
+    let peers = crate::NODES.iter().map(|entry| entry.value().clone()).collect::<Vec<_>>();
+    crate::NODES.clear();
+    info!("👋 Saying goodbye to {} peers", peers.len());
+    let mut goodbyes = tokio::task::JoinSet::new();
+    for peer in peers {
+        goodbyes.spawn(async move { peer.disconnect().await });
+    }
+    while goodbyes.join_next().await.is_some() {}
+}
+#[cfg(test)]
+mod tests {
+    use btclib::network::NodeInfo;
+    use btclib::transport::Connection;
+    use tokio::net::{TcpListener, TcpStream};
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn keeps_one_of_simultaneous_connections_from_a_node() {
+        let _ = crate::NODE_INFO.set(NodeInfo { address: "127.0.0.1:9000".to_string(), id: 1 });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut ends = vec![];
+        let mut peers = vec![];
+        for i in 0..8 {
+            let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            ends.push(listener.accept().await.unwrap().0);
+            let peer = Peer::spawn(format!("alias-{}:9000", i), Connection::plaintext(stream), false);
+            peer.set_node_id(7);
+            peers.push(peer);
+        }
+        let registering = peers.iter().map(|peer| tokio::spawn(register_peer(peer.clone()))).collect::<Vec<_>>();
+        let mut kept = 0;
+        for registered in registering {
+            kept += registered.await.unwrap() as usize;
+        }
+        assert_eq!(kept, 1);
+        let registered = crate::NODE_IDS.get(&7).unwrap().clone();
+        let aliases = crate::NODES.iter().filter(|entry| entry.key().starts_with("alias-")).count();
+        assert_eq!(aliases, 1);
+        assert!(crate::NODES.get(registered.address()).is_some_and(|known| Arc::ptr_eq(known.value(), &registered)));
+    }
+}
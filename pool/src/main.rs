@@ -0,0 +1,103 @@
+mod payout;
+mod pool;
+mod session;
+mod upstream;
+
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use env_logger::Env;
+use log::{info, warn};
+use tokio::net::TcpListener;
+use tokio::time::interval;
+use btclib::crypto::{PrivateKey, PublicKey};
+use btclib::util::Saveable;
+use payout::{Payout, Scheme};
+use pool::Pool;
+use upstream::Upstream;
+
+/// How often the coinbase is rebuilt to pay for the latest shares
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+/// How often to log the shares of every worker
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[arg(short, long)]
+    node: String,
+    /// Port miners connect to
+    #[arg(short, long, default_value_t = 3333)]
+    port: u16,
+    /// Key the pool fee and anything not owed to workers is paid to
+    #[arg(short = 'k', long)]
+    public_key_file: String,
+    /// Key signing the blocks submitted, nodes may only take blocks from the miners they know
+    #[arg(long)]
+    private_key_file: String,
+    /// How rewards are split between workers
+    #[arg(long, value_enum, default_value_t = Scheme::Pplns)]
+    payout: Scheme,
+    /// Number of latest shares a block is split over with PPLNS
+    #[arg(long, default_value_t = 1000)]
+    pplns_window: usize,
+    /// How many times easier than a block a share is
+    #[arg(long, default_value_t = 256)]
+    share_ratio: u64,
+    /// Percentage of the rewards the pool keeps
+    #[arg(long, default_value_t = 1.0)]
+    fee: f64,
+    /// Encrypt the connection to the node
+    #[arg(long)]
+    encrypt: bool,
+    /// Public key the node must prove to own, implies --encrypt
+    #[arg(long)]
+    node_key_file: Option<String>
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let cli = Cli::parse();
+    if !(0.0..=100.0).contains(&cli.fee) {
+        return Err(anyhow!("The fee is a percentage, between 0 and 100"));
+    }
+    let key = PublicKey::load_from_file(&cli.public_key_file).map_err(|e| anyhow!("Error reading public key: {}", e))?;
+    let signer = PrivateKey::load_from_file(&cli.private_key_file).map_err(|e| anyhow!("Error reading private key: {}", e))?;
+    let node_key = cli.node_key_file
+        .map(|file| PublicKey::load_from_file(&file).map_err(|e| anyhow!("Error reading node key: {}", e)))
+        .transpose()?;
+    let (pool, blocks) = Pool::new(key.clone(), cli.fee, cli.share_ratio, Payout::new(cli.payout, cli.pplns_window));
+    let pool = Arc::new(pool);
+    let listener = TcpListener::bind(("0.0.0.0", cli.port)).await?;
+    info!("🏊 Pool listening on port {}, paying out with {:?}", cli.port, cli.payout);
+    tokio::spawn({
+        let pool = pool.clone();
+        async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(session::serve(pool.clone(), stream));
+                    }
+                    Err(e) => warn!("Failed to accept a miner: {}", e),
+                }
+            }
+        }
+    });
+    tokio::spawn({
+        let pool = pool.clone();
+        async move {
+            let (mut refresh, mut stats) = (interval(REFRESH_INTERVAL), interval(STATS_INTERVAL));
+            loop {
+                tokio::select! {
+                    _ = refresh.tick() => pool.refresh(),
+                    _ = stats.tick() => pool.log_stats(),
+                }
+            }
+        }
+    });
+    let upstream = Upstream { node: cli.node, encrypt: cli.encrypt, node_key, key, signer };
+    upstream::run(pool, upstream, blocks).await;
+    Ok(())
+}
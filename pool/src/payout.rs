@@ -0,0 +1,181 @@
+use std::collections::{BTreeMap, VecDeque};
+use clap::ValueEnum;
+use btclib::crypto::PublicKey;
+
+/// How the rewards of the blocks the pool finds are split
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Scheme {
+    /// Pay per last N shares: each block is split over the workers of the latest shares
+    Pplns,
+    /// Pay per share: every share earns its expected value at once, the pool bears the luck
+    Pps,
+}
+
+/// What the workers are owed, turned into coinbase outputs
+pub enum Payout {
+    Pplns {
+        window: usize,
+        /// Latest shares with their difficulty, oldest first
+        shares: VecDeque<(PublicKey, f64)>,
+    },
+    Pps {
+        /// Sats earned and not paid yet
+        balances: BTreeMap<PublicKey, u64>,
+    },
+}
+impl Payout {
+    pub fn new(scheme: Scheme, window: usize) -> Self {
+        match scheme {
+            Scheme::Pplns => Payout::Pplns { window: window.max(1), shares: VecDeque::new() },
+            Scheme::Pps => Payout::Pps { balances: BTreeMap::new() },
+        }
+    }
+
+    /// Credit a share of the given difficulty, worth `value` Sats once the fee is taken
+    pub fn record(&mut self, key: &PublicKey, difficulty: f64, value: u64) {
+        match self {
+            Payout::Pplns { window, shares } => {
+                shares.push_back((key.clone(), difficulty));
+                while shares.len() > *window {
+                    shares.pop_front();
+                }
+            }
+            Payout::Pps { balances } => *balances.entry(key.clone()).or_insert(0) += value,
+        }
+    }
+
+    /// Coinbase outputs sharing out `total` Sats. The pool gets its fee and whatever is left
+    pub fn split(&self, total: u64, fee: f64, pool: &PublicKey) -> Vec<(PublicKey, u64)> {
+        let mut outputs: Vec<(PublicKey, u64)> = match self {
+            Payout::Pplns { shares, .. } => {
+                let mut weights: BTreeMap<&PublicKey, f64> = BTreeMap::new();
+                for (key, difficulty) in shares {
+                    *weights.entry(key).or_insert(0.0) += difficulty;
+                }
+                let sum: f64 = weights.values().sum();
+                let shared = total as f64 * (1.0 - fee / 100.0);
+                weights.into_iter()
+                    .map(|(key, weight)| (key.clone(), (shared * weight / sum) as u64))
+                    .collect()
+            }
+            Payout::Pps { balances } => {
+                // The largest debts first, as far as the block goes
+                let mut owed: Vec<_> = balances.iter().collect();
+                owed.sort_by(|a, b| b.1.cmp(a.1));
+                let mut left = total;
+                owed.into_iter()
+                    .map(|(key, balance)| {
+                        let paid = (*balance).min(left);
+                        left -= paid;
+                        (key.clone(), paid)
+                    })
+                    .collect()
+            }
+        };
+        outputs.retain(|(_, value)| *value > 0);
+        let paid: u64 = outputs.iter().map(|(_, value)| value).sum();
+        if paid < total {
+            outputs.push((pool.clone(), total - paid));
+        }
+        outputs
+    }
+
+    /// Settle the outputs of a block the pool found
+    pub fn paid(&mut self, outputs: &[(PublicKey, u64)]) {
+        if let Payout::Pps { balances } = self {
+            for (key, value) in outputs {
+                if let Some(balance) = balances.get_mut(key) {
+                    *balance = balance.saturating_sub(*value);
+                }
+            }
+            balances.retain(|_, balance| *balance > 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use btclib::crypto::PrivateKey;
+    use super::*;
+
+    fn keys<const N: usize>() -> [PublicKey; N] {
+        std::array::from_fn(|_| PrivateKey::new_key().public_key())
+    }
+
+    fn value_of(outputs: &[(PublicKey, u64)], key: &PublicKey) -> u64 {
+        outputs.iter().filter(|(output, _)| output == key).map(|(_, value)| value).sum()
+    }
+
+    fn total_of(outputs: &[(PublicKey, u64)]) -> u64 {
+        outputs.iter().map(|(_, value)| value).sum()
+    }
+
+    #[test]
+    fn pays_the_pool_everything_without_shares() {
+        let [pool] = keys();
+        for scheme in [Scheme::Pplns, Scheme::Pps] {
+            assert_eq!(Payout::new(scheme, 10).split(5_000, 1.0, &pool), vec![(pool.clone(), 5_000)]);
+        }
+    }
+
+    #[test]
+    fn gives_the_pool_its_fee_and_the_rounding_remainder() {
+        let [pool, a, b, c] = keys();
+        let mut payout = Payout::new(Scheme::Pplns, 10);
+        for key in [&a, &b, &c] {
+            payout.record(key, 1.0, 0);
+        }
+        let outputs = payout.split(1_000, 1.0, &pool);
+        // 990 Sats shared by three, the pool keeps its 10 and nothing is lost to rounding
+        for key in [&a, &b, &c] {
+            assert_eq!(value_of(&outputs, key), 330);
+        }
+        assert_eq!(value_of(&outputs, &pool), 10);
+        let outputs = payout.split(100, 0.0, &pool);
+        assert_eq!(value_of(&outputs, &a), 33);
+        assert_eq!(value_of(&outputs, &pool), 1);
+        assert_eq!(total_of(&outputs), 100);
+    }
+
+    #[test]
+    fn never_pays_more_than_the_block() {
+        let keys: [PublicKey; 7] = keys();
+        let mut payout = Payout::new(Scheme::Pplns, 100);
+        for (i, key) in keys.iter().enumerate().skip(1) {
+            payout.record(key, 1.0 / (i as f64 + 0.3), 0);
+        }
+        for total in [1, 7, 999, 5_000_000_001] {
+            for fee in [0.0, 0.5, 3.3, 100.0] {
+                assert_eq!(total_of(&payout.split(total, fee, &keys[0])), total);
+            }
+        }
+    }
+
+    #[test]
+    fn splits_over_the_latest_shares_by_difficulty() {
+        let [pool, a, b, c] = keys();
+        let mut payout = Payout::new(Scheme::Pplns, 2);
+        payout.record(&a, 1.0, 0);
+        payout.record(&b, 1.0, 0);
+        payout.record(&c, 3.0, 0);
+        let outputs = payout.split(4_000, 0.0, &pool);
+        // The share of `a` fell out of the window
+        assert_eq!(value_of(&outputs, &a), 0);
+        assert_eq!(value_of(&outputs, &b), 1_000);
+        assert_eq!(value_of(&outputs, &c), 3_000);
+    }
+
+    #[test]
+    fn pays_the_largest_debts_first_and_settles_them() {
+        let [pool, a, b] = keys();
+        let mut payout = Payout::new(Scheme::Pps, 0);
+        payout.record(&a, 1.0, 300);
+        payout.record(&b, 1.0, 800);
+        let outputs = payout.split(1_000, 1.0, &pool);
+        assert_eq!(value_of(&outputs, &b), 800);
+        assert_eq!(value_of(&outputs, &a), 200);
+        assert_eq!(value_of(&outputs, &pool), 0);
+        payout.paid(&outputs);
+        assert_eq!(payout.split(1_000, 1.0, &pool), vec![(a.clone(), 100), (pool.clone(), 900)]);
+    }
+}
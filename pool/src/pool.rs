@@ -0,0 +1,274 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use log::{info, warn};
+use primitive_types::U256;
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
+use btclib::crypto::{MerkleRoot, PublicKey};
+use btclib::stratum::{self, StratumError, Subscription};
+use btclib::types::{u256_to_f64, Block, TransactionOutput};
+use crate::payout::Payout;
+
+/// Jobs kept for the shares arriving after a newer one went out
+const MAX_JOBS: usize = 8;
+
+/// Work for the miners, a job and the target its shares have to meet
+#[derive(Clone)]
+pub struct Work {
+    pub job: stratum::Job,
+    pub share_target: U256,
+}
+
+/// A node template with the coinbase paying the workers
+struct Job {
+    id: String,
+    block: Block,
+    share_target: U256,
+    /// Sats a share earns under PPS
+    share_value: u64,
+    outputs: Vec<(PublicKey, u64)>,
+    /// Nonces already submitted
+    submitted: HashSet<u64>,
+}
+
+/// Shares of a worker since the pool started
+#[derive(Default)]
+struct WorkerStats {
+    accepted: u64,
+    stale: u64,
+    duplicate: u64,
+    invalid: u64,
+    blocks: u64,
+}
+
+struct State {
+    /// Latest template from the node
+    template: Option<Block>,
+    jobs: VecDeque<Job>,
+    next_job: u64,
+    payout: Payout,
+    workers: BTreeMap<String, WorkerStats>,
+}
+
+/// Nonce prefixes handed out, so no two connected miners ever share one
+#[derive(Default)]
+struct Sessions {
+    /// Prefixes held by a connected miner
+    live: HashSet<u16>,
+    /// Prefixes of miners gone, the longest gone first, handed out again once no fresh one is left
+    released: VecDeque<u16>,
+    /// Prefixes from this one on were never handed out
+    next: u32,
+}
+impl Sessions {
+    fn take(&mut self, resume: Option<Subscription>) -> Option<u16> {
+        let prefix = match resume {
+            Some(previous) if (previous.nonce_prefix as u32) < self.next && !self.live.contains(&previous.nonce_prefix) => {
+                self.released.retain(|prefix| *prefix != previous.nonce_prefix);
+                previous.nonce_prefix
+            }
+            _ if self.next <= u16::MAX as u32 => {
+                self.next += 1;
+                (self.next - 1) as u16
+            }
+            _ => self.released.pop_front()?,
+        };
+        self.live.insert(prefix);
+        Some(prefix)
+    }
+
+    fn release(&mut self, prefix: u16) {
+        if self.live.remove(&prefix) {
+            self.released.push_back(prefix);
+        }
+    }
+}
+
+/// Turns node templates into jobs paying the workers, and checks the shares they find
+pub struct Pool {
+    key: PublicKey,
+    /// Percentage of the rewards the pool keeps
+    fee: f64,
+    /// How many times easier than a block a share is
+    share_ratio: u64,
+    state: Mutex<State>,
+    work: broadcast::Sender<Work>,
+    blocks: mpsc::UnboundedSender<Block>,
+    sessions: Mutex<Sessions>,
+}
+impl Pool {
+    /// The pool, with the blocks it finds coming out of the receiver
+    pub fn new(key: PublicKey, fee: f64, share_ratio: u64, payout: Payout) -> (Self, mpsc::UnboundedReceiver<Block>) {
+        let (blocks, found) = mpsc::unbounded_channel();
+        let state = State { template: None, jobs: VecDeque::new(), next_job: 0, payout, workers: BTreeMap::new() };
+        let pool = Pool {
+            key,
+            fee,
+            share_ratio: share_ratio.max(1),
+            state: Mutex::new(state),
+            work: broadcast::channel(16).0,
+            blocks,
+            sessions: Mutex::new(Sessions::default()),
+        };
+        (pool, found)
+    }
+
+    /// A new session, with the nonces it may try, the current work and what comes next. A miner
+    /// reconnecting gets the nonces of its previous session back, as long as we handed them out
+    /// and no connected miner holds them. Fails once every prefix is held
+    pub fn subscribe(&self, resume: Option<Subscription>) -> Result<(Subscription, Option<Work>, broadcast::Receiver<Work>), StratumError> {
+        let nonce_prefix = self.sessions.lock().unwrap().take(resume)
+            .ok_or_else(|| StratumError::new(StratumError::OTHER, "pool full, every nonce prefix is in use"))?;
+        let receiver = self.work.subscribe();
+        let state = self.state.lock().unwrap();
+        let work = state.jobs.back().map(|job| self.work_of(job, false));
+        Ok((Subscription { nonce_prefix }, work, receiver))
+    }
+
+    /// Give back the nonces of a session that ended, for its miner to resume or another to take
+    pub fn unsubscribe(&self, subscription: Subscription) {
+        self.sessions.lock().unwrap().release(subscription.nonce_prefix);
+    }
+
+    /// Take a template from the node. Work on another tip is stale at once
+    pub fn template(&self, template: Block) {
+        let mut state = self.state.lock().unwrap();
+        let clean = state.template.as_ref()
+            .is_none_or(|current| current.header.prev_block_hash != template.header.prev_block_hash);
+        state.template = Some(template);
+        self.new_job(&mut state, clean);
+    }
+
+    /// Rebuild the job from the latest template, so the coinbase pays for the latest shares
+    pub fn refresh(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.template.is_some() {
+            self.new_job(&mut state, false);
+        }
+    }
+
+    fn new_job(&self, state: &mut State, clean: bool) {
+        let Some(mut block) = state.template.clone() else {
+            return;
+        };
+        let total: u64 = block.transactions[0].outputs.iter().map(|output| output.value).sum();
+        let outputs = state.payout.split(total, self.fee, &self.key);
+        block.transactions[0].outputs = outputs.iter()
+            .map(|(public_key, value)| TransactionOutput { value: *value, unique_id: Uuid::new_v4(), public_key: public_key.clone() })
+            .collect();
+        block.header.merkle_root = MerkleRoot::calculate(&block.transactions);
+        let share_target = block.header.target.saturating_mul(U256::from(self.share_ratio));
+        let share_value = (total as f64 * (1.0 - self.fee / 100.0) / self.share_ratio as f64) as u64;
+        let id = format!("{:x}", state.next_job);
+        state.next_job += 1;
+        if clean {
+            state.jobs.clear();
+        }
+        state.jobs.push_back(Job { id, block, share_target, share_value, outputs, submitted: HashSet::new() });
+        if state.jobs.len() > MAX_JOBS {
+            state.jobs.pop_front();
+        }
+        let job = state.jobs.back().expect("BUG: job just pushed");
+        // Nobody listening only means no miner is connected yet
+        let _ = self.work.send(self.work_of(job, clean));
+    }
+
+    fn work_of(&self, job: &Job, clean: bool) -> Work {
+        Work {
+            job: stratum::Job { id: job.id.clone(), header: job.block.header.clone(), clean },
+            share_target: job.share_target,
+        }
+    }
+
+    /// Check a share from `worker`, whose rewards go to `key`, and hand its block to the node if
+    /// it solves one
+    pub fn submit(&self, worker: &str, key: &PublicKey, subscription: &Subscription, share: &stratum::Share) -> Result<(), StratumError> {
+        let mut state = self.state.lock().unwrap();
+        let State { jobs, payout, workers, .. } = &mut *state;
+        let stats = workers.entry(worker.to_string()).or_default();
+        let Some(job) = jobs.iter_mut().find(|job| job.id == share.job_id) else {
+            stats.stale += 1;
+            return Err(StratumError::new(StratumError::STALE, "job not found"));
+        };
+        if !subscription.owns(share.nonce) {
+            stats.invalid += 1;
+            return Err(StratumError::new(StratumError::OTHER, "nonce outside the session's range"));
+        }
+        if !job.submitted.insert(share.nonce) {
+            stats.duplicate += 1;
+            return Err(StratumError::new(StratumError::DUPLICATE, "duplicate share"));
+        }
+        let mut block = job.block.clone();
+        block.header.nonce = share.nonce;
+        let hash = block.header.hash();
+        if !hash.matches_target(job.share_target) {
+            stats.invalid += 1;
+            return Err(StratumError::new(StratumError::LOW_DIFFICULTY, "low difficulty share"));
+        }
+        stats.accepted += 1;
+        payout.record(key, difficulty(job.share_target), job.share_value);
+        if hash.matches_target(block.header.target) {
+            stats.blocks += 1;
+            info!("📦️Block found by {}: {}", worker, hash);
+            payout.paid(&job.outputs);
+            if self.blocks.send(block).is_err() {
+                warn!("⚠️ Lost connection to the node, block {} not submitted", hash);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn log_stats(&self) {
+        let state = self.state.lock().unwrap();
+        for (worker, stats) in &state.workers {
+            info!(
+                "👷 {}: {} accepted, {} stale, {} duplicate, {} invalid, {} block(s)",
+                worker, stats.accepted, stats.stale, stats.duplicate, stats.invalid, stats.blocks
+            );
+        }
+    }
+}
+
+/// How much harder than the easiest target a share is, the weight it carries under PPLNS
+fn difficulty(target: U256) -> f64 {
+    u256_to_f64(btclib::MIN_TARGET) / u256_to_f64(target).max(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resume(nonce_prefix: u16) -> Option<Subscription> {
+        Some(Subscription { nonce_prefix })
+    }
+
+    #[test]
+    fn resumes_only_prefixes_nobody_holds() {
+        let mut sessions = Sessions::default();
+        assert_eq!(sessions.take(None), Some(0));
+        assert_eq!(sessions.take(None), Some(1));
+        // Held by a connected miner, or never handed out
+        assert_eq!(sessions.take(resume(0)), Some(2));
+        assert_eq!(sessions.take(resume(500)), Some(3));
+        sessions.release(1);
+        assert_eq!(sessions.take(resume(1)), Some(1));
+        assert_eq!(sessions.take(resume(1)), Some(4));
+    }
+
+    #[test]
+    fn refuses_sessions_once_every_prefix_is_held() {
+        let mut sessions = Sessions::default();
+        for prefix in 0..=u16::MAX {
+            assert_eq!(sessions.take(None), Some(prefix));
+        }
+        assert_eq!(sessions.take(None), None);
+        assert_eq!(sessions.take(resume(7)), None);
+        // Prefixes given back are handed out again, the longest gone first
+        sessions.release(9);
+        sessions.release(3);
+        sessions.release(3);
+        assert_eq!(sessions.take(None), Some(9));
+        assert_eq!(sessions.take(resume(7)), Some(3));
+        assert_eq!(sessions.take(None), None);
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use base64::Engine;
+use base64::engine::general_purpose;
+use log::{info, warn};
+use primitive_types::U256;
+use serde_json::Value;
+use tokio::net::TcpStream;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
+use btclib::crypto::PublicKey;
+use btclib::stratum::{self, Call, Line, LineReader, Reply, Share, StratumError, Subscription};
+use crate::pool::{Pool, Work};
+
+/// Talk Stratum with a miner until it hangs up
+pub async fn serve(pool: Arc<Pool>, stream: TcpStream) {
+    let address = stream.peer_addr().map(|address| address.to_string()).unwrap_or_default();
+    info!("⛏️ [{}] connected", address);
+    let (reader, writer) = stream.into_split();
+    // Reading in a task of its own, so pushing work never cuts a call in half
+    let (call_sender, mut calls) = mpsc::unbounded_channel();
+    let reading = tokio::spawn({
+        let address = address.clone();
+        async move {
+            let mut reader = LineReader::new(reader);
+            loop {
+                match reader.receive().await {
+                    Ok(Some(Line::Call(call))) => {
+                        if call_sender.send(call).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(Some(Line::Reply(_))) => warn!("Unexpected reply from [{}]", address),
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("🔌 [{}] {}", address, e);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    let mut session = Session { pool, writer, subscription: None, workers: HashMap::new(), target: None };
+    let mut work: Option<broadcast::Receiver<Work>> = None;
+    loop {
+        tokio::select! {
+            call = calls.recv() => {
+                let Some(call) = call else {
+                    break;
+                };
+                match session.handle(call).await {
+                    Ok(Some(receiver)) => work = Some(receiver),
+                    Ok(None) => {}
+                    Err(e) => {
+                        warn!("Failed to answer [{}]: {}", address, e);
+                        break;
+                    }
+                }
+            }
+            next = async { work.as_mut().expect("BUG: guarded by the condition").recv().await }, if work.is_some() => {
+                let next = match next {
+                    Ok(next) => next,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                if let Err(e) = session.push(&next).await {
+                    warn!("Failed to send work to [{}]: {}", address, e);
+                    break;
+                }
+            }
+        }
+    }
+    reading.abort();
+    if let Some(subscription) = session.subscription {
+        session.pool.unsubscribe(subscription);
+    }
+    info!("⛏️ [{}] disconnected", address);
+}
+
+struct Session {
+    pool: Arc<Pool>,
+    writer: OwnedWriteHalf,
+    subscription: Option<Subscription>,
+    /// Workers authorized on this connection, with the keys their rewards go to
+    workers: HashMap<String, PublicKey>,
+    /// Share target the miner was last told about
+    target: Option<U256>,
+}
+impl Session {
+    /// Answer a call, returning where the work comes from once the miner subscribes
+    async fn handle(&mut self, call: Call) -> std::io::Result<Option<broadcast::Receiver<Work>>> {
+        let Some(id) = call.id else {
+            return Ok(None);
+        };
+        let mut receiver = None;
+        let reply = match call.method.as_str() {
+            stratum::SUBSCRIBE => {
+                // A miner coming back asks for the nonces it had, its shares were found with them
+                let resume = call.params.first().and_then(|previous| serde_json::from_value(previous.clone()).ok());
+                // Subscribing again swaps the nonces, the old ones are free for whoever asks
+                if let Some(previous) = self.subscription.take() {
+                    self.pool.unsubscribe(previous);
+                }
+                match self.pool.subscribe(resume) {
+                    Ok((subscription, work, next)) => {
+                        self.subscription = Some(subscription);
+                        stratum::send_line(&mut self.writer, &Reply::ok(id, subscription)).await?;
+                        if let Some(work) = work {
+                            self.push(&work).await?;
+                        }
+                        receiver = Some(next);
+                        None
+                    }
+                    Err(e) => Some(Reply::error(id, e)),
+                }
+            }
+            stratum::AUTHORIZE => match call.params.first() {
+                Some(Value::String(worker)) => match worker_key(worker) {
+                    Some(key) => {
+                        info!("👷 {} authorized", worker);
+                        self.workers.insert(worker.clone(), key);
+                        Some(Reply::ok(id, true))
+                    }
+                    None => Some(Reply::error(id, StratumError::new(StratumError::UNAUTHORIZED, "workers are named after a public key"))),
+                },
+                _ => Some(Reply::error(id, StratumError::new(StratumError::OTHER, "invalid params for mining.authorize"))),
+            },
+            stratum::SUBMIT => Some(match self.submit(&call.params) {
+                Ok(()) => Reply::ok(id, true),
+                Err(e) => Reply::error(id, e),
+            }),
+            method => Some(Reply::error(id, StratumError::new(StratumError::OTHER, format!("unknown method {}", method)))),
+        };
+        if let Some(reply) = reply {
+            stratum::send_line(&mut self.writer, &reply).await?;
+        }
+        Ok(receiver)
+    }
+
+    fn submit(&self, params: &[Value]) -> Result<(), StratumError> {
+        let share = Share::from_params(params)?;
+        let subscription = self.subscription.as_ref()
+            .ok_or_else(|| StratumError::new(StratumError::NOT_SUBSCRIBED, "not subscribed"))?;
+        let key = self.workers.get(&share.worker)
+            .ok_or_else(|| StratumError::new(StratumError::UNAUTHORIZED, "unauthorized worker"))?;
+        self.pool.submit(&share.worker, key, subscription, &share)
+    }
+
+    async fn push(&mut self, work: &Work) -> std::io::Result<()> {
+        if self.target != Some(work.share_target) {
+            stratum::send_line(&mut self.writer, &stratum::set_target(work.share_target)).await?;
+            self.target = Some(work.share_target);
+        }
+        stratum::send_line(&mut self.writer, &work.job.to_call()).await
+    }
+}
+
+/// The key in a worker name, the base64 of its compressed form before any dot and rig name
+fn worker_key(worker: &str) -> Option<PublicKey> {
+    let key = worker.split('.').next()?;
+    let bytes = general_purpose::STANDARD.decode(key).ok()?;
+    PublicKey::from_bytes(&bytes)
+}
@@ -0,0 +1,132 @@
+use std::collections::VecDeque;
+use std::io::Result as IoResult;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::anyhow;
+use log::{info, warn};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout};
+use btclib::crypto::{PrivateKey, PublicKey, Signature};
+use btclib::network::{CoinbaseSpec, Message};
+use btclib::transport::{Connection, MessageWriter};
+use btclib::types::Block;
+use crate::pool::Pool;
+
+/// How long the node may take to accept the connection and finish the handshake
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait before trying the node again once it could not be reached, doubled every time
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest wait between two attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The node the templates come from and the blocks found go to
+pub struct Upstream {
+    pub node: String,
+    pub encrypt: bool,
+    /// Identity the node must prove during the handshake, which implies encryption
+    pub node_key: Option<PublicKey>,
+    /// Paid by the templates, the pool splits the rewards between the workers
+    pub key: PublicKey,
+    /// Signs the blocks submitted
+    pub signer: PrivateKey,
+}
+impl Upstream {
+    async fn connect(&self) -> anyhow::Result<Connection> {
+        // The pool needs no lasting identity, a throwaway key is enough for the handshake
+        let identity = (self.encrypt || self.node_key.is_some()).then(PrivateKey::new_key);
+        let conn = timeout(CONNECT_TIMEOUT, Connection::connect(&self.node, identity.as_ref(), self.node_key.as_ref()))
+            .await
+            .map_err(|_| anyhow!("timed out"))??;
+        match conn.remote_key() {
+            Some(key) => info!("🔒 Connected to node: [{}], identity {}", self.node, key.fingerprint()),
+            None => info!("🔗 Connected to node: [{}]", self.node),
+        }
+        Ok(conn)
+    }
+}
+
+/// Feed the pool the templates the node pushes and hand the node the blocks found, connecting
+/// again whenever the node cannot be reached or the connection drops. Blocks found in the meantime
+/// wait for the next connection. Runs until the process is killed
+pub async fn run(pool: Arc<Pool>, upstream: Upstream, mut blocks: mpsc::UnboundedReceiver<Block>) {
+    let mut unsubmitted = VecDeque::new();
+    let mut backoff = BASE_BACKOFF;
+    loop {
+        match upstream.connect().await {
+            Ok(conn) => {
+                let e = serve(&pool, &upstream, conn, &mut blocks, &mut unsubmitted, &mut backoff).await;
+                warn!("🔌 Connection to node [{}] lost: {}", upstream.node, e);
+            }
+            Err(e) => warn!("⚠️ Failed to connect to node [{}]: {}", upstream.node, e),
+        }
+        info!("⏳ Trying the node again in {}s", backoff.as_secs());
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Take templates and submit blocks, those left over from earlier connections first. Only returns
+/// once the connection is lost
+async fn serve(
+    pool: &Pool,
+    upstream: &Upstream,
+    conn: Connection,
+    blocks: &mut mpsc::UnboundedReceiver<Block>,
+    unsubmitted: &mut VecDeque<Block>,
+    backoff: &mut Duration,
+) -> anyhow::Error {
+    let (mut reader, mut writer) = conn.into_split();
+    if let Err(e) = writer.send(&Message::SubscribeTemplates(CoinbaseSpec::pay_to(upstream.key.clone()))).await {
+        return e.into();
+    }
+    // Reading in a task of its own, so submitting a block never cuts an incoming template in half
+    let (template_sender, mut templates) = mpsc::unbounded_channel();
+    let reading = tokio::spawn(async move {
+        loop {
+            match reader.receive().await {
+                Ok(Message::Template(template)) => {
+                    if template_sender.send(template).is_err() {
+                        break;
+                    }
+                }
+                Ok(Message::Goodbye) => break,
+                Ok(message) => warn!("Unexpected message from node: {}", message.kind()),
+                Err(e) => {
+                    warn!("🔌 Connection to node lost: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+    let error = loop {
+        if let Err(e) = submit(unsubmitted, &upstream.signer, &mut writer).await {
+            break e.into();
+        }
+        tokio::select! {
+            template = templates.recv() => match template {
+                Some(template) => {
+                    info!("↪️ Received new template with target: {}", template.header.target);
+                    // The node works, start over with a short wait the next time it does not
+                    *backoff = BASE_BACKOFF;
+                    pool.template(template);
+                }
+                None => break anyhow!("Node closed the connection"),
+            },
+            Some(block) = blocks.recv() => unsubmitted.push_back(block),
+        }
+    };
+    reading.abort();
+    error
+}
+
+/// Send the blocks in order, each one signed, dropping them from the queue once sent
+async fn submit(blocks: &mut VecDeque<Block>, signer: &PrivateKey, writer: &mut MessageWriter<OwnedWriteHalf>) -> IoResult<()> {
+    while let Some(block) = blocks.front() {
+        info!("🚚 Submitting block {}", block.hash());
+        let signature = Signature::sign_block(&block.hash(), signer);
+        writer.send(&Message::SubmitTemplate(block.clone(), signer.public_key(), signature)).await?;
+        blocks.pop_front();
+    }
+    Ok(())
+}
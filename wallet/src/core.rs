@@ -1,7 +1,8 @@
 use anyhow::Result;
-use btclib::crypto::{PrivateKey, PublicKey};
+use btclib::crypto::{Hash, PrivateKey, PublicKey};
 use btclib::network::Message;
-use btclib::types::{Transaction, TransactionOutput};
+use btclib::transport::Connection;
+use btclib::types::{HistoryEntry, Transaction, TransactionOutput};
 use btclib::util::Saveable;
 use crossbeam_skiplist::SkipMap;
 use flume::{Receiver, Sender};
@@ -9,8 +10,9 @@ use log::info;
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::net::TcpStream;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::sync::Mutex;
 
 /// Represent a key pair with paths to public and private keys.
@@ -67,7 +69,13 @@ pub struct Config {
     pub my_keys: Vec<Key>,
     pub contacts: Vec<Recipient>,
     pub default_node: String,
-    pub fee_config: FeeConfig
+    pub fee_config: FeeConfig,
+    /// Encrypt the connection to the node
+    #[serde(default)]
+    pub encrypt: bool,
+    /// Public key the node must prove to own, implies encryption
+    #[serde(default)]
+    pub node_key: Option<PathBuf>
 }
 
 /// Store and manage UTXOs.
@@ -85,40 +93,59 @@ impl UtxoStore {
     }
 }
 
+/// History entries asked for in one request
+const HISTORY_PAGE_SIZE: u64 = 50;
+
+/// A confirmed transaction touching any of our keys.
+#[derive(Clone)]
+pub struct WalletTransaction {
+    pub tx_hash: Hash,
+    pub height: u64,
+    /// Net change of our balance in Sats, change outputs to our own keys cancel out
+    pub delta: i128
+}
+
 /// Represent the core functionality of the wallet.
 pub struct Core {
     pub config: Config,
     utxos: UtxoStore,
+    /// Our transactions by position in the chain, (height, position in block)
+    history: SkipMap<(u64, u32), WalletTransaction>,
+    /// Chain height when the history was last fetched
+    height: AtomicU64,
     pub tx_sender: Sender<Transaction>,
-    pub stream: Mutex<TcpStream>
+    pub stream: Mutex<Connection>
 }
 impl Core {
-    fn new(config: Config, utxos: UtxoStore, stream: TcpStream) -> Self {
+    fn new(config: Config, utxos: UtxoStore, stream: Connection) -> Self {
         let (tx_sender, _rx): (Sender<Transaction>, Receiver<Transaction>) = flume::bounded(10);
-        Core { config, utxos, tx_sender, stream: Mutex::new(stream) }
+        Core { config, utxos, history: SkipMap::new(), height: AtomicU64::new(0), tx_sender, stream: Mutex::new(stream) }
     }
 
     /// Load the Core from a configuration file.
     pub async fn load(config_path: PathBuf) -> Result<Self> {
         let config: Config = toml::from_str(&fs::read_to_string(&config_path)?)?;
         let mut utxos = UtxoStore::new();
-        let stream = TcpStream::connect(&config.default_node).await?;
         // Load keys from config
         for key in &config.my_keys {
             let public = PublicKey::load_from_file(&key.public)?;
             let private = PrivateKey::load_from_file(&key.private)?;
             utxos.add_key(LoadedKey { public, private });
         }
+        let node_key = config.node_key.as_ref().map(PublicKey::load_from_file).transpose()?;
+        // The handshake only needs a throwaway identity, the wallet keys stay out of it
+        let identity = (config.encrypt || node_key.is_some()).then(PrivateKey::new_key);
+        let stream = Connection::connect(&config.default_node, identity.as_ref(), node_key.as_ref()).await?;
         Ok(Core::new(config, utxos, stream))
     }
 
     /// Fetch UTXOs from the node for all loaded keys
     pub async fn fetch_utxos(&self) -> Result<()> {
-        let mut stream = TcpStream::connect(&self.config.default_node).await?;
         for key in &self.utxos.my_keys {
             let message = Message::FetchUTXOs(key.public.clone());
-            message.send_async(&mut *self.stream.lock().await).await?;
-            if let Message::UTXOs(utxos) = Message::receive_async(&mut stream).await? {
+            let mut stream = self.stream.lock().await;
+            stream.send(&message).await?;
+            if let Message::UTXOs(utxos) = stream.receive().await? {
                 // Replace the entire UTXO set for this key
                 self.utxos.utxos.insert(key.public.clone(), utxos
                     .into_iter()
@@ -133,6 +160,54 @@ impl Core {
         Ok(())
     }
 
+    /// Fetch the transaction history of all loaded keys, page by page. Needs a node indexing public keys
+    pub async fn fetch_history(&self) -> Result<()> {
+        let mut transactions: HashMap<Hash, ((u64, u32), i128)> = HashMap::new();
+        let mut counted = HashSet::new();
+        let mut height = 0;
+        for key in &self.utxos.my_keys {
+            let mut offset = 0;
+            loop {
+                let message = Message::FetchHistory(key.public.clone(), offset, HISTORY_PAGE_SIZE);
+                let mut stream = self.stream.lock().await;
+                stream.send(&message).await?;
+                let page = match stream.receive().await? {
+                    Message::History(Some(page)) => page,
+                    Message::History(None) => return Err(anyhow::anyhow!("Node does not index public keys")),
+                    _ => return Err(anyhow::anyhow!("Unexpected response from node")),
+                };
+                drop(stream);
+                height = page.height;
+                let fetched = page.entries.len() as u64;
+                for entry in page.entries {
+                    let position = (entry.location.height, entry.location.position);
+                    let delta = uncounted_delta(&entry, &mut counted);
+                    transactions.entry(entry.tx_hash).or_insert((position, 0)).1 += delta;
+                }
+                offset += fetched;
+                if fetched == 0 || offset >= page.total {
+                    break;
+                }
+            }
+        }
+        self.history.clear();
+        for (tx_hash, (position, delta)) in transactions {
+            self.history.insert(position, WalletTransaction { tx_hash, height: position.0, delta });
+        }
+        self.height.store(height, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Our transactions, newest first, with their number of confirmations
+    pub fn transactions(&self) -> Vec<(WalletTransaction, u64)> {
+        let height = self.height.load(Ordering::Relaxed);
+        self.history.iter().rev().map(|entry| {
+            let transaction = entry.value().clone();
+            let confirmations = height.saturating_sub(transaction.height);
+            (transaction, confirmations)
+        }).collect()
+    }
+
     /// Create a new transaction.
     pub fn create_transaction(&self, recipient: &PublicKey, amount: u64) -> Result<Transaction> {
         let fee = self.calculate_fee(amount);
@@ -185,7 +260,7 @@ impl Core {
     pub async fn send_transaction(&self, transaction: Transaction) -> Result<()> {
         info!("Sending transaction to node: {}", self.config.default_node);
         let message = Message::SubmitTransaction(transaction);
-        message.send_async(&mut *self.stream.lock().await).await?;
+        self.stream.lock().await.send(&message).await?;
         info!("Transaction sent successfully");
         Ok(())
     }
@@ -231,4 +306,43 @@ impl Core {
             }
         }
     }
-}
\ No newline at end of file
+}
+/// Change an entry brings to the balance, leaving out the outputs received or spent that an earlier
+/// entry already counted. Pages shift when the chain grows between two fetches, so the same entry
+/// may come back on the next page
+fn uncounted_delta(entry: &HistoryEntry, counted: &mut HashSet<(Hash, bool)>) -> i128 {
+    let mut sum = |outputs: &[(Hash, u64)], spent: bool| -> i128 {
+        outputs.iter()
+            .filter(|(hash, _)| counted.insert((*hash, spent)))
+            .map(|(_, value)| *value as i128)
+            .sum()
+    };
+    sum(&entry.received, false) - sum(&entry.spent, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use btclib::types::TxLocation;
+    use super::*;
+
+    fn entry(tx: u64, received: &[(u64, u64)], spent: &[(u64, u64)]) -> HistoryEntry {
+        let outputs = |outputs: &[(u64, u64)]| outputs.iter().map(|(id, value)| (Hash::hash(id), *value)).collect();
+        HistoryEntry {
+            tx_hash: Hash::hash(&tx),
+            location: TxLocation { block_hash: Hash::zero(), height: tx, position: 1 },
+            received: outputs(received),
+            spent: outputs(spent),
+        }
+    }
+
+    #[test]
+    fn counts_entries_coming_back_on_the_next_page_once() {
+        let mut counted = HashSet::new();
+        let (first, second) = (entry(1, &[(10, 500)], &[]), entry(2, &[(11, 150)], &[(10, 500)]));
+        assert_eq!(uncounted_delta(&first, &mut counted), 500);
+        assert_eq!(uncounted_delta(&second, &mut counted), -350);
+        // The chain grew and both shifted onto the next page
+        assert_eq!(uncounted_delta(&first, &mut counted), 0);
+        assert_eq!(uncounted_delta(&second, &mut counted), 0);
+    }
+}
@@ -12,7 +12,7 @@ use log::info;
 use std::path::PathBuf;
 use std::sync::Arc;
 use tasks::{handle_transactions, ui_task, update_balance, update_utxos};
-use util::{big_mode_btc, generate_config};
+use util::{big_mode_btc, generate_config, transaction_list};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -59,11 +59,12 @@ async fn main() -> Result<()> {
     let core = Arc::new(core);
     info!("Starting background tasks");
     let balance_content = TextContent::new(big_mode_btc(&core));
+    let history_content = TextContent::new(transaction_list(&core));
     tokio::select! {
-        _ = ui_task(core.clone(), balance_content.clone()).await => (),
+        _ = ui_task(core.clone(), balance_content.clone(), history_content.clone()).await => (),
         _ = update_utxos(core.clone()).await => (),
         _ = handle_transactions(tx_receiver.clone(), core.clone()).await  => (),
-        _ = update_balance(core.clone(), balance_content).await => ()
+        _ = update_balance(core.clone(), balance_content, history_content).await => ()
     }
     info!("Application shutting down");
     Ok(())
@@ -7,7 +7,7 @@ use flume::Receiver;
 use btclib::types::Transaction;
 use crate::core::Core;
 use crate::ui::run_ui;
-use crate::util::big_mode_btc;
+use crate::util::{big_mode_btc, transaction_list};
 
 pub async fn update_utxos(core: Arc<Core>) -> JoinHandle<()> {
     tokio::spawn(async move {
@@ -18,6 +18,9 @@ pub async fn update_utxos(core: Arc<Core>) -> JoinHandle<()> {
             if let Err(e) = core.fetch_utxos().await {
                 error!("Failed to update UTXOs: {}", e);
             }
+            if let Err(e) = core.fetch_history().await {
+                warn!("Failed to update transaction history: {}", e);
+            }
         }
     })
 }
@@ -32,21 +35,22 @@ pub async fn handle_transactions(rx: Receiver<Transaction>, core: Arc<Core>) ->
     })
 }
 
-pub async fn ui_task(core: Arc<Core>, balance_content: TextContent) -> JoinHandle<()> {
+pub async fn ui_task(core: Arc<Core>, balance_content: TextContent, history_content: TextContent) -> JoinHandle<()> {
     tokio::task::spawn_blocking(move || {
         info!("Running UI");
-        if let Err(e) = run_ui(core, balance_content) {
+        if let Err(e) = run_ui(core, balance_content, history_content) {
             eprintln!("UI ended with error: {e}");
         };
     })
 }
 
-pub async fn update_balance(core: Arc<Core>, balance_content: TextContent) -> JoinHandle<()> {
+pub async fn update_balance(core: Arc<Core>, balance_content: TextContent, history_content: TextContent) -> JoinHandle<()> {
     tokio::spawn(async move {
         loop {
             tokio::time::sleep(Duration::from_millis(500)).await;
             info!("updating balance string");
             balance_content.set_content(big_mode_btc(&core));
+            history_content.set_content(transaction_list(&core));
         }
     })
 }
\ No newline at end of file
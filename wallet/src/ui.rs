@@ -26,17 +26,17 @@ fn convert_amount(amount: f64, from: Unit, to: Unit) -> f64 {
 }
 
 /// Initialize and run the user interface.
-pub fn run_ui(core: Arc<Core>, balance_content: TextContent) -> Result<()> {
+pub fn run_ui(core: Arc<Core>, balance_content: TextContent, history_content: TextContent) -> Result<()> {
     info!("Initializing UI");
     let mut siv = cursive::default();
-    setup_siv(&mut siv, core.clone(), balance_content);
+    setup_siv(&mut siv, core.clone(), balance_content, history_content);
     siv.run();
     info!("Starting UI event loop");
     Ok(())
 }
 
 /// Set up the Cursive interface with all necessary components and callbacks.
-fn setup_siv(siv: &mut Cursive, core: Arc<Core>, balance_content: TextContent) {
+fn setup_siv(siv: &mut Cursive, core: Arc<Core>, balance_content: TextContent, history_content: TextContent) {
     siv.set_autorefresh(true);
     siv.set_window_title("BTC wallet".to_string());
     siv.add_global_callback('q', |s| {
@@ -44,7 +44,7 @@ fn setup_siv(siv: &mut Cursive, core: Arc<Core>, balance_content: TextContent) {
         s.quit()
     });
     setup_menubar(siv, core.clone());
-    setup_layout(siv, core, balance_content);
+    setup_layout(siv, core, balance_content, history_content);
     siv.add_global_callback(Event::Key(Key::Esc), |siv| {
         siv.select_menubar()
     });
@@ -60,14 +60,16 @@ fn setup_menubar(siv: &mut Cursive, core: Arc<Core>) {
 }
 
 /// Set up the main layout of the application.
-fn setup_layout(siv: &mut Cursive, core: Arc<Core>, balance_content: TextContent) {
+fn setup_layout(siv: &mut Cursive, core: Arc<Core>, balance_content: TextContent, history_content: TextContent) {
     let instruction = TextView::new("Press Escape to select the top menu");
     let balance_panel = Panel::new(TextView::new_with_content(balance_content)).title("Balance");
     let info_layout = create_info_layout(&core);
+    let history_panel = Panel::new(TextView::new_with_content(history_content).scrollable()).title("Transactions");
     let layout = LinearLayout::vertical()
         .child(instruction)
         .child(balance_panel)
-        .child(info_layout);
+        .child(info_layout)
+        .child(history_panel);
     siv.add_layer(layout);
 }
 
@@ -18,7 +18,9 @@ pub(crate) fn generate_config(path: &PathBuf) -> Result<()> {
             }
         ],
         default_node: "127.0.0.1:9000".to_string(),
-        fee_config: FeeConfig { fee_type: FeeType::Percent, value: 0.1 }
+        fee_config: FeeConfig { fee_type: FeeType::Percent, value: 0.1 },
+        encrypt: false,
+        node_key: None
     };
     let config_str = toml::to_string_pretty(&config)?;
     std::fs::write(path, config_str)?;
@@ -31,6 +33,25 @@ pub fn sats_to_btc(sats: u64) -> String {
     format!("{} BTC", btc)
 }
 
+/// One line per transaction, newest first
+pub fn transaction_list(core: &Core) -> String {
+    let transactions = core.transactions();
+    if transactions.is_empty() {
+        return "No transactions yet".to_string();
+    }
+    transactions.iter().map(|(transaction, confirmations)| {
+        let sign = if transaction.delta < 0 { "-" } else { "+" };
+        let hash = transaction.tx_hash.to_string();
+        format!(
+            "{}{:<18} {:>5} conf.  {}…",
+            sign,
+            sats_to_btc(transaction.delta.unsigned_abs() as u64),
+            confirmations,
+            &hash[..hash.len().min(16)]
+        )
+    }).collect::<Vec<_>>().join("\n")
+}
+
 /// Make it big
 pub fn big_mode_btc(core: &Core) -> String {
     let btc_value = sats_to_btc(core.get_balance()).to_string();